@@ -13,10 +13,15 @@ use stdlib::graphics::*;
 use stdlib::*;
 
 const TAB_HEIGHT: u64 = 40;
+const FRAME_TIME: u64 = 16;
 
 struct Tab {
     color: u32,
-    pid: u32,
+    // Program that opened the window, if the desktop launched it
+    child: Option<Child>,
+    // Cleared when the program ends. Windows can not be removed from the shared page, so the
+    // window of a closed tab stays there, but it is no longer drawn
+    open: bool,
 }
 
 #[export_name = "_start"]
@@ -25,41 +30,55 @@ extern "C" fn main() {
     stdlib::heap::init().unwrap();
     let smh = stdlib::desktop::server_init();
 
-    let pointer = Image::new(File::load("USER/POINTER PPM").unwrap()).unwrap();
+    let pointer = Image::new(read("USER/POINTER.PPM").unwrap()).unwrap();
     let screen_size = get_screen();
 
-    let font = Font::new(File::load("USER/FONT    PSF").unwrap()).unwrap();
+    let font = Font::new(read("USER/FONT.PSF").unwrap()).unwrap();
 
     let mut buffer = vec![0x0u32; (screen_size.width * screen_size.height) as usize];
     let mut sbuffer =
         ScreenBuffer::new(0, 0, screen_size.width, screen_size.height, &mut buffer[..]);
 
-    // Create file icons on the desktop
-    let file_icon = Image::new(File::load("USER/EXE_ICONPPM").unwrap()).unwrap();
-    let files = vec![
-        ("USER/GUI_DEMO", &file_icon),
-        ("USER/USER2", &file_icon),
-        // ("USER/USER1", &file_icon),
-    ];
+    // Create file icons on the desktop, one for each executable in USER
+    let file_icon = Image::new(read("USER/EXE_ICON.PPM").unwrap()).unwrap();
+    let files: Vec<(String, &Image)> = read_dir("USER")
+        .filter_map(|name| name.ok())
+        .filter(|name| is_executable(name))
+        .map(|name| (String::from("USER/") + &name, &file_icon))
+        .collect();
 
     let mut tabs: Vec<Tab> = vec![];
     let mut drag_anchor: Option<(u64, u64)> = None;
     let mut current_drag: usize = 0;
     let mut is_left_pressed: bool = false;
 
-    let mut prev_pid = 0;
+    // Programs launched from the desktop that have not opened their window yet, in launch order
+    let mut launched: Vec<Child> = vec![];
     loop {
         let mouse_pos = get_mouse();
 
+        // Close the tabs of the programs that have ended, and forget the ones that ended before
+        // opening a window. try_wait reaps them, so their exit status is not kept forever
+        for tab in tabs.iter_mut() {
+            if let Some(child) = &tab.child {
+                if !matches!(child.try_wait(), Ok(None)) {
+                    tab.child = None;
+                    tab.open = false;
+                }
+            }
+        }
+        launched.retain(|child| matches!(child.try_wait(), Ok(None)));
+
         // Check for acknowledgements and advance free pointer
         if smh.ack == 1 {
             // Advance free space ptr
             smh.advance_free_space();
 
-            // Create tab
+            // Create tab, owned by the oldest program that has no window yet
             tabs.push(Tab {
                 color: 0xcccccc,
-                pid: prev_pid,
+                child: (!launched.is_empty()).then(|| launched.remove(0)),
+                open: true,
             });
         }
 
@@ -71,6 +90,9 @@ extern "C" fn main() {
             if i >= tabs.len() {
                 break;
             }
+            if !tabs[i].open {
+                continue;
+            }
 
             // Bounds checking
             let left = u64::clamp(window.x, 0, sbuffer.w);
@@ -110,6 +132,9 @@ extern "C" fn main() {
                     if i >= tabs.len() {
                         break;
                     }
+                    if !tabs[i].open {
+                        continue;
+                    }
 
                     let tab = Rectangle {
                         rect: Rect {
@@ -145,7 +170,9 @@ extern "C" fn main() {
                     };
 
                     if r.point_intersection(mx as i64, my as i64) {
-                        prev_pid = exec(file.0).unwrap();
+                        if let Ok(child) = exec(&file.0) {
+                            launched.push(child);
+                        }
                     }
                 }
             }
@@ -159,14 +186,7 @@ extern "C" fn main() {
         for (i, file) in files.iter().enumerate() {
             file.1.draw(&mut sbuffer, i as i64 * 150, 0, 100, 100);
 
-            font.draw_string(
-                &String::from(file.0),
-                i as i64 * 150,
-                100,
-                1,
-                0x0,
-                &mut sbuffer,
-            );
+            font.draw_string(&file.0, i as i64 * 150, 100, 1, 0x0, &mut sbuffer);
         }
 
         pointer.draw(
@@ -177,5 +197,13 @@ extern "C" fn main() {
             100,
         );
         sbuffer.put();
+        sleep(FRAME_TIME);
     }
 }
+
+// Executables are the files without an extension, except for the desktop itself
+fn is_executable(name: &str) -> bool {
+    let has_extension = name.contains('.');
+    let is_dir = stat(&(String::from("USER/") + name)).is_ok_and(|stat| stat.is_dir());
+    !has_extension && !is_dir && !name.eq_ignore_ascii_case("DESKTOP")
+}
@@ -32,9 +32,9 @@ extern "C" fn main() {
     let mut sbuffer = ScreenBuffer::new(0, 0, 500, 500, &mut buffer[..]);
 
     // Load assets
-    let f = File::load("USER/LOGO    PPM").unwrap();
+    let f = read("USER/LOGO.PPM").unwrap();
     let img = Image::new(f).unwrap();
-    let font = Font::new(File::load("USER/FONT    PSF").unwrap()).unwrap();
+    let font = Font::new(read("USER/FONT.PSF").unwrap()).unwrap();
 
     // Build GUI tree
     let mut image = GuiRect {
@@ -93,5 +93,6 @@ extern "C" fn main() {
         };
         draw_gui_tree(&gui_root, &mut sbuffer, &io);
         window_buffer.copy_from_screen_buffer(&sbuffer);
+        sleep(16);
     }
 }
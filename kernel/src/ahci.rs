@@ -0,0 +1,334 @@
+#![allow(unused)]
+
+use super::Mutex;
+use crate::drive::*;
+use crate::memory::{alloc_dma_frame, map_mmio, DmaBuffer, DMA_FRAMES};
+use crate::pci::{self, Bar, PciDriver, PciInfo, PciMatch};
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+
+// Generic host control registers
+const HBA_CAP: u64 = 0x00;
+const HBA_GHC: u64 = 0x04;
+const HBA_PI: u64 = 0x0c;
+const HBA_PORTS: u64 = 0x100;
+const HBA_PORT_SIZE: u64 = 0x80;
+const HBA_SIZE: u64 = HBA_PORTS + 32 * HBA_PORT_SIZE;
+
+const CAP_S64A: u32 = 1 << 31; // 64 bit addressing
+const GHC_AE: u32 = 1 << 31; // AHCI enable
+
+// Port registers, relative to the port
+const PORT_CLB: u64 = 0x00;
+const PORT_CLBU: u64 = 0x04;
+const PORT_FB: u64 = 0x08;
+const PORT_FBU: u64 = 0x0c;
+const PORT_IS: u64 = 0x10;
+const PORT_CMD: u64 = 0x18;
+const PORT_TFD: u64 = 0x20;
+const PORT_SIG: u64 = 0x24;
+const PORT_SSTS: u64 = 0x28;
+const PORT_SERR: u64 = 0x30;
+const PORT_CI: u64 = 0x38;
+
+const CMD_ST: u32 = 1 << 0; // Start processing the command list
+const CMD_FRE: u32 = 1 << 4; // FIS receive enable
+const CMD_FR: u32 = 1 << 14; // FIS receive running
+const CMD_CR: u32 = 1 << 15; // Command list running
+
+const IS_TFES: u32 = 1 << 30; // Task file error
+const TFD_BSY: u32 = 1 << 7;
+const TFD_DRQ: u32 = 1 << 3;
+
+const SSTS_DET_PRESENT: u32 = 3; // Device present and communication established
+const SIG_ATA: u32 = 0x0000_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+
+// Bound of the waits for the port, in reads of its registers. The timer does not run while a
+// syscall waits, so time is not measured
+const PORT_POLLS: u32 = 10_000_000;
+
+// The command table only has one PRD per frame of the DMA buffer
+const DMA_MAX_SECTORS: u64 = (DmaBuffer::SIZE / SECTOR_SIZE) as u64;
+const COMMAND_TABLE_PRDT: u64 = 0x80;
+
+// Header of the command in slot 0 of the command list, the only slot used
+#[repr(C)]
+struct CommandHeader {
+    flags: u16, // Length of the command FIS in dwords, and the write bit
+    prdt_length: u16,
+    prd_byte_count: u32,
+    command_table: u64,
+    _reserved: [u32; 4],
+}
+
+const HEADER_WRITE: u16 = 1 << 6;
+
+// Register host to device FIS, at the start of the command table
+#[repr(C)]
+struct FisRegH2D {
+    fis_type: u8,
+    flags: u8, // Bit 7 is set for commands
+    command: u8,
+    feature_low: u8,
+    lba0: u8,
+    lba1: u8,
+    lba2: u8,
+    device: u8,
+    lba3: u8,
+    lba4: u8,
+    lba5: u8,
+    feature_high: u8,
+    count: u16,
+    icc: u8,
+    control: u8,
+    _reserved: u32,
+}
+
+#[repr(C)]
+struct Prd {
+    address: u64,
+    _reserved: u32,
+    byte_count: u32, // Minus one
+}
+
+// A SATA drive on a port of an AHCI controller. Every address is physical, and identity mapped in
+// the kernel. Commands are issued one at a time in slot 0, and completion is polled, for a bounded
+// time
+pub struct AhciDrive {
+    port: u64,
+    command_list: u64, // The received FIS area is in the same frame, at offset 0x400
+    command_table: u64,
+    buffer: DmaBuffer,
+}
+
+fn read_register(address: u64) -> u32 {
+    unsafe { read_volatile(address as *const u32) }
+}
+
+fn write_register(address: u64, value: u32) {
+    unsafe { write_volatile(address as *mut u32, value) }
+}
+
+// Polls until done returns true, giving up after PORT_POLLS tries
+fn poll(mut done: impl FnMut() -> bool) -> Result<(), DriveError> {
+    for _ in 0..PORT_POLLS {
+        if done() {
+            return Ok(());
+        }
+    }
+    Err(DriveError::Timeout)
+}
+
+static DRIVER: PciDriver = PciDriver {
+    name: "ahci",
+    matches: &[PciMatch::Class(0x01, 0x06)],
+    probe,
+};
+
+// Drives set up by probe, until init hands them out
+static PROBED: Mutex<Vec<AhciDrive>> = Mutex::new(Vec::new());
+
+// Registers the driver with the PCI subsystem, and returns a drive for every port of the AHCI
+// controllers with a SATA disk attached
+pub fn init() -> Result<Vec<AhciDrive>, &'static str> {
+    if pci::register_driver(&DRIVER) == 0 {
+        return Err("No AHCI controller");
+    }
+    Ok(core::mem::take(&mut *PROBED.lock()))
+}
+
+fn probe(info: &PciInfo) -> Result<(), &'static str> {
+    let abar = match info.bars[5] {
+        Some(Bar::Memory { address, .. }) if address != 0 => address,
+        _ => return Err("AHCI registers are not mapped"),
+    };
+    info.device.enable_decoding();
+    info.device.enable_bus_master();
+
+    map_mmio(abar, HBA_SIZE);
+
+    write_register(abar + HBA_GHC, read_register(abar + HBA_GHC) | GHC_AE);
+    let s64a = read_register(abar + HBA_CAP) & CAP_S64A != 0;
+    let implemented = read_register(abar + HBA_PI);
+
+    let mut drives = Vec::new();
+    for i in 0..32 {
+        let port = abar + HBA_PORTS + i * HBA_PORT_SIZE;
+        if implemented & (1 << i) == 0
+            || read_register(port + PORT_SSTS) & 0xf != SSTS_DET_PRESENT
+            || read_register(port + PORT_SIG) != SIG_ATA
+        {
+            continue;
+        }
+        drives.push(AhciDrive::new(port, s64a)?);
+    }
+    PROBED.lock().append(&mut drives);
+    Ok(())
+}
+
+impl AhciDrive {
+    // Without 64 bit addressing, every structure given to the controller must be below 4 GiB
+    fn new(port: u64, s64a: bool) -> Result<AhciDrive, &'static str> {
+        let drive = AhciDrive {
+            port,
+            command_list: alloc_dma_frame(!s64a)?,
+            command_table: alloc_dma_frame(!s64a)?,
+            buffer: DmaBuffer::new(!s64a)?,
+        };
+
+        // The command list and the received FIS area can only be changed while the port is idle
+        drive.stop().map_err(|_| "AHCI port does not stop")?;
+        drive.write(PORT_CLB, drive.command_list as u32);
+        drive.write(PORT_CLBU, (drive.command_list >> 32) as u32);
+        drive.write(PORT_FB, (drive.command_list + 0x400) as u32);
+        drive.write(PORT_FBU, ((drive.command_list + 0x400) >> 32) as u32);
+        drive.write(PORT_SERR, u32::MAX);
+        drive.write(PORT_IS, u32::MAX);
+        drive.start().map_err(|_| "AHCI port does not start")?;
+        Ok(drive)
+    }
+
+    fn read(&self, register: u64) -> u32 {
+        read_register(self.port + register)
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        write_register(self.port + register, value)
+    }
+
+    fn stop(&self) -> Result<(), DriveError> {
+        self.write(PORT_CMD, self.read(PORT_CMD) & !(CMD_ST | CMD_FRE));
+        poll(|| self.read(PORT_CMD) & (CMD_CR | CMD_FR) == 0)
+    }
+
+    fn start(&self) -> Result<(), DriveError> {
+        poll(|| self.read(PORT_CMD) & CMD_CR == 0)?;
+        self.write(PORT_CMD, self.read(PORT_CMD) | CMD_FRE);
+        self.write(PORT_CMD, self.read(PORT_CMD) | CMD_ST);
+        Ok(())
+    }
+
+    // Restarts the port after a failed command, which clears its command slots and errors
+    fn recover(&self) {
+        let _ = self.stop();
+        self.write(PORT_SERR, u32::MAX);
+        self.write(PORT_IS, u32::MAX);
+        let _ = self.start();
+    }
+
+    // Issues an ATA command in slot 0, transferring len bytes from or to the DMA buffer, and polls
+    // until it completes. A task file error fails with Io, and a port that does not complete the
+    // command with Timeout
+    fn issue(
+        &self,
+        command: u8,
+        lba: u64,
+        sector_count: u64,
+        len: usize,
+        write: bool,
+    ) -> Result<(), DriveError> {
+        let prd_count = len.div_ceil(0x1000);
+        unsafe {
+            *(self.command_list as *mut CommandHeader) = CommandHeader {
+                flags: (size_of::<FisRegH2D>() / 4) as u16 | if write { HEADER_WRITE } else { 0 },
+                prdt_length: prd_count as u16,
+                prd_byte_count: 0,
+                command_table: self.command_table,
+                _reserved: [0; 4],
+            };
+
+            *(self.command_table as *mut FisRegH2D) = FisRegH2D {
+                fis_type: FIS_TYPE_REG_H2D,
+                flags: 1 << 7,
+                command,
+                feature_low: 0,
+                lba0: lba as u8,
+                lba1: (lba >> 8) as u8,
+                lba2: (lba >> 16) as u8,
+                device: 1 << 6, // LBA mode
+                lba3: (lba >> 24) as u8,
+                lba4: (lba >> 32) as u8,
+                lba5: (lba >> 40) as u8,
+                feature_high: 0,
+                count: sector_count as u16,
+                icc: 0,
+                control: 0,
+                _reserved: 0,
+            };
+
+            let prdt = (self.command_table + COMMAND_TABLE_PRDT) as *mut Prd;
+            for (i, (frame, size)) in self.buffer.regions(len).enumerate() {
+                *prdt.add(i) = Prd {
+                    address: frame,
+                    _reserved: 0,
+                    byte_count: (size - 1) as u32,
+                };
+            }
+        }
+
+        let result = poll(|| self.read(PORT_TFD) & (TFD_BSY | TFD_DRQ) == 0).and_then(|()| {
+            self.write(PORT_IS, u32::MAX);
+            self.write(PORT_CI, 1);
+            poll(|| self.read(PORT_CI) & 1 == 0 || self.read(PORT_IS) & IS_TFES != 0)?;
+            if self.read(PORT_IS) & IS_TFES != 0 {
+                return Err(DriveError::Io);
+            }
+            Ok(())
+        });
+        if result.is_err() {
+            self.recover();
+        }
+        result
+    }
+
+    // Splits the transfer in chunks that fit in the DMA buffer
+    fn transfer(
+        &self,
+        lba: u64,
+        sector_count: u64,
+        buffer: *mut u8,
+        write: bool,
+    ) -> Result<(), DriveError> {
+        let mut done = 0;
+        while done < sector_count {
+            let count = u64::min(sector_count - done, DMA_MAX_SECTORS);
+            let len = count as usize * SECTOR_SIZE;
+            let chunk = unsafe { buffer.add(done as usize * SECTOR_SIZE) };
+
+            if write {
+                self.buffer.copy_from(chunk, len);
+                self.issue(ATA_WRITE_DMA_EXT, lba + done, count, len, true)?;
+            } else {
+                self.issue(ATA_READ_DMA_EXT, lba + done, count, len, false)?;
+                self.buffer.copy_to(chunk, len);
+            }
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+impl Drive for AhciDrive {
+    fn read_sectors(&self, lba: u64, sector_count: u64, buffer: *mut u8) -> Result<(), DriveError> {
+        self.transfer(lba, sector_count, buffer, false)
+    }
+
+    fn write_sectors(
+        &self,
+        lba: u64,
+        sector_count: u64,
+        buffer: *const u8,
+    ) -> Result<(), DriveError> {
+        self.transfer(lba, sector_count, buffer as *mut u8, true)
+    }
+
+    fn flush(&self) -> Result<(), DriveError> {
+        self.issue(ATA_FLUSH_CACHE_EXT, 0, 0, 0, false)
+    }
+}
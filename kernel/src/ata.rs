@@ -2,7 +2,12 @@
 
 use super::Mutex;
 use crate::drive::*;
+use crate::memory::{alloc_dma_frame, DmaBuffer};
+use crate::pci::{self, Bar, PciDriver, PciInfo, PciMatch};
+use crate::pic8259;
 use crate::utils::*;
+use alloc::sync::Arc;
+use core::arch::asm;
 
 const PRIMARY_IO_BASE: u16 = 0x1F0;
 const PRIMARY_CONTROL_BASE: u16 = 0x3F6;
@@ -10,16 +15,281 @@ const PRIMARY_CONTROL_BASE: u16 = 0x3F6;
 const SECONDARY_IO_BASE: u16 = 0x170;
 const SECONDARY_CONTROL_BASE: u16 = 0x376;
 
-pub static ATA_DRIVE_48: Mutex<AtaDrive48> = Mutex::new(AtaDrive48::new());
+const PRIMARY_IRQ: u8 = 14;
+const SECONDARY_IRQ: u8 = 15;
 
-pub fn init() -> Result<(), &'static str> {
-    if AtaBus::primary().get_status() == 0xff {
-        return Err("Primary bus floating");
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+
+const STATUS_BSY: u8 = 1 << 7;
+
+// Drive of the primary and of the secondary channel, used by their IRQ handlers
+static CHANNELS: Mutex<[Option<Arc<QueuedDrive<AtaChannel>>>; 2]> = Mutex::new([None, None]);
+
+// Binds to IDE controllers that can do bus master DMA. The drives are on the legacy ports anyway
+static DRIVER: PciDriver = PciDriver {
+    name: "ide",
+    matches: &[PciMatch::Class(0x01, 0x01)],
+    probe,
+};
+
+// Base of the bus master registers of the bound IDE controller
+static BUS_MASTER_BASE: Mutex<Option<u16>> = Mutex::new(None);
+
+fn probe(info: &PciInfo) -> Result<(), &'static str> {
+    if info.prog_if & (1 << 7) == 0 {
+        return Err("IDE controller does not support bus mastering");
+    }
+    let port = match info.bars[4] {
+        Some(Bar::Io { port, .. }) => port,
+        _ => return Err("Bus master registers are not in I/O space"),
+    };
+
+    let mut base = BUS_MASTER_BASE.lock();
+    if base.is_some() {
+        return Err("An IDE controller is already bound");
     }
-    *ATA_DRIVE_48.lock() = AtaBus::primary().identify(DriveSelector::Master)?;
+    info.device.enable_decoding();
+    info.device.enable_bus_master();
+    *base = Some(port);
     Ok(())
 }
 
+// Finds the master drive of the primary channel, or else of the secondary one. It uses bus master
+// DMA if the IDE controller supports it, and PIO otherwise. Transfers are queued, and DMA ones are
+// completed by the IRQ of the channel
+pub fn init() -> Result<Arc<QueuedDrive<AtaChannel>>, &'static str> {
+    pci::register_driver(&DRIVER);
+    let mut error = "No ATA drive";
+    for (channel, (bus, irq)) in [
+        (AtaBus::primary(), PRIMARY_IRQ),
+        (AtaBus::secondary(), SECONDARY_IRQ),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        if bus.get_status() == 0xff {
+            error = "Bus floating";
+            continue;
+        }
+        let drive = match bus.identify(DriveSelector::Master) {
+            Ok(drive) => drive,
+            Err(e) => {
+                error = e;
+                continue;
+            }
+        };
+
+        let drive = Arc::new(QueuedDrive::new(AtaChannel {
+            drive,
+            bus_master: BusMaster::new(channel as u16).ok(),
+        }));
+        CHANNELS.lock()[channel] = Some(drive.clone());
+        pic8259::enable_irq(irq);
+        return Ok(drive);
+    }
+    Err(error)
+}
+
+// Called by the IRQ 14 and 15 handlers, with the channel 0 for the primary one. Reading the status
+// register acknowledges the interrupt on the drive
+pub fn handle_irq(channel: usize) {
+    [AtaBus::primary(), AtaBus::secondary()][channel].get_status();
+    if let Some(drive) = CHANNELS.lock()[channel].as_ref() {
+        drive.handle_irq();
+    }
+}
+
+// Bus master IDE registers of a channel, relative to BAR4 of the controller plus 8 for the
+// secondary channel
+const BM_COMMAND: u16 = 0;
+const BM_STATUS: u16 = 2;
+const BM_PRDT: u16 = 4;
+const BM_CHANNEL_SIZE: u16 = 8;
+
+const BM_COMMAND_START: u8 = 1 << 0;
+const BM_COMMAND_READ: u8 = 1 << 3; // The controller writes to memory
+const BM_STATUS_ERROR: u8 = 1 << 1;
+const BM_STATUS_INTERRUPT: u8 = 1 << 2;
+
+const PRD_END_OF_TABLE: u16 = 1 << 15;
+// Each PRD of the table covers one frame of the DMA buffer
+const DMA_MAX_SECTORS: u64 = (DmaBuffer::SIZE / SECTOR_SIZE) as u64;
+
+// Physical region descriptor. Addresses are physical, and must be below 4 GiB
+#[repr(C)]
+struct Prd {
+    address: u32,
+    byte_count: u16,
+    flags: u16,
+}
+
+struct BusMaster {
+    io_base: u16,
+    prdt: u64,
+    buffer: DmaBuffer,
+}
+
+impl BusMaster {
+    // Sets up the bus master DMA engine of a channel of the IDE controller, 0 for the primary one
+    fn new(channel: u16) -> Result<BusMaster, &'static str> {
+        let base = BUS_MASTER_BASE
+            .lock()
+            .ok_or("No IDE controller with bus mastering")?;
+
+        Ok(BusMaster {
+            io_base: base + channel * BM_CHANNEL_SIZE,
+            prdt: alloc_dma_frame(true)?,
+            buffer: DmaBuffer::new(true)?,
+        })
+    }
+
+    // Fills the PRD table for a transfer of len bytes, and sets the direction
+    fn prepare(&self, len: usize, write: bool) {
+        let prdt = self.prdt as *mut Prd;
+        let count = len.div_ceil(0x1000);
+        for (i, (frame, size)) in self.buffer.regions(len).enumerate() {
+            unsafe {
+                *prdt.add(i) = Prd {
+                    address: frame as u32,
+                    byte_count: size as u16,
+                    flags: if i + 1 == count { PRD_END_OF_TABLE } else { 0 },
+                };
+            }
+        }
+
+        outd(self.io_base + BM_PRDT, self.prdt as u32);
+        outb(
+            self.io_base + BM_COMMAND,
+            if write { 0 } else { BM_COMMAND_READ },
+        );
+        // The interrupt and error bits are cleared by writing 1 to them
+        outb(
+            self.io_base + BM_STATUS,
+            BM_STATUS_INTERRUPT | BM_STATUS_ERROR,
+        );
+    }
+
+    // Starts the transfer of the command that has just been sent. The drive raises the IRQ of the
+    // channel once it is done
+    fn start(&self, write: bool) {
+        let command = if write { 0 } else { BM_COMMAND_READ };
+        outb(self.io_base + BM_COMMAND, command | BM_COMMAND_START);
+    }
+
+    fn status(&self) -> u8 {
+        inb(self.io_base + BM_STATUS)
+    }
+
+    // Stops the engine once the transfer is done, and clears its interrupt and error bits
+    fn stop(&self) {
+        let command = inb(self.io_base + BM_COMMAND);
+        outb(self.io_base + BM_COMMAND, command & !BM_COMMAND_START);
+        outb(
+            self.io_base + BM_STATUS,
+            BM_STATUS_INTERRUPT | BM_STATUS_ERROR,
+        );
+    }
+}
+
+// The drive of a channel, and the bus master DMA engine of the channel if the IDE controller has
+// one. Without it, and when the engine fails, sectors are transferred with PIO
+pub struct AtaChannel {
+    drive: AtaDrive48,
+    bus_master: Option<BusMaster>,
+}
+
+impl AtaChannel {
+    pub fn uses_dma(&self) -> bool {
+        self.bus_master.is_some()
+    }
+
+    // Transfers the chunk of the request starting at request.done with PIO, polling the drive
+    fn transfer_pio(&self, request: &mut Request, sectors: u64) -> Result<(), DriveError> {
+        let lba = request.lba + request.done;
+        let offset = request.done as usize * SECTOR_SIZE;
+        let chunk = &mut request.data[offset..offset + sectors as usize * SECTOR_SIZE];
+        match request.kind {
+            RequestKind::Read => self.drive.read_sectors(lba, sectors, chunk.as_mut_ptr()),
+            RequestKind::Write => self.drive.write_sectors(lba, sectors, chunk.as_ptr()),
+            RequestKind::Flush => self.drive.flush(),
+        }
+    }
+}
+
+impl Controller for AtaChannel {
+    fn start(&self, request: &mut Request) -> Chunk {
+        let sectors = u64::min(request.sector_count - request.done, DMA_MAX_SECTORS);
+        let Some(bus_master) = &self.bus_master else {
+            return Chunk::Done(sectors, self.transfer_pio(request, sectors));
+        };
+
+        let bus = self.drive.ata_bus;
+        match request.kind {
+            RequestKind::Flush => {
+                // Also selects the drive
+                self.drive.set_lba(0, 0);
+                bus.set_command(ATA_FLUSH_CACHE_EXT);
+            }
+            RequestKind::Read | RequestKind::Write => {
+                let write = request.kind == RequestKind::Write;
+                let len = sectors as usize * SECTOR_SIZE;
+                if write {
+                    let offset = request.done as usize * SECTOR_SIZE;
+                    bus_master
+                        .buffer
+                        .copy_from(request.data[offset..].as_ptr(), len);
+                }
+                bus_master.prepare(len, write);
+                self.drive.set_lba(request.lba + request.done, sectors);
+                bus.set_command(if write {
+                    ATA_WRITE_DMA_EXT
+                } else {
+                    ATA_READ_DMA_EXT
+                });
+                bus_master.start(write);
+            }
+        }
+        Chunk::Running(sectors)
+    }
+
+    fn finish(&self, request: &mut Request, sectors: u64) -> Option<Result<(), DriveError>> {
+        let bus_master = self.bus_master.as_ref()?;
+        let bus = self.drive.ata_bus;
+        if request.kind == RequestKind::Flush {
+            if bus.get_status() & STATUS_BSY != 0 {
+                return None;
+            }
+            return Some(bus.check_error());
+        }
+
+        // The interrupt bit stays set until it is cleared, unlike the IRQ, which may also have
+        // been raised by an earlier PIO transfer
+        let status = bus_master.status();
+        if status & BM_STATUS_INTERRUPT == 0 {
+            return None;
+        }
+        bus_master.stop();
+        if status & BM_STATUS_ERROR != 0 {
+            // The controller failed to access memory, so the chunk is transferred again with PIO
+            return Some(self.transfer_pio(request, sectors));
+        }
+        if let Err(e) = bus.check_error() {
+            return Some(Err(e));
+        }
+
+        if request.kind == RequestKind::Read {
+            let offset = request.done as usize * SECTOR_SIZE;
+            bus_master.buffer.copy_to(
+                request.data[offset..].as_mut_ptr(),
+                sectors as usize * SECTOR_SIZE,
+            );
+        }
+        Some(Ok(()))
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct AtaDrive28 {
     ata_bus: AtaBus,
@@ -36,7 +306,7 @@ impl AtaDrive28 {
 }
 
 impl Drive for AtaDrive28 {
-    fn read_sectors(&self, lba: u64, sector_count: u64, buffer: *mut u8) {
+    fn read_sectors(&self, lba: u64, sector_count: u64, buffer: *mut u8) -> Result<(), DriveError> {
         let buffer = buffer as *mut u16;
         self.ata_bus.set_sector_count(sector_count as u8);
         self.ata_bus.set_lba_low(lba as u8);
@@ -53,13 +323,7 @@ impl Drive for AtaDrive28 {
         self.ata_bus.set_command(0x20);
 
         self.ata_bus.delay_400ns();
-        if self.ata_bus.get_status() & 1 == 1 {
-            panic!(
-                "Error while reading drive {:?}: 0b{:b}",
-                self.drive_selector,
-                self.ata_bus.get_error()
-            );
-        }
+        self.ata_bus.check_error()?;
 
         for sector in 0..sector_count {
             // Wait for the drive to be ready to transfer data
@@ -73,6 +337,47 @@ impl Drive for AtaDrive28 {
             }
             self.ata_bus.delay_400ns();
         }
+        Ok(())
+    }
+
+    fn write_sectors(
+        &self,
+        lba: u64,
+        sector_count: u64,
+        buffer: *const u8,
+    ) -> Result<(), DriveError> {
+        let buffer = buffer as *const u16;
+        self.ata_bus.set_sector_count(sector_count as u8);
+        self.ata_bus.set_lba_low(lba as u8);
+        self.ata_bus.set_lba_mid((lba >> (8 * 1)) as u8);
+        self.ata_bus.set_lba_high((lba >> (8 * 2)) as u8);
+        match self.drive_selector {
+            DriveSelector::Master => {
+                self.ata_bus.set_drive(0xe0);
+            }
+            DriveSelector::Slave => {
+                self.ata_bus.set_drive(0xf0);
+            }
+        }
+        self.ata_bus.set_command(0x30);
+
+        self.ata_bus.delay_400ns();
+        self.ata_bus.check_error()?;
+
+        for sector in 0..sector_count {
+            // Wait for the drive to be ready to accept data
+            self.ata_bus.wait_bsy_clear();
+            self.ata_bus.wait_drq_set();
+
+            for i in 0..256 {
+                unsafe {
+                    self.ata_bus
+                        .set_data(*buffer.offset(i as isize + sector as isize * 256));
+                }
+            }
+            self.ata_bus.delay_400ns();
+        }
+        self.ata_bus.flush_cache()
     }
 }
 
@@ -89,11 +394,9 @@ impl AtaDrive48 {
             drive_selector: DriveSelector::Master,
         }
     }
-}
 
-impl Drive for AtaDrive48 {
-    fn read_sectors(&self, lba: u64, sector_count: u64, buffer: *mut u8) {
-        let buffer = buffer as *mut u16;
+    // Selects the drive and sets the LBA48 address and sector count of the next command
+    fn set_lba(&self, lba: u64, sector_count: u64) {
         self.ata_bus.set_sector_count((sector_count >> 8) as u8);
         self.ata_bus.set_lba_low((lba >> (8 * 3)) as u8);
         self.ata_bus.set_lba_mid((lba >> (8 * 4)) as u8);
@@ -110,16 +413,18 @@ impl Drive for AtaDrive48 {
                 self.ata_bus.set_drive(0x50);
             }
         }
+    }
+}
+
+// Transfers sectors with PIO
+impl Drive for AtaDrive48 {
+    fn read_sectors(&self, lba: u64, sector_count: u64, buffer: *mut u8) -> Result<(), DriveError> {
+        let buffer = buffer as *mut u16;
+        self.set_lba(lba, sector_count);
         self.ata_bus.set_command(0x24);
 
         self.ata_bus.delay_400ns();
-        if self.ata_bus.get_status() & 1 == 1 {
-            panic!(
-                "Error while reading drive {:?}: 0b{:b}",
-                self.drive_selector,
-                self.ata_bus.get_error()
-            );
-        }
+        self.ata_bus.check_error()?;
 
         for sector in 0..sector_count {
             // Wait for the drive to be ready to transfer data
@@ -133,6 +438,41 @@ impl Drive for AtaDrive48 {
             }
             self.ata_bus.delay_400ns();
         }
+        Ok(())
+    }
+
+    fn write_sectors(
+        &self,
+        lba: u64,
+        sector_count: u64,
+        buffer: *const u8,
+    ) -> Result<(), DriveError> {
+        let buffer = buffer as *const u16;
+        self.set_lba(lba, sector_count);
+        self.ata_bus.set_command(0x34);
+
+        self.ata_bus.delay_400ns();
+        self.ata_bus.check_error()?;
+
+        for sector in 0..sector_count {
+            // Wait for the drive to be ready to accept data
+            self.ata_bus.wait_bsy_clear();
+            self.ata_bus.wait_drq_set();
+
+            for i in 0..256 {
+                unsafe {
+                    self.ata_bus
+                        .set_data(*buffer.offset(i as isize + sector as isize * 256));
+                }
+            }
+            self.ata_bus.delay_400ns();
+        }
+        self.ata_bus.wait_bsy_clear();
+        self.ata_bus.check_error()
+    }
+
+    fn flush(&self) -> Result<(), DriveError> {
+        self.ata_bus.flush_cache_ext()
     }
 }
 
@@ -248,9 +588,24 @@ impl AtaBus {
         while &self.get_status() & (1 << 3) == 0 {}
     }
 
-    pub fn flush_cache(&self) {
+    pub fn flush_cache(&self) -> Result<(), DriveError> {
         self.set_command(0xe7);
         self.wait_bsy_clear();
+        self.check_error()
+    }
+
+    pub fn flush_cache_ext(&self) -> Result<(), DriveError> {
+        self.set_command(0xea);
+        self.wait_bsy_clear();
+        self.check_error()
+    }
+
+    // Fails if the ERR bit of the status is set by the last command
+    pub fn check_error(&self) -> Result<(), DriveError> {
+        if self.get_status() & 1 == 1 {
+            return Err(DriveError::Io);
+        }
+        Ok(())
     }
 
     pub fn identify(&self, drive: DriveSelector) -> Result<AtaDrive48, &'static str> {
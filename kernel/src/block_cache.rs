@@ -0,0 +1,271 @@
+#![allow(unused)]
+
+use super::Mutex;
+use crate::drive::{Drive, DriveError, SECTOR_SIZE};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    // Dirty sectors written back to the drive
+    pub write_backs: u64,
+}
+
+impl CacheStats {
+    pub fn to_bytes(&self) -> [u8; size_of::<CacheStats>()] {
+        let mut bytes = [0u8; size_of::<CacheStats>()];
+        bytes[0..8].copy_from_slice(&self.hits.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.misses.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.evictions.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.write_backs.to_le_bytes());
+        bytes
+    }
+}
+
+struct Block {
+    data: Box<[u8; SECTOR_SIZE]>,
+    dirty: bool,
+    last_used: u64,
+}
+
+struct CacheState {
+    blocks: BTreeMap<u64, Block>,
+    // Sectors by the clock value of their last use, so the first one is the least recently used
+    order: BTreeMap<u64, u64>,
+    // Incremented on every access
+    clock: u64,
+    // Contents and dirty bit of the sectors written since the last flush, from before their first
+    // write, or None for the sectors that were not cached
+    journal: BTreeMap<u64, Option<(Box<[u8; SECTOR_SIZE]>, bool)>>,
+    stats: CacheStats,
+}
+
+impl CacheState {
+    // Marks the cached sector as the most recently used one
+    fn touch(&mut self, lba: u64) {
+        self.clock += 1;
+        let block = self.blocks.get_mut(&lba).unwrap();
+        self.order.remove(&block.last_used);
+        block.last_used = self.clock;
+        self.order.insert(self.clock, lba);
+    }
+
+    fn remove(&mut self, lba: u64) {
+        if let Some(block) = self.blocks.remove(&lba) {
+            self.order.remove(&block.last_used);
+        }
+    }
+
+    // Undoes the writes since the last flush
+    fn rollback(&mut self) {
+        for (lba, before) in core::mem::take(&mut self.journal) {
+            match before {
+                // Dirty sectors are never evicted, so the sector is still cached
+                Some((data, dirty)) => {
+                    let block = self.blocks.get_mut(&lba).unwrap();
+                    block.data = data;
+                    block.dirty = dirty;
+                }
+                None => self.remove(lba),
+            }
+        }
+    }
+}
+
+// Number of sectors kept in the block cache of the drive of a mounted file system
+pub const CACHE_SECTORS: usize = 2048;
+// Sectors an operation can write before they are written back without waiting for flush
+const DIRTY_LIMIT: usize = 1024;
+// Longest run of sectors written back with a single write
+const MAX_WRITE_BACK_RUN: usize = 128;
+
+// Write back cache of the sectors of a drive, with LRU eviction. Writes only reach the drive when
+// flush is called, which filesystems do at the end of every operation. Until then they can be
+// undone: a read that fails, for instance because the calling process has to wait for the drive,
+// undoes the operation, so that it can be run again from the start. Dirty sectors are never
+// evicted, so an operation that writes more than DIRTY_LIMIT sectors has them written back in
+// batches, and can then only be undone back to its last batch. The VFS splits large writes in
+// chunks that stay below the limit
+pub struct BlockCache<D: Drive> {
+    drive: D,
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+impl<D: Drive> BlockCache<D> {
+    pub fn new(drive: D, capacity: usize) -> BlockCache<D> {
+        BlockCache {
+            drive,
+            capacity,
+            state: Mutex::new(CacheState {
+                blocks: BTreeMap::new(),
+                order: BTreeMap::new(),
+                clock: 0,
+                journal: BTreeMap::new(),
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    // Adds a sector to the cache, evicting the least recently used clean one if the cache is full
+    fn insert(&self, state: &mut CacheState, lba: u64, data: &[u8], dirty: bool) {
+        if dirty && !state.journal.contains_key(&lba) {
+            let before = state
+                .blocks
+                .get(&lba)
+                .map(|block| (block.data.clone(), block.dirty));
+            state.journal.insert(lba, before);
+        }
+
+        if let Some(block) = state.blocks.get_mut(&lba) {
+            block.data.copy_from_slice(data);
+            block.dirty |= dirty;
+            state.touch(lba);
+            return;
+        }
+
+        if state.blocks.len() >= self.capacity {
+            let oldest = state
+                .order
+                .values()
+                .find(|lba| !state.blocks[lba].dirty)
+                .copied();
+            if let Some(lba) = oldest {
+                state.remove(lba);
+                state.stats.evictions += 1;
+            }
+        }
+
+        let mut block = Block {
+            data: Box::new([0; SECTOR_SIZE]),
+            dirty,
+            last_used: 0,
+        };
+        block.data.copy_from_slice(data);
+        state.blocks.insert(lba, block);
+        state.touch(lba);
+    }
+
+    // Writes every dirty sector back to the drive, with one write per run of consecutive sectors.
+    // The writes since the last write back can't be undone afterwards
+    fn write_back(&self, state: &mut CacheState) -> Result<(), DriveError> {
+        state.journal.clear();
+
+        let dirty: Vec<u64> = state
+            .blocks
+            .iter()
+            .filter(|(_, block)| block.dirty)
+            .map(|(lba, _)| *lba)
+            .collect();
+        let mut result = Ok(());
+        let mut i = 0;
+        while i < dirty.len() {
+            let mut run = 1;
+            while i + run < dirty.len()
+                && run < MAX_WRITE_BACK_RUN
+                && dirty[i + run] == dirty[i] + run as u64
+            {
+                run += 1;
+            }
+            let mut data = Vec::with_capacity(run * SECTOR_SIZE);
+            for lba in &dirty[i..i + run] {
+                data.extend_from_slice(&state.blocks[lba].data[..]);
+            }
+
+            // Sectors that fail stay dirty, and the others are still written
+            match self
+                .drive
+                .write_sectors(dirty[i], run as u64, data.as_ptr())
+            {
+                Ok(()) => {
+                    for lba in &dirty[i..i + run] {
+                        state.blocks.get_mut(lba).unwrap().dirty = false;
+                    }
+                    state.stats.write_backs += run as u64;
+                }
+                Err(e) => result = Err(e),
+            }
+            i += run;
+        }
+        result
+    }
+}
+
+impl<D: Drive> Drive for BlockCache<D> {
+    // Runs of missing sectors are read from the drive with a single command
+    fn read_sectors(&self, lba: u64, sector_count: u64, buffer: *mut u8) -> Result<(), DriveError> {
+        let buffer =
+            unsafe { core::slice::from_raw_parts_mut(buffer, sector_count as usize * SECTOR_SIZE) };
+        let mut state = self.state.lock();
+
+        let mut i = 0;
+        while i < sector_count {
+            if let Some(block) = state.blocks.get(&(lba + i)) {
+                buffer[i as usize * SECTOR_SIZE..(i as usize + 1) * SECTOR_SIZE]
+                    .copy_from_slice(&block.data[..]);
+                state.touch(lba + i);
+                state.stats.hits += 1;
+                i += 1;
+                continue;
+            }
+
+            let mut run = 1;
+            while i + run < sector_count && !state.blocks.contains_key(&(lba + i + run)) {
+                run += 1;
+            }
+            let data = &mut buffer[i as usize * SECTOR_SIZE..(i + run) as usize * SECTOR_SIZE];
+            if let Err(e) = self.drive.read_sectors(lba + i, run, data.as_mut_ptr()) {
+                state.rollback();
+                return Err(e);
+            }
+            for j in 0..run as usize {
+                self.insert(
+                    &mut state,
+                    lba + i + j as u64,
+                    &data[j * SECTOR_SIZE..(j + 1) * SECTOR_SIZE],
+                    false,
+                );
+            }
+            state.stats.misses += run;
+            i += run;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(
+        &self,
+        lba: u64,
+        sector_count: u64,
+        buffer: *const u8,
+    ) -> Result<(), DriveError> {
+        let buffer =
+            unsafe { core::slice::from_raw_parts(buffer, sector_count as usize * SECTOR_SIZE) };
+        let mut state = self.state.lock();
+        for i in 0..sector_count as usize {
+            self.insert(
+                &mut state,
+                lba + i as u64,
+                &buffer[i * SECTOR_SIZE..(i + 1) * SECTOR_SIZE],
+                true,
+            );
+            if state.journal.len() > DIRTY_LIMIT {
+                self.write_back(&mut state)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), DriveError> {
+        self.write_back(&mut self.state.lock())?;
+        self.drive.flush()
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.state.lock().stats)
+    }
+}
@@ -1,5 +1,437 @@
 #![allow(unused)]
 
+use super::Mutex;
+use crate::block_cache::CacheStats;
+use crate::pic8259::{get_masks, set_masks};
+use crate::process::*;
+use crate::utils::without_interrupts;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::asm;
+
+pub const SECTOR_SIZE: usize = 512;
+// Most bytes of writes kept by a QueuedDrive. Past it, writes wait for the drive to catch up
+const MAX_QUEUED_WRITES: usize = 0x10_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DriveError {
+    // The drive reported an error for the transfer
+    Io,
+    // The drive did not complete the transfer in time
+    Timeout,
+    // The sectors are past the end of the drive
+    OutOfRange,
+    // The read has been queued, and the calling process has to wait for it. The syscall that needed
+    // it is run again once it completes
+    WouldBlock,
+}
+
 pub trait Drive {
-    fn read_sectors(&self, lba: u64, sector_count: u64, buffer: *mut u8);
+    fn read_sectors(&self, lba: u64, sector_count: u64, buffer: *mut u8) -> Result<(), DriveError>;
+    fn write_sectors(
+        &self,
+        lba: u64,
+        sector_count: u64,
+        buffer: *const u8,
+    ) -> Result<(), DriveError>;
+    // Makes sure that every completed write has reached the drive
+    fn flush(&self) -> Result<(), DriveError> {
+        Ok(())
+    }
+    // Hit and miss counters of the drive, if it is a block cache
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+}
+
+// Lets a drive be shared with its IRQ handler
+impl<D: Drive> Drive for Arc<D> {
+    fn read_sectors(&self, lba: u64, sector_count: u64, buffer: *mut u8) -> Result<(), DriveError> {
+        (**self).read_sectors(lba, sector_count, buffer)
+    }
+
+    fn write_sectors(
+        &self,
+        lba: u64,
+        sector_count: u64,
+        buffer: *const u8,
+    ) -> Result<(), DriveError> {
+        (**self).write_sectors(lba, sector_count, buffer)
+    }
+
+    fn flush(&self) -> Result<(), DriveError> {
+        (**self).flush()
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        (**self).cache_stats()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RequestKind {
+    Read,
+    Write,
+    // Makes sure that every write queued before it has reached the drive
+    Flush,
+}
+
+// A transfer queued on a QueuedDrive
+pub struct Request {
+    pub kind: RequestKind,
+    pub lba: u64,
+    pub sector_count: u64,
+    // The sectors to write, or the sectors read
+    pub data: Vec<u8>,
+    // Number of sectors already transferred
+    pub done: u64,
+    id: u64,
+    // Process that waits for the read. Reads during boot, writes and flushes have none
+    owner: Option<u32>,
+    // Set once the request has completed
+    result: Option<Result<(), DriveError>>,
+    // Set when a later write overlaps the read, whose data is then out of date
+    stale: bool,
+}
+
+impl Request {
+    fn overlaps(&self, lba: u64, sector_count: u64) -> bool {
+        self.lba < lba + sector_count && lba < self.lba + self.sector_count
+    }
+
+    // Accounts for a transferred chunk. Returns whether the request completed and a process waits
+    // for it
+    fn end_chunk(
+        &mut self,
+        sectors: u64,
+        result: Result<(), DriveError>,
+        write_error: &mut Option<DriveError>,
+    ) -> bool {
+        self.done += sectors;
+        if result.is_ok() && self.done < self.sector_count {
+            return false;
+        }
+        if let Err(e) = result {
+            if self.kind != RequestKind::Read {
+                write_error.get_or_insert(e);
+            }
+        }
+        self.result = Some(result);
+        self.owner.is_some()
+    }
+}
+
+// Result of starting the transfer of a chunk of a request
+pub enum Chunk {
+    // The chunk of sectors is being transferred, and the controller raises its IRQ once it is done
+    Running(u64),
+    // The chunk of sectors was transferred synchronously, or failed to start
+    Done(u64, Result<(), DriveError>),
+}
+
+// Hardware side of a QueuedDrive, which transfers the requests one chunk at a time
+pub trait Controller {
+    // Starts the transfer of the sectors of the request from request.done on
+    fn start(&self, request: &mut Request) -> Chunk;
+    // Ends the running chunk of sectors, once the IRQ of the controller is taken. Returns None if
+    // the chunk is still running, since the IRQ may be shared or left pending
+    fn finish(&self, request: &mut Request, sectors: u64) -> Option<Result<(), DriveError>>;
+}
+
+struct DriveQueue {
+    // In order of submission. Completed reads stay until the process that waits for them takes
+    // them, and other completed requests until the queue is next used outside of the IRQ handler,
+    // which must not free memory
+    requests: VecDeque<Request>,
+    // Id of the request being transferred and size of its running chunk
+    running: Option<(u64, u64)>,
+    next_id: u64,
+    // First error of a write or a flush, reported by the next flush
+    write_error: Option<DriveError>,
+}
+
+impl DriveQueue {
+    // Drops the completed requests nobody waits for: writes, flushes, and reads that are stale or
+    // whose process has ended
+    fn collect(&mut self) {
+        self.requests.retain(|request| {
+            request.result.is_none()
+                || request.kind == RequestKind::Read
+                    && !request.stale
+                    && request
+                        .owner
+                        .is_some_and(|pid| PROCESS_LIST.lock().is_alive(pid))
+        });
+    }
+
+    // Bytes held by the queued writes, completed ones included until they are collected
+    fn write_bytes(&self) -> usize {
+        self.requests
+            .iter()
+            .filter(|request| request.kind == RequestKind::Write)
+            .map(|request| request.data.len())
+            .sum()
+    }
+
+    fn take(&mut self, id: u64) -> Option<Request> {
+        let index = self
+            .requests
+            .iter()
+            .position(|request| request.id == id && request.result.is_some())?;
+        self.requests.remove(index)
+    }
+}
+
+// A drive whose transfers are queued and run by a controller in the background. A process that
+// reads from it is blocked until the read completes, and then runs the syscall again, which finds
+// the sectors it asked for. Writes and flushes return at once, and their errors are reported by the
+// next flush. During boot there is no process to block, and reads halt until they complete.
+// The queue is only locked with interrupts disabled, since the IRQ handler uses it
+pub struct QueuedDrive<C: Controller> {
+    controller: C,
+    queue: Mutex<DriveQueue>,
+}
+
+impl<C: Controller> QueuedDrive<C> {
+    pub fn new(controller: C) -> QueuedDrive<C> {
+        QueuedDrive {
+            controller,
+            queue: Mutex::new(DriveQueue {
+                requests: VecDeque::new(),
+                running: None,
+                next_id: 0,
+                write_error: None,
+            }),
+        }
+    }
+
+    pub fn controller(&self) -> &C {
+        &self.controller
+    }
+
+    // Queues a request and starts it if the controller is idle. Returns its id, and whether a
+    // process has to be woken up
+    fn submit(
+        &self,
+        queue: &mut DriveQueue,
+        kind: RequestKind,
+        lba: u64,
+        sector_count: u64,
+        data: Vec<u8>,
+        owner: Option<u32>,
+    ) -> (u64, bool) {
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.requests.push_back(Request {
+            kind,
+            lba,
+            sector_count,
+            data,
+            done: 0,
+            id,
+            owner,
+            result: None,
+            stale: false,
+        });
+        (id, self.start_next(queue))
+    }
+
+    // Starts the next chunk, unless one is already running. Returns whether a request that a
+    // process waits for completed
+    fn start_next(&self, queue: &mut DriveQueue) -> bool {
+        let mut woken = false;
+        while queue.running.is_none() {
+            let Some(request) = queue.requests.iter_mut().find(|r| r.result.is_none()) else {
+                break;
+            };
+            match self.controller.start(request) {
+                Chunk::Running(sectors) => queue.running = Some((request.id, sectors)),
+                Chunk::Done(sectors, result) => {
+                    woken |= request.end_chunk(sectors, result, &mut queue.write_error);
+                }
+            }
+        }
+        woken
+    }
+
+    // Called by the IRQ handler of the controller. Ends the running chunk and starts the next one
+    pub fn handle_irq(&self) {
+        let woken = {
+            let mut queue = self.queue.lock();
+            let queue = &mut *queue;
+            let Some((id, sectors)) = queue.running else {
+                return;
+            };
+            let Some(request) = queue.requests.iter_mut().find(|r| r.id == id) else {
+                return;
+            };
+            let Some(result) = self.controller.finish(request, sectors) else {
+                return;
+            };
+            queue.running = None;
+            let woken = request.end_chunk(sectors, result, &mut queue.write_error);
+            woken | self.start_next(queue)
+        };
+        if woken {
+            wake_readers();
+        }
+    }
+
+    // Returns the read of the process if it has completed. Otherwise queues it, unless it already
+    // is, and fails with WouldBlock
+    fn read_for(&self, pid: u32, lba: u64, sector_count: u64) -> Result<Request, DriveError> {
+        let (request, woken) = without_interrupts(|| {
+            let mut queue = self.queue.lock();
+            queue.collect();
+            let queued = queue.requests.iter().find(|r| {
+                r.kind == RequestKind::Read
+                    && r.owner == Some(pid)
+                    && !r.stale
+                    && r.lba == lba
+                    && r.sector_count == sector_count
+            });
+            if let Some(id) = queued.map(|r| r.id) {
+                return (queue.take(id), false);
+            }
+
+            // The syscall has moved on to other sectors, so the earlier reads are not needed
+            for request in queue.requests.iter_mut().filter(|r| r.owner == Some(pid)) {
+                request.stale = true;
+            }
+            let data = vec![0; sector_count as usize * SECTOR_SIZE];
+            let (id, woken) = self.submit(
+                &mut queue,
+                RequestKind::Read,
+                lba,
+                sector_count,
+                data,
+                Some(pid),
+            );
+            (queue.take(id), woken)
+        });
+        if woken {
+            wake_readers();
+        }
+        request.ok_or(DriveError::WouldBlock)
+    }
+
+    // Halts until the queued writes hold at most MAX_QUEUED_WRITES bytes. The timer is masked
+    // meanwhile, since it would switch to another process in the middle of a syscall
+    fn wait_for_writes(&self) {
+        let (master, slave) = get_masks();
+        set_masks(master | 1, slave);
+        without_interrupts(|| loop {
+            {
+                let mut queue = self.queue.lock();
+                queue.collect();
+                if queue.write_bytes() <= MAX_QUEUED_WRITES {
+                    break;
+                }
+            }
+            // sti only takes effect after hlt, so the IRQ can not be missed
+            unsafe { asm!("sti", "hlt", "cli") };
+        });
+        set_masks(master, slave);
+    }
+
+    // Queues a read and halts until it completes. Used during boot, which runs with interrupts
+    // enabled
+    fn read_during_boot(&self, lba: u64, sector_count: u64) -> Request {
+        let data = vec![0; sector_count as usize * SECTOR_SIZE];
+        let (id, _) = without_interrupts(|| {
+            let mut queue = self.queue.lock();
+            queue.collect();
+            self.submit(&mut queue, RequestKind::Read, lba, sector_count, data, None)
+        });
+
+        loop {
+            unsafe { asm!("cli") };
+            if let Some(request) = self.queue.lock().take(id) {
+                unsafe { asm!("sti") };
+                return request;
+            }
+            // sti only takes effect after hlt, so the IRQ can not be missed
+            unsafe { asm!("sti", "hlt") };
+        }
+    }
+}
+
+impl<C: Controller> Drive for QueuedDrive<C> {
+    fn read_sectors(&self, lba: u64, sector_count: u64, buffer: *mut u8) -> Result<(), DriveError> {
+        if sector_count == 0 {
+            return Ok(());
+        }
+        let request = match current_pid() {
+            Some(pid) => self.read_for(pid, lba, sector_count)?,
+            None => self.read_during_boot(lba, sector_count),
+        };
+        request.result.unwrap()?;
+
+        let buffer =
+            unsafe { core::slice::from_raw_parts_mut(buffer, sector_count as usize * SECTOR_SIZE) };
+        buffer.copy_from_slice(&request.data);
+        Ok(())
+    }
+
+    // The data is copied, and written once the requests before it are done. Waits first if the
+    // queue already holds too many writes
+    fn write_sectors(
+        &self,
+        lba: u64,
+        sector_count: u64,
+        buffer: *const u8,
+    ) -> Result<(), DriveError> {
+        if sector_count == 0 {
+            return Ok(());
+        }
+        self.wait_for_writes();
+        let data =
+            unsafe { core::slice::from_raw_parts(buffer, sector_count as usize * SECTOR_SIZE) }
+                .to_vec();
+        let (_, woken) = without_interrupts(|| {
+            let mut queue = self.queue.lock();
+            queue.collect();
+            // Reads queued before the write would return the old sectors
+            for request in queue
+                .requests
+                .iter_mut()
+                .filter(|r| r.kind == RequestKind::Read && r.overlaps(lba, sector_count))
+            {
+                request.stale = true;
+            }
+            self.submit(
+                &mut queue,
+                RequestKind::Write,
+                lba,
+                sector_count,
+                data,
+                None,
+            )
+        });
+        if woken {
+            wake_readers();
+        }
+        Ok(())
+    }
+
+    // Queues a flush, and reports the first error of the writes and flushes queued before it
+    fn flush(&self) -> Result<(), DriveError> {
+        let (woken, error) = without_interrupts(|| {
+            let mut queue = self.queue.lock();
+            queue.collect();
+            let (_, woken) = self.submit(&mut queue, RequestKind::Flush, 0, 0, Vec::new(), None);
+            (woken, queue.write_error.take())
+        });
+        if woken {
+            wake_readers();
+        }
+        error.map_or(Ok(()), Err)
+    }
+}
+
+// Wakes the processes waiting for a drive, so that they look for their reads again
+fn wake_readers() {
+    PROCESS_LIST.lock().wake(&WaitEvent::Disk);
 }
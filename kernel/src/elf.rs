@@ -63,8 +63,9 @@ impl ElfExecutable {
         out
     }
 
-    // Loads segment at the correct virtual address and refreshes cr3
-    fn load_segment(&self, segment: u64) -> VirtualMapping {
+    // Loads segment at the correct virtual address of the given address space. Returns None if the
+    // segment overlaps memory of the kernel
+    fn load_segment(&self, segment: u64, space: &mut AddressSpace) -> Option<VirtualMapping> {
         let segment = &self.get_segments()[segment as usize];
         let file_offset = segment.file_offset;
         let file_size = segment.file_size;
@@ -73,30 +74,29 @@ impl ElfExecutable {
         let vaddr = segment.virtual_address;
         let memory_size = segment.memory_size;
 
-        let page_count = (memory_size + (vaddr & 0xfff)) as usize / 0x1000 + 1;
+        let page_count = (memory_size.checked_add(vaddr & 0xfff)? / 0x1000 + 1) as usize;
+        if !can_map_user(vaddr & !0xfff, page_count as u64 * 0x1000) {
+            return None;
+        }
         let mut out = VirtualMapping::new(vaddr & !0xfff, Vec::with_capacity(page_count));
         for i in 0..page_count {
             out.frames
                 .push(MEMORY_MANAGER.lock().physical_map.alloc_frame());
             clear_page(out.frames[i as usize]);
         }
-        let plm4 = MEMORY_MANAGER.lock().get_plm4();
-        plm4.map_mapping(&out);
-        MEMORY_MANAGER.lock().set_plm4(plm4);
-
-        for i in 0..page_count {
-            clear_page(out.frames[i as usize]);
-        }
+        space.map_mapping_user(&out);
 
-        // Copy segment
+        // Copy segment. The target address space is not the current one, so the frames are
+        // written through the identity map, which needs the kernel plm4 to be loaded
         for i in 0..file_size {
+            let offset = (vaddr & 0xfff) + i;
             unsafe {
-                *(vaddr as *mut u8).offset(i as isize) =
+                *((out.frames[(offset / 0x1000) as usize] + offset % 0x1000) as *mut u8) =
                     *(self.mapping.vaddr as *const u8).offset(file_offset as isize + i as isize);
             }
         }
 
-        out
+        Some(out)
     }
 
     pub fn list_sections(&self) {
@@ -165,18 +165,28 @@ impl ElfExecutable {
         }
     }
 
-    pub fn load_all(&self) -> Vec<VirtualMapping> {
-        let mut out = Vec::new();
+    // Loads every PT_LOAD segment in the address space. Returns None, after freeing the segments
+    // already loaded, if one of them overlaps memory of the kernel. Has to be called with the
+    // kernel plm4 loaded
+    pub fn load_all(&self, space: &mut AddressSpace) -> Option<Vec<VirtualMapping>> {
+        let mut out: Vec<VirtualMapping> = Vec::new();
         let segments = self.get_segments();
 
         for (i, segment) in segments.iter().enumerate() {
             let t = segment.segment_type;
             if t == ElfSegmentType::Load {
-                out.push(self.load_segment(i as u64));
+                let Some(mapping) = self.load_segment(i as u64, space) else {
+                    for mapping in &out {
+                        space.unmap_mapping(mapping);
+                        mapping.free_frames();
+                    }
+                    return None;
+                };
+                out.push(mapping);
             }
         }
 
-        out
+        Some(out)
     }
 
     pub fn get_entry(&self) -> u64 {
@@ -184,6 +194,14 @@ impl ElfExecutable {
     }
 }
 
+impl Drop for ElfExecutable {
+    // The file buffer is only needed until the segments have been copied
+    fn drop(&mut self) {
+        let mapping = core::mem::replace(&mut self.mapping, VirtualMapping::new(0, Vec::new()));
+        mapping.free_kernel();
+    }
+}
+
 #[repr(C, packed)]
 struct ElfHeader64 {
     magic: [u8; 4],
@@ -0,0 +1,529 @@
+#![allow(unused)]
+
+use super::Mutex;
+use crate::block_cache::{BlockCache, CacheStats, CACHE_SECTORS};
+use crate::drive::{Drive, SECTOR_SIZE};
+use crate::fs::*;
+use crate::vfs::VFS;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const ROOT_INODE: Inode = 0;
+const ENTRY_SIZE: usize = 32;
+// Values of the FAT at or above this one end a chain, or mark a bad cluster
+const END_OF_CHAIN: u32 = 0xffff_fff7;
+// Cursor of read_dir past the last entry of a directory
+const END_OF_DIRECTORY: u64 = u64::MAX;
+// Names of up to 255 characters take 17 file name entries, which follow the stream extension
+const MAX_SECONDARY: usize = 18;
+
+// Entry types, with the in use bit set
+const ENTRY_END: u8 = 0x00;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM_EXTENSION: u8 = 0xc0;
+const ENTRY_FILE_NAME: u8 = 0xc1;
+
+// Set in the flags of stream extensions when the clusters of the file are contiguous, and the FAT
+// is not used for them
+const NO_FAT_CHAIN: u8 = 0x02;
+const FILE_NAME_CHARS: usize = 15;
+
+// Sectors are 512 bytes, and clusters at most 32 MiB
+const BYTES_PER_SECTOR_SHIFT: u8 = 9;
+const MAX_CLUSTER_SHIFT: u8 = 25;
+
+// Mounts the exFAT file system of the drive at path. exFAT volumes are read only
+pub fn init<D: Drive + Send + 'static>(drive: D, path: &str) -> Result<(), ()> {
+    if !is_exfat(&drive) {
+        return Err(());
+    }
+    let fs = ExFatFs::new(BlockCache::new(drive, CACHE_SECTORS)).map_err(|_| ())?;
+    VFS.lock().mount(path, Box::new(fs)).map_err(|_| ())
+}
+
+// Checks the file system name of the boot sector, and the sizes of sectors and clusters
+pub fn is_exfat<D: Drive>(drive: &D) -> bool {
+    let mut sector = [0u8; SECTOR_SIZE];
+    if drive.read_sectors(0, 1, sector.as_mut_ptr()).is_err() {
+        return false;
+    }
+    &sector[3..11] == b"EXFAT   " && valid_shifts(&sector) && sector[510..512] == [0x55, 0xaa]
+}
+
+// The sizes of sectors and clusters are stored as shifts, which are only valid in a small range
+fn valid_shifts(boot_sector: &[u8; SECTOR_SIZE]) -> bool {
+    boot_sector[108] == BYTES_PER_SECTOR_SHIFT
+        && boot_sector[109] <= MAX_CLUSTER_SHIFT - BYTES_PER_SECTOR_SHIFT
+}
+
+// Clusters of a file or directory. Contiguous extents do not use the FAT, and need their length
+#[derive(Debug, Clone, Copy)]
+struct Extent {
+    first: u32,
+    contiguous: bool,
+    length: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    // Index of the file entry in its directory
+    index: usize,
+    // Number of entries of the set that follow the file entry
+    secondary_count: usize,
+    name: String,
+    attributes: u16,
+    extent: Extent,
+    // Bytes past the valid data length are read as zeros
+    valid_length: u64,
+    created: u32,
+    modified: u32,
+    accessed: u32,
+    created_10ms: u8,
+}
+
+impl Entry {
+    fn is_directory(&self) -> bool {
+        self.attributes as u8 & ATTRIBUTE_DIRECTORY != 0
+    }
+
+    // exFAT timestamps have the FAT date in the high half and the FAT time in the low one
+    fn stat(&self) -> Stat {
+        Stat {
+            size: if self.is_directory() {
+                0
+            } else {
+                self.extent.length
+            },
+            attributes: self.attributes as u8,
+            creation_time_hundredths: self.created_10ms,
+            creation_time: self.created as u16,
+            creation_date: (self.created >> 16) as u16,
+            last_accessed_date: (self.accessed >> 16) as u16,
+            last_modification_time: self.modified as u16,
+            last_modification_date: (self.modified >> 16) as u16,
+        }
+    }
+}
+
+pub struct ExFatFs<D: Drive> {
+    drive: D,
+    fat_offset: u64,
+    cluster_heap_offset: u64,
+    sectors_per_cluster: u64,
+    cluster_count: u32,
+    root_cluster: u32,
+    // Extents of the directories seen so far, by first cluster. Inodes only hold the first cluster
+    // of their directory, and the extent is needed to read contiguous directories
+    directories: Mutex<BTreeMap<u32, Extent>>,
+    // The same extents by inode, so that listing a directory does not read its parent every time
+    directory_inodes: Mutex<BTreeMap<Inode, Extent>>,
+}
+
+impl<D: Drive> ExFatFs<D> {
+    pub fn new(drive: D) -> Result<ExFatFs<D>, FsError> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        drive.read_sectors(0, 1, sector.as_mut_ptr())?;
+        if !valid_shifts(&sector) {
+            return Err(FsError::Io);
+        }
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap());
+
+        Ok(ExFatFs {
+            fat_offset: u32_at(80) as u64,
+            cluster_heap_offset: u32_at(88) as u64,
+            cluster_count: u32_at(92),
+            root_cluster: u32_at(96),
+            sectors_per_cluster: 1 << sector[109],
+            drive,
+            directories: Mutex::new(BTreeMap::new()),
+            directory_inodes: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.sectors_per_cluster * SECTOR_SIZE as u64
+    }
+
+    fn get_fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let entries_per_sector = (SECTOR_SIZE / 4) as u64;
+        let mut sector = [0u32; SECTOR_SIZE / 4];
+        self.drive.read_sectors(
+            self.fat_offset + cluster as u64 / entries_per_sector,
+            1,
+            sector.as_mut_ptr() as *mut u8,
+        )?;
+        Ok(sector[(cluster as u64 % entries_per_sector) as usize])
+    }
+
+    // Fails with Io if the extent is longer than the volume, or if its chain loops
+    fn clusters(&self, extent: &Extent) -> Result<Vec<u32>, FsError> {
+        if extent.contiguous {
+            let count = extent.length.div_ceil(self.cluster_size());
+            if count > self.cluster_count as u64 {
+                return Err(FsError::Io);
+            }
+            return Ok((extent.first..extent.first + count as u32).collect());
+        }
+
+        let mut clusters = vec![];
+        let mut cluster = extent.first;
+        while cluster >= 2 && cluster < END_OF_CHAIN {
+            if clusters.len() >= self.cluster_count as usize {
+                return Err(FsError::Io);
+            }
+            clusters.push(cluster);
+            cluster = self.get_fat_entry(cluster)?;
+        }
+        Ok(clusters)
+    }
+
+    fn read_cluster(&self, cluster: u32, buffer: &mut [u8]) -> Result<(), FsError> {
+        if cluster < 2 || cluster - 2 >= self.cluster_count {
+            return Err(FsError::Io);
+        }
+        self.drive.read_sectors(
+            self.cluster_heap_offset + (cluster as u64 - 2) * self.sectors_per_cluster,
+            self.sectors_per_cluster,
+            buffer.as_mut_ptr(),
+        )?;
+        Ok(())
+    }
+
+    // The root directory has no entry, and always uses the FAT
+    fn root_extent(&self) -> Extent {
+        Extent {
+            first: self.root_cluster,
+            contiguous: false,
+            length: 0,
+        }
+    }
+
+    fn directory_extent(&self, cluster: u32) -> Result<Extent, FsError> {
+        if cluster == self.root_cluster {
+            return Ok(self.root_extent());
+        }
+        self.directories
+            .lock()
+            .get(&cluster)
+            .copied()
+            .ok_or(FsError::NotFound)
+    }
+
+    // Reads the entries of the directory, and remembers the extents of its subdirectories
+    fn read_directory(&self, extent: &Extent) -> Result<Vec<Entry>, FsError> {
+        let cluster_size = self.cluster_size() as usize;
+        let clusters = self.clusters(extent)?;
+        let mut data = vec![0u8; clusters.len() * cluster_size];
+        for (i, cluster) in clusters.iter().enumerate() {
+            self.read_cluster(
+                *cluster,
+                &mut data[i * cluster_size..(i + 1) * cluster_size],
+            )?;
+        }
+
+        let (entries, _) = parse_directory(&data);
+        self.remember_directories(extent, &entries);
+        Ok(entries)
+    }
+
+    fn remember_directories(&self, extent: &Extent, entries: &[Entry]) {
+        let mut directories = self.directories.lock();
+        let mut directory_inodes = self.directory_inodes.lock();
+        for entry in entries.iter().filter(|entry| entry.is_directory()) {
+            directories.insert(entry.extent.first, entry.extent);
+            directory_inodes.insert(Self::inode(extent.first, entry.index), entry.extent);
+        }
+    }
+
+    // Returns the cluster that follows cluster in the extent, if any
+    fn next_cluster(&self, extent: &Extent, cluster: u32) -> Result<Option<u32>, FsError> {
+        if extent.contiguous {
+            let count = extent.length.div_ceil(self.cluster_size()) as u32;
+            return Ok(Some(cluster + 1).filter(|next| *next < extent.first + count));
+        }
+        match self.get_fat_entry(cluster)? {
+            next @ 2..END_OF_CHAIN => Ok(Some(next)),
+            _ => Ok(None),
+        }
+    }
+
+    // Returns the cluster at index n of the extent, following the FAT from its first cluster
+    fn nth_cluster(&self, extent: &Extent, n: u64) -> Result<u32, FsError> {
+        let mut cluster = extent.first;
+        if extent.contiguous {
+            if n >= extent.length.div_ceil(self.cluster_size()) {
+                return Err(FsError::NotFound);
+            }
+            return Ok(cluster + n as u32);
+        }
+        for _ in 0..n {
+            cluster = self
+                .next_cluster(extent, cluster)?
+                .ok_or(FsError::NotFound)?;
+        }
+        Ok(cluster)
+    }
+
+    // Returns the first entry set starting at index of the given cluster of the directory, along
+    // with the cursor that follows it. Entry sets can span clusters, so following clusters are
+    // loaded until one is complete. Cursors hold the cluster in the high half and the index in it
+    // in the low one
+    fn next_entry(
+        &self,
+        extent: &Extent,
+        mut cluster: u32,
+        mut index: usize,
+    ) -> Result<Option<(Entry, u64)>, FsError> {
+        let entries_per_cluster = self.cluster_size() as usize / ENTRY_SIZE;
+        let mut clusters = vec![cluster];
+        let mut data = vec![0u8; self.cluster_size() as usize];
+        self.read_cluster(cluster, &mut data)?;
+        loop {
+            let (entries, ended) = parse_directory(&data[index * ENTRY_SIZE..]);
+            if let Some(entry) = entries.into_iter().next() {
+                let next = index + entry.index + 1 + entry.secondary_count;
+                let position = next / entries_per_cluster;
+                let next = if position < clusters.len() {
+                    (clusters[position] as u64) << 32 | (next % entries_per_cluster) as u64
+                } else {
+                    match self.next_cluster(extent, clusters[clusters.len() - 1])? {
+                        Some(cluster) => (cluster as u64) << 32,
+                        None => END_OF_DIRECTORY,
+                    }
+                };
+                return Ok(Some((entry, next)));
+            }
+            if ended {
+                return Ok(None);
+            }
+
+            // Only the entries of an incomplete entry set need to be parsed again
+            let Some(next) = self.next_cluster(extent, cluster)? else {
+                return Ok(None);
+            };
+            index = usize::max(
+                index,
+                (data.len() / ENTRY_SIZE).saturating_sub(MAX_SECONDARY),
+            );
+            cluster = next;
+            clusters.push(next);
+            let start = data.len();
+            data.resize(start + self.cluster_size() as usize, 0);
+            self.read_cluster(cluster, &mut data[start..])?;
+        }
+    }
+
+    // Inodes identify entries by the first cluster of their directory and their index in it
+    fn inode(cluster: u32, index: usize) -> Inode {
+        (cluster as u64) << 32 | index as u64
+    }
+
+    fn get_entry(&self, inode: Inode) -> Result<Entry, FsError> {
+        let extent = self.directory_extent((inode >> 32) as u32)?;
+        let index = (inode & 0xffff_ffff) as usize;
+        self.read_directory(&extent)?
+            .into_iter()
+            .find(|entry| entry.index == index)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn get_directory(&self, inode: Inode) -> Result<Extent, FsError> {
+        if inode == ROOT_INODE {
+            return Ok(self.root_extent());
+        }
+        if let Some(extent) = self.directory_inodes.lock().get(&inode) {
+            return Ok(*extent);
+        }
+        let entry = self.get_entry(inode)?;
+        if !entry.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(entry.extent)
+    }
+}
+
+// Parses the entry sets of a directory: a file entry, followed by a stream extension and the file
+// name entries. Sets with missing entries are skipped. Indices are counted from the start of data,
+// and whether the end of directory entry was found is also returned
+fn parse_directory(data: &[u8]) -> (Vec<Entry>, bool) {
+    let u16_at = |offset: usize| u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap());
+    let u32_at = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+    let u64_at = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+
+    let count = data.len() / ENTRY_SIZE;
+    let mut entries = vec![];
+    let mut i = 0;
+    while i < count {
+        let file = i * ENTRY_SIZE;
+        match data[file] {
+            ENTRY_END => return (entries, true),
+            ENTRY_FILE => {}
+            _ => {
+                i += 1;
+                continue;
+            }
+        }
+
+        let secondary_count = data[file + 1] as usize;
+        let stream = file + ENTRY_SIZE;
+        if secondary_count < 2
+            || i + secondary_count >= count
+            || data[stream] != ENTRY_STREAM_EXTENSION
+        {
+            i += 1;
+            continue;
+        }
+
+        let name_length = data[stream + 3] as usize;
+        let mut name = Vec::with_capacity(name_length);
+        for j in 2..=secondary_count {
+            let name_entry = file + j * ENTRY_SIZE;
+            if data[name_entry] != ENTRY_FILE_NAME {
+                break;
+            }
+            for k in 0..FILE_NAME_CHARS {
+                if name.len() < name_length {
+                    name.push(u16_at(name_entry + 2 + k * 2));
+                }
+            }
+        }
+
+        entries.push(Entry {
+            index: i,
+            secondary_count,
+            name: String::from_utf16_lossy(&name),
+            attributes: u16_at(file + 4),
+            extent: Extent {
+                first: u32_at(stream + 20),
+                contiguous: data[stream + 1] & NO_FAT_CHAIN != 0,
+                length: u64_at(stream + 24),
+            },
+            valid_length: u64_at(stream + 8),
+            created: u32_at(file + 8),
+            modified: u32_at(file + 12),
+            accessed: u32_at(file + 16),
+            created_10ms: data[file + 20],
+        });
+        i += 1 + secondary_count;
+    }
+    (entries, false)
+}
+
+impl<D: Drive> Fs for ExFatFs<D> {
+    fn root(&self) -> Inode {
+        ROOT_INODE
+    }
+
+    // Names are compared case insensitively for ASCII only, the up-case table is not used
+    fn lookup(&self, directory: Inode, name: &str) -> Result<Inode, FsError> {
+        let extent = self.get_directory(directory)?;
+        self.read_directory(&extent)?
+            .into_iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(name))
+            .map(|entry| Self::inode(extent.first, entry.index))
+            .ok_or(FsError::NotFound)
+    }
+
+    fn stat(&self, inode: Inode) -> Result<Stat, FsError> {
+        if inode == ROOT_INODE {
+            return Ok(Stat {
+                attributes: ATTRIBUTE_DIRECTORY,
+                ..Stat::default()
+            });
+        }
+        Ok(self.get_entry(inode)?.stat())
+    }
+
+    fn read_dir(&self, directory: Inode, cursor: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        let extent = self.get_directory(directory)?;
+        let (cluster, index) = match cursor {
+            0 => (extent.first, 0),
+            END_OF_DIRECTORY => return Ok(None),
+            cursor => ((cursor >> 32) as u32, (cursor & 0xffff_ffff) as usize),
+        };
+        // Cursors come from userspace, and must point into the cluster heap
+        if cluster < 2
+            || cluster - 2 >= self.cluster_count
+            || index >= self.cluster_size() as usize / ENTRY_SIZE
+        {
+            return Err(FsError::NotFound);
+        }
+
+        Ok(self
+            .next_entry(&extent, cluster, index)?
+            .map(|(entry, next)| {
+                let entry = DirEntry {
+                    stat: entry.stat(),
+                    name: entry.name,
+                };
+                (entry, next)
+            }))
+    }
+
+    fn read(&self, file: Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let entry = self.get_entry(file)?;
+        if entry.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+
+        let size = entry.extent.length;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = u64::min(buffer.len() as u64, size - offset);
+        let valid_end = u64::min(entry.valid_length, offset + len);
+        buffer[..len as usize].fill(0);
+
+        // Only the clusters in the requested range are read. The chain is followed once to the
+        // first of them, and then one cluster at a time
+        let cluster_size = self.cluster_size();
+        let mut cluster_buffer = vec![0u8; cluster_size as usize];
+        let mut position = offset;
+        let mut cluster = None;
+        while position < valid_end {
+            let start = (position % cluster_size) as usize;
+            let count = u64::min(cluster_size - start as u64, valid_end - position) as usize;
+            let current = match cluster {
+                None => self.nth_cluster(&entry.extent, position / cluster_size)?,
+                Some(previous) => self
+                    .next_cluster(&entry.extent, previous)?
+                    .ok_or(FsError::NotFound)?,
+            };
+            cluster = Some(current);
+
+            self.read_cluster(current, &mut cluster_buffer)?;
+            let done = (position - offset) as usize;
+            buffer[done..done + count].copy_from_slice(&cluster_buffer[start..start + count]);
+            position += count as u64;
+        }
+        Ok(len as usize)
+    }
+
+    fn write(&mut self, file: Inode, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&mut self, file: Inode, size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn create(&mut self, directory: Inode, name: &str) -> Result<Inode, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn create_dir(&mut self, directory: Inode, name: &str) -> Result<Inode, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn delete(&mut self, directory: Inode, name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.drive.cache_stats()
+    }
+}
@@ -0,0 +1,524 @@
+#![allow(unused)]
+
+use crate::block_cache::{BlockCache, CacheStats, CACHE_SECTORS};
+use crate::drive::{Drive, DriveError, SECTOR_SIZE};
+use crate::fs::*;
+use crate::vfs::VFS;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const ROOT_INODE: Inode = 2;
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+const GROUP_DESCRIPTOR_SIZE: u64 = 32;
+
+// Incompatible features that do not change how the file system is read. Volumes with any other
+// one, like extents or a journal to recover, are rejected
+const INCOMPAT_FILETYPE: u32 = 0x0002; // Directory entries hold the file type
+const INCOMPAT_FLEX_BG: u32 = 0x0200; // Metadata of a block group can be stored in another one
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+// The block array of inodes has 12 direct blocks, followed by the single, double and triple
+// indirect blocks
+const DIRECT_BLOCKS: u64 = 12;
+const INDIRECT_BLOCKS: usize = 12;
+
+// File type bits of the mode
+const MODE_TYPE: u16 = 0xf000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_SYMLINK: u16 = 0xa000;
+const MODE_WRITE: u16 = 0o222;
+// FAT attribute reported for files that nobody can write
+const ATTRIBUTE_READ_ONLY: u8 = 0x01;
+
+// Targets of fast symlinks are stored in the block array instead of a data block
+const FAST_SYMLINK_SIZE: u64 = 60;
+// Symlinks followed while looking up a single name, after which the lookup fails
+const MAX_SYMLINKS: usize = 8;
+
+// Mounts the ext2 file system of the drive at path. ext2 volumes are read only
+pub fn init<D: Drive + Send + 'static>(drive: D, path: &str) -> Result<(), ()> {
+    if !is_ext2(&drive) {
+        return Err(());
+    }
+    let fs = Ext2Fs::new(BlockCache::new(drive, CACHE_SECTORS)).map_err(|_| ())?;
+    VFS.lock().mount(path, Box::new(fs)).map_err(|_| ())
+}
+
+// Checks the magic number of the superblock, and that the volume only uses supported features
+pub fn is_ext2<D: Drive>(drive: &D) -> bool {
+    let Ok(superblock) = read_superblock(drive) else {
+        return false;
+    };
+    let u16_at = |offset: usize| u16::from_le_bytes([superblock[offset], superblock[offset + 1]]);
+    let u32_at =
+        |offset: usize| u32::from_le_bytes(superblock[offset..offset + 4].try_into().unwrap());
+
+    u16_at(56) == MAGIC
+        && u32_at(24) <= 6 // Blocks of up to 64 KiB
+        && u32_at(32) != 0
+        && u32_at(40) != 0
+        && (u32_at(76) == 0 || u16_at(88).is_power_of_two() && u16_at(88) >= 128)
+        && u32_at(96) & !SUPPORTED_INCOMPAT == 0
+}
+
+fn read_superblock<D: Drive>(drive: &D) -> Result<[u8; SUPERBLOCK_SIZE], DriveError> {
+    let mut superblock = [0u8; SUPERBLOCK_SIZE];
+    drive.read_sectors(
+        SUPERBLOCK_OFFSET / SECTOR_SIZE as u64,
+        (SUPERBLOCK_SIZE / SECTOR_SIZE) as u64,
+        superblock.as_mut_ptr(),
+    )?;
+    Ok(superblock)
+}
+
+#[derive(Debug, Clone)]
+struct Ext2Inode {
+    mode: u16,
+    size: u64,
+    accessed: u32,
+    // ext2 has no creation time, the time of the last change of the inode is used instead
+    changed: u32,
+    modified: u32,
+    // Number of 512 byte sectors used by the inode, including its extended attribute block
+    sectors: u32,
+    blocks: [u32; 15],
+    file_acl: u32,
+}
+
+impl Ext2Inode {
+    fn is_directory(&self) -> bool {
+        self.mode & MODE_TYPE == MODE_DIRECTORY
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.mode & MODE_TYPE == MODE_SYMLINK
+    }
+}
+
+struct Entry {
+    inode: u32,
+    name: String,
+    // Offset of the entry that follows, from the start of the parsed data
+    next: usize,
+}
+
+pub struct Ext2Fs<D: Drive> {
+    drive: D,
+    block_size: u64,
+    blocks_count: u64,
+    inode_size: u64,
+    inodes_count: u32,
+    inodes_per_group: u32,
+    // First block of the block group descriptor table, which follows the superblock
+    group_table: u64,
+}
+
+impl<D: Drive> Ext2Fs<D> {
+    pub fn new(drive: D) -> Result<Ext2Fs<D>, FsError> {
+        let superblock = read_superblock(&drive)?;
+        let u16_at =
+            |offset: usize| u16::from_le_bytes([superblock[offset], superblock[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(superblock[offset..offset + 4].try_into().unwrap());
+
+        Ok(Ext2Fs {
+            drive,
+            block_size: 1024 << u32_at(24),
+            blocks_count: u32_at(4) as u64,
+            // Revision 0 volumes have a fixed inode size
+            inode_size: if u32_at(76) == 0 {
+                128
+            } else {
+                u16_at(88) as u64
+            },
+            inodes_count: u32_at(0),
+            inodes_per_group: u32_at(40),
+            group_table: u32_at(20) as u64 + 1,
+        })
+    }
+
+    // Reads buffer.len() bytes starting at the byte offset in the volume
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        let first = offset / SECTOR_SIZE as u64;
+        let end = (offset + buffer.len() as u64).div_ceil(SECTOR_SIZE as u64);
+        let mut sectors = vec![0u8; (end - first) as usize * SECTOR_SIZE];
+        self.drive
+            .read_sectors(first, end - first, sectors.as_mut_ptr())?;
+        let start = (offset % SECTOR_SIZE as u64) as usize;
+        buffer.copy_from_slice(&sectors[start..start + buffer.len()]);
+        Ok(())
+    }
+
+    fn read_u32(&self, offset: u64) -> Result<u32, FsError> {
+        let mut bytes = [0u8; 4];
+        self.read_bytes(offset, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    // Block 0 is never used for data, and marks holes in sparse files, which are read as zeros
+    fn read_block(&self, block: u32, buffer: &mut [u8]) -> Result<(), FsError> {
+        if block == 0 {
+            buffer.fill(0);
+            return Ok(());
+        }
+        let sectors = self.block_size / SECTOR_SIZE as u64;
+        self.drive
+            .read_sectors(block as u64 * sectors, sectors, buffer.as_mut_ptr())?;
+        Ok(())
+    }
+
+    fn read_inode(&self, inode: Inode) -> Result<Ext2Inode, FsError> {
+        if inode == 0 || inode > self.inodes_count as Inode {
+            return Err(FsError::NotFound);
+        }
+        let group = (inode - 1) / self.inodes_per_group as u64;
+        let index = (inode - 1) % self.inodes_per_group as u64;
+        let inode_table = self
+            .read_u32(self.group_table * self.block_size + group * GROUP_DESCRIPTOR_SIZE + 8)?
+            as u64;
+
+        let mut data = [0u8; 128];
+        self.read_bytes(
+            inode_table * self.block_size + index * self.inode_size,
+            &mut data,
+        )?;
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+
+        let mode = u16_at(0);
+        let mut blocks = [0u32; 15];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            *block = u32_at(40 + i * 4);
+        }
+        // The high half of the size is only used by regular files, and holds the ACL of directories
+        let size_high = match mode & MODE_TYPE {
+            MODE_DIRECTORY => 0,
+            _ => u32_at(108),
+        };
+
+        Ok(Ext2Inode {
+            mode,
+            size: (size_high as u64) << 32 | u32_at(4) as u64,
+            accessed: u32_at(8),
+            changed: u32_at(12),
+            modified: u32_at(16),
+            sectors: u32_at(28),
+            blocks,
+            file_acl: u32_at(104),
+        })
+    }
+
+    // Returns the block holding the block at index in the inode, or 0 if it is a hole
+    fn block_of(&self, inode: &Ext2Inode, index: u64) -> Result<u32, FsError> {
+        if index < DIRECT_BLOCKS {
+            return Ok(inode.blocks[index as usize]);
+        }
+
+        let per_block = self.block_size / 4;
+        let mut index = index - DIRECT_BLOCKS;
+        let mut span = 1;
+        for level in 0..3 {
+            span *= per_block;
+            if index >= span {
+                index -= span;
+                continue;
+            }
+
+            // Each level of indirection divides the range of blocks in per_block parts
+            let mut block = inode.blocks[INDIRECT_BLOCKS + level];
+            let mut part = span;
+            for _ in 0..=level {
+                if block == 0 {
+                    return Ok(0);
+                }
+                part /= per_block;
+                block = self.read_u32(block as u64 * self.block_size + index / part * 4)?;
+                index %= part;
+            }
+            return Ok(block);
+        }
+        Ok(0)
+    }
+
+    // Reads from offset into buffer, and returns the number of bytes read
+    fn read_data(
+        &self,
+        inode: &Ext2Inode,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize, FsError> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = u64::min(buffer.len() as u64, inode.size - offset);
+
+        // Only the blocks in the requested range are read
+        let mut block_buffer = vec![0u8; self.block_size as usize];
+        let mut position = offset;
+        while position < offset + len {
+            let start = (position % self.block_size) as usize;
+            let count = u64::min(self.block_size - start as u64, offset + len - position) as usize;
+            self.read_block(
+                self.block_of(inode, position / self.block_size)?,
+                &mut block_buffer,
+            )?;
+
+            let done = (position - offset) as usize;
+            buffer[done..done + count].copy_from_slice(&block_buffer[start..start + count]);
+            position += count as u64;
+        }
+        Ok(len as usize)
+    }
+
+    fn read_directory(&self, directory: Inode) -> Result<Vec<Entry>, FsError> {
+        let inode = self.read_inode(directory)?;
+        if !inode.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        let mut data = vec![0u8; self.directory_size(&inode) as usize];
+        self.read_data(&inode, 0, &mut data)?;
+        Ok(parse_directory(&data))
+    }
+
+    // Directories have no holes, so their size is bounded by the sectors they use, and by the
+    // volume. This keeps a corrupted size from allocating more than the volume holds
+    fn directory_size(&self, inode: &Ext2Inode) -> u64 {
+        let used = inode.sectors as u64 * SECTOR_SIZE as u64;
+        u64::min(
+            inode.size,
+            u64::min(used, self.blocks_count * self.block_size),
+        )
+    }
+
+    // Fast symlinks are the ones without data blocks, apart from their extended attribute block
+    fn symlink_target(&self, inode: &Ext2Inode) -> Result<String, FsError> {
+        let attribute_sectors = match inode.file_acl {
+            0 => 0,
+            _ => (self.block_size / SECTOR_SIZE as u64) as u32,
+        };
+        // Targets take at most one block
+        let mut target = vec![0u8; u64::min(inode.size, self.block_size) as usize];
+        if inode.size < FAST_SYMLINK_SIZE && inode.sectors == attribute_sectors {
+            for (i, byte) in target.iter_mut().enumerate() {
+                *byte = inode.blocks[i / 4].to_le_bytes()[i % 4];
+            }
+        } else {
+            self.read_data(inode, 0, &mut target)?;
+        }
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+
+    // Finds the entry called name in the directory, and follows it if it is a symlink. Absolute
+    // targets are resolved from the root of the file system, not the one of the VFS
+    fn lookup_entry(
+        &self,
+        directory: Inode,
+        name: &str,
+        symlinks: &mut usize,
+    ) -> Result<Inode, FsError> {
+        let entry = self
+            .read_directory(directory)?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .ok_or(FsError::NotFound)?;
+        self.follow(directory, entry.inode as Inode, symlinks)
+    }
+
+    // Returns the inode that an entry of the directory refers to, following it if it is a symlink
+    fn follow(
+        &self,
+        directory: Inode,
+        file: Inode,
+        symlinks: &mut usize,
+    ) -> Result<Inode, FsError> {
+        let inode = self.read_inode(file)?;
+        if !inode.is_symlink() {
+            return Ok(file);
+        }
+
+        *symlinks += 1;
+        if *symlinks > MAX_SYMLINKS {
+            return Err(FsError::NotFound);
+        }
+        let target = self.symlink_target(&inode)?;
+        let mut current = if target.starts_with('/') {
+            ROOT_INODE
+        } else {
+            directory
+        };
+        for name in target.split('/').filter(|name| !name.is_empty()) {
+            current = self.lookup_entry(current, name, symlinks)?;
+        }
+        Ok(current)
+    }
+
+    fn stat_inode(&self, inode: &Ext2Inode) -> Stat {
+        let mut attributes = 0;
+        if inode.is_directory() {
+            attributes |= ATTRIBUTE_DIRECTORY;
+        }
+        if inode.mode & MODE_WRITE == 0 {
+            attributes |= ATTRIBUTE_READ_ONLY;
+        }
+        let (creation_date, creation_time) = fat_timestamp(inode.changed);
+        let (last_modification_date, last_modification_time) = fat_timestamp(inode.modified);
+        Stat {
+            size: if inode.is_directory() { 0 } else { inode.size },
+            attributes,
+            creation_time_hundredths: 0,
+            creation_time,
+            creation_date,
+            last_accessed_date: fat_timestamp(inode.accessed).0,
+            last_modification_time,
+            last_modification_date,
+        }
+    }
+}
+
+// Entries are never split across blocks, and their length covers the unused space that follows
+// them. Unused entries have inode 0. The high byte of the name length holds the file type on
+// volumes with the filetype feature, and is always 0 otherwise, since names are at most 255 bytes
+fn parse_directory(data: &[u8]) -> Vec<Entry> {
+    let mut entries = vec![];
+    let mut offset = 0;
+    while offset + 8 <= data.len() {
+        let inode = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let record_length = u16::from_le_bytes([data[offset + 4], data[offset + 5]]) as usize;
+        let name_length = data[offset + 6] as usize;
+        if record_length < 8 || offset + 8 + name_length > data.len() {
+            break;
+        }
+
+        if inode != 0 {
+            entries.push(Entry {
+                inode,
+                name: String::from_utf8_lossy(&data[offset + 8..offset + 8 + name_length])
+                    .into_owned(),
+                next: offset + record_length,
+            });
+        }
+        offset += record_length;
+    }
+    entries
+}
+
+// Converts a Unix timestamp to a FAT date and time, which can not represent times before 1980
+fn fat_timestamp(timestamp: u32) -> (u16, u16) {
+    let days = timestamp / 86400;
+    let seconds = timestamp % 86400;
+
+    // Civil date of the days since 1970-01-01, counted in 400 year eras starting in March
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    if year < 1980 {
+        return (1 << 5 | 1, 0);
+    }
+    let date = u32::min(year - 1980, 127) << 9 | month << 5 | day;
+    let time = (seconds / 3600) << 11 | (seconds / 60 % 60) << 5 | seconds % 60 / 2;
+    (date as u16, time as u16)
+}
+
+impl<D: Drive> Fs for Ext2Fs<D> {
+    fn root(&self) -> Inode {
+        ROOT_INODE
+    }
+
+    // Names are case sensitive, and symlinks are followed
+    fn lookup(&self, directory: Inode, name: &str) -> Result<Inode, FsError> {
+        self.lookup_entry(directory, name, &mut 0)
+    }
+
+    fn stat(&self, inode: Inode) -> Result<Stat, FsError> {
+        Ok(self.stat_inode(&self.read_inode(inode)?))
+    }
+
+    // The . and .. entries are not listed. Symlinks are described by their target, like the inodes
+    // returned by lookup, and dangling ones by themselves. Cursors are byte offsets in the
+    // directory, and only the block holding the cursor is read, since entries never span blocks
+    fn read_dir(&self, directory: Inode, cursor: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        let inode = self.read_inode(directory)?;
+        if !inode.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+
+        let mut block = vec![0u8; self.block_size as usize];
+        let mut position = cursor;
+        let entry = loop {
+            if position >= inode.size {
+                return Ok(None);
+            }
+            let start = position % self.block_size;
+            self.read_block(
+                self.block_of(&inode, position / self.block_size)?,
+                &mut block,
+            )?;
+            let entry = parse_directory(&block[start as usize..])
+                .into_iter()
+                .find(|entry| entry.name != "." && entry.name != "..");
+            match entry {
+                Some(entry) => {
+                    position += entry.next as u64;
+                    break entry;
+                }
+                None => position += self.block_size - start,
+            }
+        };
+
+        let inode = match self.follow(directory, entry.inode as Inode, &mut 0) {
+            Ok(inode) => inode,
+            Err(FsError::NotFound | FsError::NotADirectory) => entry.inode as Inode,
+            Err(e) => return Err(e),
+        };
+        let entry = DirEntry {
+            stat: self.stat_inode(&self.read_inode(inode)?),
+            name: entry.name,
+        };
+        Ok(Some((entry, position)))
+    }
+
+    fn read(&self, file: Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let inode = self.read_inode(file)?;
+        if inode.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        self.read_data(&inode, offset, buffer)
+    }
+
+    fn write(&mut self, file: Inode, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&mut self, file: Inode, size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn create(&mut self, directory: Inode, name: &str) -> Result<Inode, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn create_dir(&mut self, directory: Inode, name: &str) -> Result<Inode, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn delete(&mut self, directory: Inode, name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.drive.cache_stats()
+    }
+}
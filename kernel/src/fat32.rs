@@ -4,86 +4,496 @@ use core::str::FromStr;
 
 use super::{print, println, Mutex};
 use crate::ata::*;
-use crate::drive::Drive;
+use crate::block_cache::{BlockCache, CacheStats, CACHE_SECTORS};
+use crate::drive::{Drive, DriveError, SECTOR_SIZE};
 use crate::fs::*;
 use crate::memory::{VirtualMapping, KERNEL_VALLOCATOR, MEMORY_MANAGER};
-use crate::utils::clear_page;
+use crate::vfs::VFS;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
 use alloc::string::*;
 use alloc::vec;
 use alloc::vec::*;
 
-pub static FAT32: Mutex<Option<Fat32Fs<AtaDrive48>>> = Mutex::new(None);
+const ROOT_INODE: Inode = 0;
+const END_OF_CHAIN: u32 = 0x0fff_ffff;
+const DELETED_ENTRY: u8 = 0xe5;
+// Stored instead of 0xe5 as the first byte of names starting with it
+const ESCAPED_DELETED_ENTRY: u8 = 0x05;
+// Flags in the reserved byte of standard entries, set when the base name or the extension of a
+// short name without long file name entries should be shown in lowercase
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+const DIRECTORY_ENTRY_SIZE: usize = size_of::<StandardDirectory>();
+// Used as the cluster of the root directory of FAT12 and FAT16, which is a fixed region before the
+// data area. Data clusters are numbered starting from 2
+const FIXED_ROOT_CLUSTER: u32 = 1;
+// Cursor of read_dir past the last entry of a directory
+const END_OF_DIRECTORY: u64 = u64::MAX;
+// Long file names of up to 255 characters take up to 20 entries of 13 characters
+const MAX_LFN_SLOTS: usize = 20;
 
-pub fn init() -> Result<(), ()> {
-    *FAT32.lock() = Some(Fat32Fs::new(*crate::ata::ATA_DRIVE_48.lock()));
-    Ok(())
+mod fsck;
+
+// Mounts the FAT12, FAT16 or FAT32 file system of the drive at path
+pub fn init<D: Drive + Send + 'static>(drive: D, path: &str) -> Result<(), ()> {
+    if !is_fat(&drive) {
+        return Err(());
+    }
+    let mut fs = Fat32Fs::new(BlockCache::new(drive, CACHE_SECTORS)).map_err(|_| ())?;
+    #[cfg(feature = "fsck")]
+    fsck::check_at_mount(&mut fs);
+    VFS.lock().mount(path, Box::new(fs)).map_err(|_| ())
+}
+
+// Checks the boot sector signature and the BPB of the drive. The type labels are informative
+// only, and are not checked. exFAT boot sectors have a zeroed BPB, so they are rejected
+pub fn is_fat<D: Drive>(drive: &D) -> bool {
+    let mut sector = [0u8; SECTOR_SIZE];
+    if drive.read_sectors(0, 1, sector.as_mut_ptr()).is_err() {
+        return false;
+    }
+    let boot_sector =
+        unsafe { core::ptr::read_unaligned(sector.as_ptr() as *const Fat32BootSector) };
+    let bpb = boot_sector.bpd;
+    sector[510..512] == [0x55, 0xaa]
+        && bpb.bytes_per_sector as usize == SECTOR_SIZE
+        && bpb.sectors_per_cluster.is_power_of_two()
+        && bpb.reserved_sector_count != 0
+        && bpb.table_count != 0
+        && boot_sector.fat_size() != 0
+        && boot_sector.total_sectors() > boot_sector.start_of_data()
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+// Despite the name, also handles FAT12 and FAT16, whose root directory is a fixed region instead of
+// a cluster chain, and whose FAT entries are 12 and 16 bits wide
 pub struct Fat32Fs<D: Drive> {
     drive: D,
     boot_sector: Fat32BootSector,
-    fat_buffer: u64,
+    fat_type: FatType,
+    // Cluster from which the search for free clusters starts
+    next_free: u32,
+    // Number of times the standard entry at a slot of a directory was deleted, by first cluster of
+    // the directory and slot. Missing slots were never deleted
+    generations: BTreeMap<(u32, usize), u16>,
 }
 
 impl<D: Drive> Fat32Fs<D> {
-    pub fn new(drive: D) -> Fat32Fs<D> {
-        let boot_sector = Fat32BootSector::new(&drive);
-
-        // Load fat into memory
-        let first_fat_sector = boot_sector.bpd.reserved_sector_count;
-        let fat_size = boot_sector.ebpb.table_size_32;
-        let fat_buffer = KERNEL_VALLOCATOR
-            .lock()
-            .alloc_pages(fat_size as u64 * boot_sector.bpd.bytes_per_sector as u64 / 0x1000 + 1)
-            .vaddr;
-
-        drive.read_sectors(
-            first_fat_sector as u64,
-            fat_size as u64,
-            fat_buffer as *mut u8,
-        );
-
-        Fat32Fs {
+    pub fn new(drive: D) -> Result<Fat32Fs<D>, FsError> {
+        let boot_sector = Fat32BootSector::new(&drive)?;
+        Ok(Fat32Fs {
             drive,
             boot_sector,
-            fat_buffer,
+            fat_type: boot_sector.fat_type(),
+            next_free: 2,
+            generations: BTreeMap::new(),
+        })
+    }
+
+    fn start_of_data(&self) -> u64 {
+        self.boot_sector.start_of_data()
+    }
+
+    fn root_cluster(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat32 => self.boot_sector.ebpb.root_cluster,
+            _ => FIXED_ROOT_CLUSTER,
         }
     }
 
     fn cluster_to_sector(&self, cluster: u32) -> u64 {
-        let start_of_data = self.boot_sector.bpd.reserved_sector_count as u64
-            + self.boot_sector.bpd.table_count as u64 * self.boot_sector.ebpb.table_size_32 as u64;
-
-        let sector = start_of_data as i64
+        let sector = self.start_of_data() as i64
             + (cluster as i64 - 2) * self.boot_sector.bpd.sectors_per_cluster as i64;
         sector as u64
     }
 
-    pub fn read_directory(&self, cluseter: u32) -> Vec<DirectoryEntry> {
-        let buffer = MEMORY_MANAGER.lock().physical_map.alloc_frame() as *const StandardDirectory;
-        let mut dirs: Vec<DirectoryEntry> = vec![];
+    fn cluster_size(&self) -> u64 {
+        self.boot_sector.bpd.sectors_per_cluster as u64
+            * self.boot_sector.bpd.bytes_per_sector as u64
+    }
+
+    // Number of data clusters, which are numbered starting from 2
+    fn cluster_count(&self) -> u32 {
+        self.boot_sector.cluster_count()
+    }
+
+    // Offset in bytes of the entry of cluster in the FAT
+    fn fat_offset(&self, cluster: u32) -> u64 {
+        match self.fat_type {
+            FatType::Fat12 => cluster as u64 + cluster as u64 / 2,
+            FatType::Fat16 => cluster as u64 * 2,
+            FatType::Fat32 => cluster as u64 * 4,
+        }
+    }
+
+    // The FAT is not kept in memory, and its sectors are read through the block cache instead.
+    // Returns sector index of the first FAT along with the next one, since FAT12 entries can span
+    // two sectors
+    fn read_fat_sectors(&self, index: u64) -> Result<[u8; 2 * SECTOR_SIZE], FsError> {
+        let mut sectors = [0u8; 2 * SECTOR_SIZE];
+        self.drive.read_sectors(
+            self.boot_sector.bpd.reserved_sector_count as u64 + index,
+            2,
+            sectors.as_mut_ptr(),
+        )?;
+        Ok(sectors)
+    }
+
+    // Decodes the entry of cluster from the FAT sectors starting at index. The end of chain and bad
+    // cluster values of FAT12 and FAT16 are extended to the FAT32 ones
+    fn decode_fat_entry(&self, sectors: &[u8; 2 * SECTOR_SIZE], index: u64, cluster: u32) -> u32 {
+        let offset = (self.fat_offset(cluster) - index * SECTOR_SIZE as u64) as usize;
+        let word = u16::from_le_bytes([sectors[offset], sectors[offset + 1]]) as u32;
+        match self.fat_type {
+            FatType::Fat12 => {
+                let entry = if cluster & 1 == 0 {
+                    word & 0xfff
+                } else {
+                    word >> 4
+                };
+                if entry >= 0xff7 {
+                    entry | 0x0fff_f000
+                } else {
+                    entry
+                }
+            }
+            FatType::Fat16 if word >= 0xfff7 => word | 0x0fff_0000,
+            FatType::Fat16 => word,
+            FatType::Fat32 => {
+                u32::from_le_bytes(sectors[offset..offset + 4].try_into().unwrap()) & 0x0fff_ffff
+            }
+        }
+    }
+
+    fn get_fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let index = self.fat_offset(cluster) / SECTOR_SIZE as u64;
+        Ok(self.decode_fat_entry(&self.read_fat_sectors(index)?, index, cluster))
+    }
+
+    // Updates the entry in every copy of the FAT
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let index = self.fat_offset(cluster) / SECTOR_SIZE as u64;
+        let offset = (self.fat_offset(cluster) % SECTOR_SIZE as u64) as usize;
+        let mut sectors = self.read_fat_sectors(index)?;
+        let entry_size = match self.fat_type {
+            FatType::Fat12 => {
+                // Odd entries use the high 12 bits of the 16 bits at their offset
+                let word = u16::from_le_bytes([sectors[offset], sectors[offset + 1]]);
+                let value = (value & 0xfff) as u16;
+                let word = match cluster & 1 {
+                    0 => (word & 0xf000) | value,
+                    _ => (word & 0x000f) | value << 4,
+                };
+                sectors[offset..offset + 2].copy_from_slice(&word.to_le_bytes());
+                2
+            }
+            FatType::Fat16 => {
+                sectors[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes());
+                2
+            }
+            FatType::Fat32 => {
+                let entry = u32::from_le_bytes(sectors[offset..offset + 4].try_into().unwrap());
+                let entry = (entry & 0xf000_0000) | (value & 0x0fff_ffff);
+                sectors[offset..offset + 4].copy_from_slice(&entry.to_le_bytes());
+                4
+            }
+        };
+
+        let count = if offset + entry_size > SECTOR_SIZE {
+            2
+        } else {
+            1
+        };
+        for i in 0..self.boot_sector.bpd.table_count as u64 {
+            self.drive.write_sectors(
+                self.boot_sector.bpd.reserved_sector_count as u64
+                    + i * self.boot_sector.fat_size()
+                    + index,
+                count,
+                sectors.as_ptr(),
+            )?;
+        }
+        Ok(())
+    }
+
+    // Calls f with every free cluster, starting from start and wrapping around, until it returns
+    // true. Whole FAT sectors are read at once
+    fn find_free_cluster(
+        &self,
+        start: u32,
+        mut f: impl FnMut(u32) -> bool,
+    ) -> Result<Option<u32>, FsError> {
+        // A volume without data clusters has nothing to allocate
+        if self.cluster_count() == 0 {
+            return Ok(None);
+        }
+        let end = self.cluster_count() + 2;
+        let start = u32::clamp(start, 2, end - 1);
+        let mut cluster = start;
+        let mut index = self.fat_offset(cluster) / SECTOR_SIZE as u64;
+        let mut sectors = self.read_fat_sectors(index)?;
+        loop {
+            if self.decode_fat_entry(&sectors, index, cluster) == 0 && f(cluster) {
+                return Ok(Some(cluster));
+            }
+
+            cluster += 1;
+            if cluster == end {
+                cluster = 2;
+            }
+            if cluster == start {
+                return Ok(None);
+            }
+            if self.fat_offset(cluster) / SECTOR_SIZE as u64 != index {
+                index = self.fat_offset(cluster) / SECTOR_SIZE as u64;
+                sectors = self.read_fat_sectors(index)?;
+            }
+        }
+    }
 
+    // Returns the clusters of the chain starting at cluster. Empty files have cluster 0, and an
+    // empty chain. Fails with Io if the chain is longer than the volume, which means that it loops
+    fn cluster_chain(&self, cluster: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = vec![];
+        let mut current_cluster = cluster;
+        while current_cluster >= 2 && current_cluster < 0xFFFFFF8 {
+            if chain.len() >= self.cluster_count() as usize {
+                return Err(FsError::Io);
+            }
+            chain.push(current_cluster);
+            current_cluster = self.get_fat_entry(current_cluster)?;
+        }
+        Ok(chain)
+    }
+
+    fn read_cluster(&self, cluster: u32, buffer: &mut [u8]) -> Result<(), FsError> {
         self.drive.read_sectors(
-            self.cluster_to_sector(cluseter) as u64,
+            self.cluster_to_sector(cluster),
+            self.boot_sector.bpd.sectors_per_cluster as u64,
+            buffer.as_mut_ptr(),
+        )?;
+        Ok(())
+    }
+
+    fn write_cluster(&self, cluster: u32, buffer: &[u8]) -> Result<(), FsError> {
+        self.drive.write_sectors(
+            self.cluster_to_sector(cluster),
             self.boot_sector.bpd.sectors_per_cluster as u64,
-            buffer as *mut u8,
-        );
+            buffer.as_ptr(),
+        )?;
+        Ok(())
+    }
+
+    // Checks whether at least count clusters are free, stopping as soon as they are found
+    fn has_free_clusters(&self, count: usize) -> Result<bool, FsError> {
+        if count == 0 {
+            return Ok(true);
+        }
+        let mut found = 0;
+        let cluster = self.find_free_cluster(self.next_free, |_| {
+            found += 1;
+            found == count
+        })?;
+        Ok(cluster.is_some())
+    }
+
+    // Finds a free cluster, clears it and marks it as the end of a chain. If previous is given, the
+    // new cluster is appended to it
+    fn alloc_cluster(&mut self, previous: Option<u32>) -> Result<u32, FsError> {
+        let cluster = self
+            .find_free_cluster(self.next_free, |_| true)?
+            .ok_or(FsError::NoSpace)?;
+        self.next_free = cluster + 1;
+
+        self.write_cluster(cluster, &vec![0; self.cluster_size() as usize])?;
+        self.set_fat_entry(cluster, END_OF_CHAIN)?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+        Ok(cluster)
+    }
 
-        let mut max_entries = self.boot_sector.bpd.sectors_per_cluster as u16
-            * self.boot_sector.bpd.bytes_per_sector
-            / size_of::<StandardDirectory>() as u16;
+    fn free_chain(&mut self, cluster: u32) -> Result<(), FsError> {
+        for cluster in self.cluster_chain(cluster)? {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        Ok(())
+    }
+
+    // Makes the chain starting at first exactly cluster_count clusters long, and returns its new
+    // first cluster
+    fn resize_chain(&mut self, first: u32, cluster_count: usize) -> Result<u32, FsError> {
+        let mut chain = self.cluster_chain(first)?;
+        if chain.len() > cluster_count {
+            if cluster_count == 0 {
+                self.free_chain(first)?;
+                return Ok(0);
+            }
+            self.set_fat_entry(chain[cluster_count - 1], END_OF_CHAIN)?;
+            self.free_chain(chain[cluster_count])?;
+            return Ok(first);
+        }
+
+        // Check beforehand, so that a chain is never left half grown
+        if !self.has_free_clusters(cluster_count - chain.len())? {
+            return Err(FsError::NoSpace);
+        }
+        while chain.len() < cluster_count {
+            let cluster = self.alloc_cluster(chain.last().copied())?;
+            chain.push(cluster);
+        }
+        Ok(chain.first().copied().unwrap_or(0))
+    }
+
+    fn clusters_for(&self, size: u64) -> usize {
+        size.div_ceil(self.cluster_size()) as usize
+    }
+
+    // Writes len bytes to the chain starting at first, from offset. Bytes are taken from data, or
+    // are zeros if data is None. The chain must be long enough
+    fn write_chain(
+        &self,
+        first: u32,
+        offset: u64,
+        len: u64,
+        data: Option<&[u8]>,
+    ) -> Result<(), FsError> {
+        let cluster_size = self.cluster_size();
+        let chain = self.cluster_chain(first)?;
+        let mut buffer = vec![0u8; cluster_size as usize];
+
+        let mut done = 0;
+        while done < len {
+            let position = offset + done;
+            let cluster = *chain
+                .get((position / cluster_size) as usize)
+                .ok_or(FsError::Io)?;
+            let start = (position % cluster_size) as usize;
+            let count = u64::min(cluster_size - start as u64, len - done) as usize;
+
+            // Partially written clusters have to be read first
+            if count != cluster_size as usize {
+                self.read_cluster(cluster, &mut buffer)?;
+            }
+            match data {
+                Some(data) => buffer[start..start + count]
+                    .copy_from_slice(&data[done as usize..done as usize + count]),
+                None => buffer[start..start + count].fill(0),
+            }
+            self.write_cluster(cluster, &buffer)?;
+
+            done += count as u64;
+        }
+        Ok(())
+    }
+
+    fn load_directory(&self, cluster: u32) -> Result<Directory, FsError> {
+        if cluster == FIXED_ROOT_CLUSTER {
+            let sectors = self.boot_sector.root_dir_sectors();
+            let mut data = vec![0u8; sectors as usize * SECTOR_SIZE];
+            self.drive.read_sectors(
+                self.boot_sector.start_of_root_dir(),
+                sectors,
+                data.as_mut_ptr(),
+            )?;
+            return Ok(Directory {
+                cluster,
+                clusters: vec![],
+                data,
+            });
+        }
+
+        let cluster_size = self.cluster_size() as usize;
+        let clusters = self.cluster_chain(cluster)?;
+        let mut data = vec![0u8; clusters.len() * cluster_size];
+        for (i, cluster) in clusters.iter().enumerate() {
+            self.read_cluster(
+                *cluster,
+                &mut data[i * cluster_size..(i + 1) * cluster_size],
+            )?;
+        }
+
+        Ok(Directory {
+            cluster,
+            clusters,
+            data,
+        })
+    }
+
+    fn store_directory(&self, directory: &Directory) -> Result<(), FsError> {
+        if directory.cluster == FIXED_ROOT_CLUSTER {
+            self.drive.write_sectors(
+                self.boot_sector.start_of_root_dir(),
+                self.boot_sector.root_dir_sectors(),
+                directory.data.as_ptr(),
+            )?;
+            return Ok(());
+        }
+
+        let cluster_size = self.cluster_size() as usize;
+        for (i, cluster) in directory.clusters.iter().enumerate() {
+            self.write_cluster(
+                *cluster,
+                &directory.data[i * cluster_size..(i + 1) * cluster_size],
+            )?;
+        }
+        Ok(())
+    }
+
+    // Appends a cleared cluster to the directory. The fixed root directory can not grow
+    fn grow_directory(&mut self, directory: &mut Directory) -> Result<(), FsError> {
+        if directory.cluster == FIXED_ROOT_CLUSTER {
+            return Err(FsError::NoSpace);
+        }
+        let cluster = self.alloc_cluster(directory.clusters.last().copied())?;
+        directory.clusters.push(cluster);
+        directory
+            .data
+            .resize(directory.data.len() + self.cluster_size() as usize, 0);
+        Ok(())
+    }
+
+    pub fn read_directory(&self, cluster: u32) -> Result<Vec<DirectoryEntry>, FsError> {
+        Ok(Self::parse_directory(&self.load_directory(cluster)?))
+    }
+
+    fn parse_directory(directory: &Directory) -> Vec<DirectoryEntry> {
+        Self::parse_entries(&directory.data).0
+    }
+
+    // Parses the entries in data, whose slots are numbered from its start. Also returns whether the
+    // end of directory marker was found
+    fn parse_entries(data: &[u8]) -> (Vec<DirectoryEntry>, bool) {
+        let buffer = data.as_ptr() as *const StandardDirectory;
+        let mut dirs: Vec<DirectoryEntry> = vec![];
+
+        let mut max_entries = data.len() / DIRECTORY_ENTRY_SIZE;
 
         let mut lfn_buffer = String::new();
+        let mut lfn_slots = 0;
         for i in 0..max_entries {
             let base = unsafe { buffer.offset(i as isize) };
 
             let byte_array = base as *const u8;
             if unsafe { *byte_array } == 0 {
-                break;
+                return (dirs, true);
+            }
+            if unsafe { *byte_array } == DELETED_ENTRY {
+                lfn_buffer.clear();
+                lfn_slots = 0;
+                continue;
             }
 
             let dir = unsafe { &*(buffer as *const StandardDirectory).offset(i as isize) };
-            if dir.attributes == StandardDirectoryAttributes::Lfn {
+            if dir.attributes == StandardDirectoryAttributes::Lfn as u8 {
                 let dir = unsafe { &*(buffer as *const LongFileName).offset(i as isize) };
                 let mut s1 = &{ dir.filename1 }[..];
                 for (i, c) in s1.iter().enumerate() {
@@ -108,99 +518,259 @@ impl<D: Drive> Fat32Fs<D> {
                     }
                 }
 
-                let s1 = String::from_utf16(s1).unwrap();
-                let s2 = String::from_utf16(s2).unwrap();
-                let s3 = String::from_utf16(s3).unwrap();
+                let s1 = String::from_utf16_lossy(s1);
+                let s2 = String::from_utf16_lossy(s2);
+                let s3 = String::from_utf16_lossy(s3);
                 lfn_buffer = s1 + &s2 + &s3 + &lfn_buffer;
+                lfn_slots += 1;
             } else {
-                let s = core::str::from_utf8(&dir.filename).unwrap();
                 dirs.push(DirectoryEntry {
                     name: if lfn_buffer.len() == 0 {
-                        String::from(s)
+                        decode_short_name(&dir.filename, dir.reserved_by_windows)
                     } else {
                         lfn_buffer.clone()
                     },
                     cluster: dir.first_cluster_low as u32 | ((dir.first_cluster_high as u32) << 16),
                     size: dir.file_size_bytes,
                     attributes: dir.attributes,
+                    short_name: dir.filename,
+                    creation_time_hundredths: dir.creation_time_hundredths,
+                    creation_time: dir.creation_time,
+                    creation_date: dir.creation_date,
+                    last_accessed_date: dir.last_accessed_date,
+                    last_modification_time: dir.last_modification_time,
+                    last_modification_date: dir.last_modification_date,
+                    slot: i,
+                    lfn_slots,
                 });
                 lfn_buffer.clear();
+                lfn_slots = 0;
             }
         }
 
-        dirs
-    }
-
-    fn path_to_cluster(&self, path: &str) -> Result<DirectoryEntry, &str> {
-        self.recursive_path_to_cluster(path.split("/").peekable(), 2)
+        (dirs, false)
     }
 
-    // TODO rewrite it as non recursive
-    fn recursive_path_to_cluster(
+    // Returns the first entry that is not a volume label, starting at slot of the given cluster of
+    // a directory, along with the cursor that follows it. The fixed root directory is a single
+    // cluster. Entries can span clusters, so following clusters are loaded until one is complete.
+    // Cursors hold the cluster in the high half and the slot in it in the low one
+    fn next_entry(
         &self,
-        mut path: core::iter::Peekable<core::str::Split<&str>>,
-        cluster: u32,
-    ) -> Result<DirectoryEntry, &str> {
-        let this = path.next();
-        if this == None {
-            return Err("File not found");
-        }
-        let this = this.unwrap();
-
-        let next = path.peek();
-        let directory = self.read_directory(cluster);
-        for entry in directory.iter() {
-            if entry.name.trim() == this {
-                if next.is_some() {
-                    return self.recursive_path_to_cluster(path, entry.cluster);
+        mut cluster: u32,
+        mut slot: usize,
+    ) -> Result<Option<(DirectoryEntry, u64)>, FsError> {
+        let mut clusters = vec![cluster];
+        let mut data = self.load_cluster_of_directory(cluster)?;
+        loop {
+            let (entries, ended) = Self::parse_entries(&data[slot * DIRECTORY_ENTRY_SIZE..]);
+            let entry = entries
+                .into_iter()
+                .find(|entry| entry.attributes & StandardDirectoryAttributes::VolumeId as u8 == 0);
+            if let Some(entry) = entry {
+                // The cursor points past the standard entry, in the cluster that holds it
+                let slots_per_cluster = data.len() / clusters.len() / DIRECTORY_ENTRY_SIZE;
+                let next = slot + entry.slot + 1;
+                let index = next / slots_per_cluster;
+                let next = if index < clusters.len() {
+                    (clusters[index] as u64) << 32 | (next % slots_per_cluster) as u64
                 } else {
-                    return Ok(entry.clone());
+                    match self.next_directory_cluster(clusters[clusters.len() - 1])? {
+                        Some(cluster) => (cluster as u64) << 32,
+                        None => END_OF_DIRECTORY,
+                    }
+                };
+                return Ok(Some((entry, next)));
+            }
+            if ended {
+                return Ok(None);
+            }
+
+            // Only the long file name entries of an incomplete entry need to be parsed again
+            let Some(next) = self.next_directory_cluster(cluster)? else {
+                return Ok(None);
+            };
+            slot = usize::max(
+                slot,
+                (data.len() / DIRECTORY_ENTRY_SIZE).saturating_sub(MAX_LFN_SLOTS),
+            );
+            cluster = next;
+            clusters.push(next);
+            data.extend(self.load_cluster_of_directory(next)?);
+        }
+    }
+
+    fn load_cluster_of_directory(&self, cluster: u32) -> Result<Vec<u8>, FsError> {
+        if cluster == FIXED_ROOT_CLUSTER {
+            return Ok(self.load_directory(cluster)?.data);
+        }
+        let mut data = vec![0u8; self.cluster_size() as usize];
+        self.read_cluster(cluster, &mut data)?;
+        Ok(data)
+    }
+
+    fn next_directory_cluster(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        if cluster == FIXED_ROOT_CLUSTER {
+            return Ok(None);
+        }
+        match self.get_fat_entry(cluster)? {
+            next @ 2..0x0fff_fff8 => Ok(Some(next)),
+            _ => Ok(None),
+        }
+    }
+
+    // Returns the index of the first of count consecutive free slots in the directory
+    fn find_free_slots(directory: &Directory, count: usize) -> Option<usize> {
+        let mut run = 0;
+        for i in 0..directory.data.len() / DIRECTORY_ENTRY_SIZE {
+            let first_byte = directory.data[i * DIRECTORY_ENTRY_SIZE];
+            if first_byte == 0 || first_byte == DELETED_ENTRY {
+                run += 1;
+                if run == count {
+                    return Some(i + 1 - count);
                 }
+            } else {
+                run = 0;
             }
         }
+        None
+    }
 
-        return Err("File not found");
+    // Inodes identify entries by the first cluster of their directory and their slot in it, which
+    // is below 65536. The generation of the slot is in the high 16 bits, so that the inodes of
+    // deleted files never refer to a file created later in the same slot. The root directory has no
+    // entry, and is inode 0
+    fn inode(&self, cluster: u32, slot: usize) -> Inode {
+        let generation = self.generations.get(&(cluster, slot)).copied().unwrap_or(0);
+        (generation as u64) << 48 | (cluster as u64) << 16 | slot as u64
     }
 
-    fn read_cluster_chain(&self, cluster: u32) -> VirtualMapping {
-        // Create file buffer
-        let mut current_cluster = cluster;
-        let mut cluster_count = 0;
-        while current_cluster < 0xFFFFFF8 {
-            cluster_count += 1;
-            current_cluster =
-                unsafe { *(self.fat_buffer as *const u32).offset(current_cluster as isize) };
+    // Loads the directory containing the entry of inode, and the entry
+    fn get_entry(&self, inode: Inode) -> Result<(Directory, DirectoryEntry), FsError> {
+        let cluster = (inode >> 16) as u32;
+        let slot = (inode & 0xffff) as usize;
+        if self.inode(cluster, slot) != inode {
+            return Err(FsError::NotFound);
         }
+        let directory = self.load_directory(cluster)?;
+        let entry = Self::parse_directory(&directory)
+            .into_iter()
+            .find(|entry| entry.slot == slot)
+            .ok_or(FsError::NotFound)?;
+        Ok((directory, entry))
+    }
 
-        let page_count = cluster_count
-            * self.boot_sector.bpd.sectors_per_cluster as u64
-            * self.boot_sector.bpd.bytes_per_sector as u64
-            / 0x1000
-            + 1;
-        let mut file_mapping = KERNEL_VALLOCATOR.lock().alloc_pages(page_count);
+    // Same as get_entry, but fails if inode is a directory
+    fn get_file_entry(&self, inode: Inode) -> Result<(Directory, DirectoryEntry), FsError> {
+        let (directory, entry) = self.get_entry(inode)?;
+        if entry.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        Ok((directory, entry))
+    }
 
-        // Follow cluster chain
-        let mut current_cluster = cluster;
-        let mut file_buffer_offset = 0;
-        while current_cluster < 0xFFFFFF8 {
-            self.drive.read_sectors(
-                self.cluster_to_sector(current_cluster),
-                self.boot_sector.bpd.sectors_per_cluster as u64,
-                (file_mapping.vaddr + file_buffer_offset) as *mut u8,
-            );
+    // Returns the first cluster of the directory inode
+    fn directory_cluster(&self, inode: Inode) -> Result<u32, FsError> {
+        if inode == ROOT_INODE {
+            return Ok(self.root_cluster());
+        }
 
-            file_buffer_offset += self.boot_sector.bpd.sectors_per_cluster as u64
-                * self.boot_sector.bpd.bytes_per_sector as u64;
-            current_cluster =
-                unsafe { *(self.fat_buffer as *const u32).offset(current_cluster as isize) };
+        let (_, entry) = self.get_entry(inode)?;
+        if !entry.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+
+        // ".." entries of subdirectories of the root use cluster 0
+        if entry.cluster == 0 {
+            Ok(self.root_cluster())
+        } else {
+            Ok(entry.cluster)
+        }
+    }
+
+    // Adds an empty entry called name with the given attributes to the directory, and returns the
+    // directory and the slot of the new standard entry
+    fn create_entry(
+        &mut self,
+        directory: Inode,
+        name: &str,
+        attributes: StandardDirectoryAttributes,
+    ) -> Result<(Directory, usize), FsError> {
+        let cluster = self.directory_cluster(directory)?;
+        let mut directory = self.load_directory(cluster)?;
+        let entries = Self::parse_directory(&directory);
+        if entries.iter().any(|entry| entry.matches(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        // Names that are not valid 8.3 names are stored in long file name entries, which precede
+        // the standard entry holding a generated short name
+        let (short_name, case_flags, long_name) = match encode_short_name(name) {
+            Some((short_name, case_flags)) => (short_name, case_flags, vec![]),
+            None => {
+                let short_name = generate_short_name(name, &entries)?;
+                let long_name = long_name_entries(name, short_name_checksum(&short_name))?;
+                (short_name, 0, long_name)
+            }
+        };
+
+        let slot_count = long_name.len() + 1;
+        let slot = loop {
+            match Self::find_free_slots(&directory, slot_count) {
+                Some(slot) => break slot,
+                None => self.grow_directory(&mut directory)?,
+            }
+        };
+
+        let buffer = directory.data.as_mut_ptr();
+        unsafe {
+            for (i, entry) in long_name.into_iter().enumerate() {
+                *(buffer as *mut LongFileName).add(slot + i) = entry;
+            }
+            *(buffer as *mut StandardDirectory).add(slot + slot_count - 1) = StandardDirectory {
+                filename: short_name,
+                attributes: attributes as u8,
+                reserved_by_windows: case_flags,
+                creation_time_hundredths: 0,
+                creation_time: 0,
+                creation_date: 0,
+                last_accessed_date: 0,
+                first_cluster_high: 0,
+                last_modification_time: 0,
+                last_modification_date: 0,
+                first_cluster_low: 0,
+                file_size_bytes: 0,
+            };
         }
 
-        file_mapping
+        Ok((directory, slot + slot_count - 1))
+    }
+
+    // Returns a standard entry with no cluster, used for the "." and ".." entries
+    fn dot_entry(name: &[u8], cluster: u32) -> StandardDirectory {
+        let mut filename = [b' '; 11];
+        filename[..name.len()].copy_from_slice(name);
+        StandardDirectory {
+            filename,
+            attributes: StandardDirectoryAttributes::Directory as u8,
+            reserved_by_windows: 0,
+            creation_time_hundredths: 0,
+            creation_time: 0,
+            creation_date: 0,
+            last_accessed_date: 0,
+            first_cluster_high: (cluster >> 16) as u16,
+            last_modification_time: 0,
+            last_modification_date: 0,
+            first_cluster_low: cluster as u16,
+            file_size_bytes: 0,
+        }
     }
 
     // Depth first search
     pub fn dfs(&self, clusrer: u32, depth: u32) {
-        let directory = self.read_directory(clusrer);
+        let Ok(directory) = self.read_directory(clusrer) else {
+            return;
+        };
         for file in directory {
             for _ in 0..depth {
                 print!(" ");
@@ -211,7 +781,7 @@ impl<D: Drive> Fat32Fs<D> {
             // && file.attributes as u32 & StandardDirectoryAttributes::Hidden as u32 == 0
             {
                 println!("{}", file.name);
-                if file.attributes as u32 & StandardDirectoryAttributes::Directory as u32 != 0 {
+                if file.is_directory() {
                     self.dfs(file.cluster, depth + 1);
                 }
             }
@@ -220,13 +790,356 @@ impl<D: Drive> Fat32Fs<D> {
 }
 
 impl<D: Drive> Fs for Fat32Fs<D> {
-    fn read_file(&self, path: &str) -> Result<File, &str> {
-        let entry = self.path_to_cluster(path)?;
-        Ok(File {
-            mapping: self.read_cluster_chain(entry.cluster),
-            size: entry.size as u64,
-        })
+    fn root(&self) -> Inode {
+        ROOT_INODE
+    }
+
+    fn lookup(&self, directory: Inode, name: &str) -> Result<Inode, FsError> {
+        let cluster = self.directory_cluster(directory)?;
+        self.read_directory(cluster)?
+            .into_iter()
+            .find(|entry| entry.matches(name))
+            .map(|entry| self.inode(cluster, entry.slot))
+            .ok_or(FsError::NotFound)
+    }
+
+    fn stat(&self, inode: Inode) -> Result<Stat, FsError> {
+        if inode == ROOT_INODE {
+            return Ok(Stat {
+                attributes: StandardDirectoryAttributes::Directory as u8,
+                ..Stat::default()
+            });
+        }
+        Ok(self.get_entry(inode)?.1.stat())
+    }
+
+    fn read(&self, file: Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let (_, entry) = self.get_file_entry(file)?;
+
+        let size = entry.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = u64::min(buffer.len() as u64, size - offset);
+
+        // Only the clusters in the requested range are read
+        let cluster_size = self.cluster_size();
+        let chain = self.cluster_chain(entry.cluster)?;
+        let mut cluster_buffer = vec![0u8; cluster_size as usize];
+
+        let mut done = 0;
+        while done < len {
+            let position = offset + done;
+            let start = (position % cluster_size) as usize;
+            let count = u64::min(cluster_size - start as u64, len - done) as usize;
+
+            // The chain is shorter than the size of the file if the volume is corrupted
+            let cluster = *chain
+                .get((position / cluster_size) as usize)
+                .ok_or(FsError::Io)?;
+            self.read_cluster(cluster, &mut cluster_buffer)?;
+            buffer[done as usize..done as usize + count]
+                .copy_from_slice(&cluster_buffer[start..start + count]);
+
+            done += count as u64;
+        }
+        Ok(len as usize)
+    }
+
+    fn read_dir(&self, directory: Inode, cursor: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        let (cluster, slot) = match cursor {
+            0 => (self.directory_cluster(directory)?, 0),
+            END_OF_DIRECTORY => return Ok(None),
+            cursor => ((cursor >> 32) as u32, (cursor & 0xffff_ffff) as usize),
+        };
+        // Cursors come from userspace, and must point into a directory cluster
+        let slots = match cluster {
+            FIXED_ROOT_CLUSTER => self.boot_sector.root_dir_sectors() as usize * SECTOR_SIZE,
+            2.. if cluster < self.cluster_count() + 2 => self.cluster_size() as usize,
+            _ => 0,
+        } / DIRECTORY_ENTRY_SIZE;
+        if slot >= slots {
+            return Err(FsError::NotFound);
+        }
+
+        Ok(self.next_entry(cluster, slot)?.map(|(entry, next)| {
+            let entry = DirEntry {
+                stat: entry.stat(),
+                name: entry.name,
+            };
+            (entry, next)
+        }))
+    }
+
+    fn create(&mut self, directory: Inode, name: &str) -> Result<Inode, FsError> {
+        let (directory, slot) =
+            self.create_entry(directory, name, StandardDirectoryAttributes::Archive)?;
+        self.store_directory(&directory)?;
+        // The writes of an operation reach the drive together, once it is complete
+        self.drive.flush()?;
+        Ok(self.inode(directory.cluster, slot))
+    }
+
+    fn create_dir(&mut self, directory: Inode, name: &str) -> Result<Inode, FsError> {
+        let parent = self.directory_cluster(directory)?;
+        let (mut directory, slot) =
+            self.create_entry(directory, name, StandardDirectoryAttributes::Directory)?;
+
+        // Every directory but the root starts with "." and "..", where ".." uses cluster 0 for
+        // the root
+        let cluster = self.alloc_cluster(None)?;
+        let dotdot = if parent == self.root_cluster() {
+            0
+        } else {
+            parent
+        };
+        let mut data = vec![0u8; self.cluster_size() as usize];
+        unsafe {
+            *(data.as_mut_ptr() as *mut StandardDirectory) = Self::dot_entry(b".", cluster);
+            *(data.as_mut_ptr() as *mut StandardDirectory).add(1) = Self::dot_entry(b"..", dotdot);
+        }
+        self.write_cluster(cluster, &data)?;
+
+        directory.set_entry(slot, cluster, 0);
+        self.store_directory(&directory)?;
+        self.drive.flush()?;
+        Ok(self.inode(directory.cluster, slot))
+    }
+
+    fn write(&mut self, file: Inode, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let (mut directory, entry) = self.get_file_entry(file)?;
+        let size = entry.size as u64;
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|end| *end <= u32::MAX as u64)
+            .ok_or(FsError::NoSpace)?;
+        let new_size = u64::max(size, end);
+
+        let first = self.resize_chain(entry.cluster, self.clusters_for(new_size))?;
+        if offset > size {
+            self.write_chain(first, size, offset - size, None)?;
+        }
+        self.write_chain(first, offset, data.len() as u64, Some(data))?;
+
+        directory.set_entry(entry.slot, first, new_size as u32);
+        self.store_directory(&directory)?;
+        self.drive.flush()?;
+        Ok(())
+    }
+
+    fn truncate(&mut self, file: Inode, size: u64) -> Result<(), FsError> {
+        let (mut directory, entry) = self.get_file_entry(file)?;
+        if size > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+
+        let first = self.resize_chain(entry.cluster, self.clusters_for(size))?;
+        if size > entry.size as u64 {
+            self.write_chain(first, entry.size as u64, size - entry.size as u64, None)?;
+        }
+
+        directory.set_entry(entry.slot, first, size as u32);
+        self.store_directory(&directory)?;
+        self.drive.flush()?;
+        Ok(())
+    }
+
+    fn delete(&mut self, directory: Inode, name: &str) -> Result<(), FsError> {
+        let cluster = self.directory_cluster(directory)?;
+        let mut directory = self.load_directory(cluster)?;
+        let entry = Self::parse_directory(&directory)
+            .into_iter()
+            .find(|entry| entry.matches(name))
+            .ok_or(FsError::NotFound)?;
+        if entry.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+
+        self.free_chain(entry.cluster)?;
+
+        for slot in entry.slot - entry.lfn_slots..=entry.slot {
+            directory.data[slot * DIRECTORY_ENTRY_SIZE] = DELETED_ENTRY;
+        }
+        self.store_directory(&directory)?;
+        self.drive.flush()?;
+
+        let generation = self.generations.entry((cluster, entry.slot)).or_insert(0);
+        *generation = generation.wrapping_add(1);
+        Ok(())
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.drive.cache_stats()
+    }
+}
+
+// A directory loaded in memory, along with the clusters it is stored in. The fixed root directory
+// has FIXED_ROOT_CLUSTER as cluster, and no clusters
+struct Directory {
+    cluster: u32,
+    clusters: Vec<u32>,
+    data: Vec<u8>,
+}
+
+impl Directory {
+    // Updates the first cluster and the size of the standard entry at slot
+    fn set_entry(&mut self, slot: usize, cluster: u32, size: u32) {
+        let entry = unsafe { &mut *(self.data.as_mut_ptr() as *mut StandardDirectory).add(slot) };
+        entry.first_cluster_low = cluster as u16;
+        entry.first_cluster_high = (cluster >> 16) as u16;
+        entry.file_size_bytes = size;
+    }
+}
+
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+// Turns a raw 8.3 name such as "FONT    PSF" into "FONT.PSF", applying the lowercase flags
+fn decode_short_name(short_name: &[u8; 11], case_flags: u8) -> String {
+    let mut short_name = *short_name;
+    if short_name[0] == ESCAPED_DELETED_ENTRY {
+        short_name[0] = DELETED_ENTRY;
+    }
+
+    let decode = |part: &[u8], lowercase: bool| -> String {
+        let len = part.iter().rposition(|c| *c != b' ').map_or(0, |i| i + 1);
+        part[..len]
+            .iter()
+            .map(|c| match lowercase {
+                true => c.to_ascii_lowercase() as char,
+                false => *c as char,
+            })
+            .collect()
+    };
+
+    let base = decode(&short_name[..8], case_flags & LOWERCASE_BASE != 0);
+    let extension = decode(&short_name[8..], case_flags & LOWERCASE_EXTENSION != 0);
+    if extension.is_empty() {
+        base
+    } else {
+        base + "." + &extension
+    }
+}
+
+// Returns the raw 8.3 name and the lowercase flags for name, if it can be stored without long file
+// name entries. Each of the base name and the extension must be all uppercase or all lowercase
+fn encode_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = match name.split_once('.') {
+        Some((base, extension)) => (base, extension),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || name.ends_with('.') {
+        return None;
+    }
+
+    // Returns whether part is lowercase, or None if it is not valid or has mixed case
+    let is_lowercase = |part: &str| -> Option<bool> {
+        if !part
+            .bytes()
+            .all(|c| is_short_name_char(c.to_ascii_uppercase()))
+        {
+            return None;
+        }
+        match (
+            part.bytes().any(|c| c.is_ascii_lowercase()),
+            part.bytes().any(|c| c.is_ascii_uppercase()),
+        ) {
+            (true, true) => None,
+            (lowercase, _) => Some(lowercase),
+        }
+    };
+
+    let mut case_flags = 0;
+    if is_lowercase(base)? {
+        case_flags |= LOWERCASE_BASE;
+    }
+    if is_lowercase(extension)? {
+        case_flags |= LOWERCASE_EXTENSION;
+    }
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.to_ascii_uppercase().as_bytes());
+    Some((short_name, case_flags))
+}
+
+// Generates a short name such as "NOTES~1 TXT", that is not used by any of the entries
+fn generate_short_name(name: &str, entries: &[DirectoryEntry]) -> Result<[u8; 11], FsError> {
+    let (base, extension) = match name.rsplit_once('.') {
+        Some((base, extension)) if !base.is_empty() => (base, extension),
+        _ => (name, ""),
+    };
+    let clean = |s: &str| -> Vec<u8> {
+        s.bytes()
+            .filter(|c| *c != b' ' && *c != b'.')
+            .map(|c| c.to_ascii_uppercase())
+            .map(|c| if is_short_name_char(c) { c } else { b'_' })
+            .collect()
+    };
+    let base = clean(base);
+    let extension = clean(extension);
+
+    let mut short_name = [b' '; 11];
+    let extension_len = usize::min(extension.len(), 3);
+    short_name[8..8 + extension_len].copy_from_slice(&extension[..extension_len]);
+
+    for n in 1..1000000 {
+        let tail = format!("~{}", n);
+        let base_len = usize::min(base.len(), 8 - tail.len());
+        short_name[..8].fill(b' ');
+        short_name[..base_len].copy_from_slice(&base[..base_len]);
+        short_name[base_len..base_len + tail.len()].copy_from_slice(tail.as_bytes());
+
+        if !entries.iter().any(|entry| entry.short_name == short_name) {
+            return Ok(short_name);
+        }
+    }
+    Err(FsError::NoSpace)
+}
+
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, c| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*c)
+    })
+}
+
+// Builds the long file name entries for name, in the order they are stored on disk
+fn long_name_entries(name: &str, checksum: u8) -> Result<Vec<LongFileName>, FsError> {
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    if chars.is_empty()
+        || chars.len() > 255
+        || name == "."
+        || name == ".."
+        || name
+            .chars()
+            .any(|c| c.is_control() || "\"*:<>?\\|".contains(c))
+    {
+        return Err(FsError::InvalidName);
+    }
+
+    // The name is null terminated if it does not fill the last entry, and then padded
+    let count = chars.len().div_ceil(13);
+    if chars.len() % 13 != 0 {
+        chars.push(0);
+    }
+    chars.resize(count * 13, 0xffff);
+
+    let mut entries = vec![];
+    for i in (0..count).rev() {
+        let part = &chars[i * 13..(i + 1) * 13];
+        entries.push(LongFileName {
+            order: (i + 1) as u8 | if i == count - 1 { 0x40 } else { 0 },
+            filename1: part[0..5].try_into().unwrap(),
+            lfn_attribute: StandardDirectoryAttributes::Lfn as u8,
+            t: 0,
+            checksum,
+            filename2: part[5..11].try_into().unwrap(),
+            _reserved: 0,
+            filename3: part[11..13].try_into().unwrap(),
+        });
     }
+    Ok(entries)
 }
 
 #[derive(Clone, Copy)]
@@ -237,12 +1150,52 @@ struct Fat32BootSector {
 }
 
 impl Fat32BootSector {
-    pub fn new<D: Drive>(drive: &D) -> Fat32BootSector {
-        let buffer = MEMORY_MANAGER.lock().physical_map.alloc_frame();
-        clear_page(buffer);
-        drive.read_sectors(0, 1, buffer as *mut u8);
-        let boot_sector = unsafe { *(buffer as *const Fat32BootSector) };
-        boot_sector
+    pub fn new<D: Drive>(drive: &D) -> Result<Fat32BootSector, DriveError> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        drive.read_sectors(0, 1, sector.as_mut_ptr())?;
+        Ok(unsafe { core::ptr::read_unaligned(sector.as_ptr() as *const Fat32BootSector) })
+    }
+
+    // The 16 bit fields are used if they are not zero, which is never the case on FAT32
+    fn fat_size(&self) -> u64 {
+        match self.bpd.table_size_16 {
+            0 => self.ebpb.table_size_32 as u64,
+            size => size as u64,
+        }
+    }
+
+    fn total_sectors(&self) -> u64 {
+        match self.bpd.total_sectors_16 {
+            0 => self.bpd.total_sectors_32 as u64,
+            sectors => sectors as u64,
+        }
+    }
+
+    // Size of the fixed root directory of FAT12 and FAT16, which is 0 on FAT32
+    fn root_dir_sectors(&self) -> u64 {
+        (self.bpd.root_entry_count as u64 * DIRECTORY_ENTRY_SIZE as u64)
+            .div_ceil(self.bpd.bytes_per_sector as u64)
+    }
+
+    fn start_of_root_dir(&self) -> u64 {
+        self.bpd.reserved_sector_count as u64 + self.bpd.table_count as u64 * self.fat_size()
+    }
+
+    fn start_of_data(&self) -> u64 {
+        self.start_of_root_dir() + self.root_dir_sectors()
+    }
+
+    fn cluster_count(&self) -> u32 {
+        ((self.total_sectors() - self.start_of_data()) / self.bpd.sectors_per_cluster as u64) as u32
+    }
+
+    // The type only depends on the number of clusters
+    fn fat_type(&self) -> FatType {
+        match self.cluster_count() {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        }
     }
 }
 
@@ -288,14 +1241,51 @@ pub struct DirectoryEntry {
     pub name: String,
     pub cluster: u32,
     pub size: u32,
-    pub attributes: StandardDirectoryAttributes,
+    pub attributes: u8,
+    pub short_name: [u8; 11],
+    pub creation_time_hundredths: u8,
+    pub creation_time: u16,
+    pub creation_date: u16,
+    pub last_accessed_date: u16,
+    pub last_modification_time: u16,
+    pub last_modification_date: u16,
+    // Index of the standard entry in the directory, which is preceded by lfn_slots long file name
+    // entries
+    pub slot: usize,
+    pub lfn_slots: usize,
+}
+
+impl DirectoryEntry {
+    // Names are matched case insensitively against both the long and the short name
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || decode_short_name(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attributes & StandardDirectoryAttributes::Directory as u8 != 0
+    }
+
+    pub fn stat(&self) -> Stat {
+        Stat {
+            size: self.size as u64,
+            attributes: self.attributes,
+            creation_time_hundredths: self.creation_time_hundredths,
+            creation_time: self.creation_time,
+            creation_date: self.creation_date,
+            last_accessed_date: self.last_accessed_date,
+            last_modification_time: self.last_modification_time,
+            last_modification_date: self.last_modification_date,
+        }
+    }
 }
 
 #[derive(Debug)]
 #[repr(C, packed)]
 pub struct StandardDirectory {
     pub filename: [u8; 11],
-    pub attributes: StandardDirectoryAttributes,
+    // Raw attribute bits, as any value can be found on disk
+    pub attributes: u8,
     pub reserved_by_windows: u8,
     pub creation_time_hundredths: u8,
     pub creation_time: u16,
@@ -0,0 +1,304 @@
+// Consistency checker for FAT volumes. Every chain reachable from the root directory is followed
+// through the FAT, and compared with the size of its directory entry, with the other chains and
+// with the clusters allocated in the FAT
+use super::*;
+use crate::utils::outb;
+
+const BAD_CLUSTER: u32 = 0x0fff_fff7;
+// Port of the debug console of QEMU, which is ignored elsewhere
+const DEBUGCON_PORT: u16 = 0xe9;
+
+// Checks the volume when it is mounted, and repairs it with the fsck-repair feature, checking it
+// again afterwards to make sure that the repair left it clean
+pub fn check_at_mount<D: Drive>(fs: &mut Fat32Fs<D>) {
+    let repair = cfg!(feature = "fsck-repair");
+    log(fs.check(repair));
+    if repair {
+        log(fs.check(false));
+    }
+}
+
+// Prints the result, and copies it to the debug console, which make check-fsck reads
+fn log(result: Result<FsckReport, FsError>) {
+    let text = match result {
+        Ok(report) => format!("{}", report),
+        Err(e) => format!("fsck: {:?}", e),
+    };
+    println!("{}", text);
+    for byte in text.bytes().chain(Some(b'\n')) {
+        outb(DEBUGCON_PORT, byte);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub directories: usize,
+    pub files: usize,
+    // Paths of the entries whose chain runs into a cluster of another chain
+    pub cross_linked: Vec<String>,
+    // Paths of the entries whose chain contains a free, bad or out of range cluster
+    pub broken_chains: Vec<String>,
+    // Paths of the files whose size does not match the length of their chain
+    pub size_mismatches: Vec<String>,
+    // Allocated clusters that are not part of any chain
+    pub lost_clusters: usize,
+    // Sectors of the FAT copies that differ from the first FAT
+    pub fat_mismatches: usize,
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.cross_linked.is_empty()
+            && self.broken_chains.is_empty()
+            && self.size_mismatches.is_empty()
+            && self.lost_clusters == 0
+            && self.fat_mismatches == 0
+    }
+}
+
+impl core::fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "fsck: {} directories, {} files, {} lost clusters, {} mismatched FAT sectors",
+            self.directories, self.files, self.lost_clusters, self.fat_mismatches
+        )?;
+        for path in self.cross_linked.iter() {
+            write!(f, "\nfsck: cross-linked chain {}", path)?;
+        }
+        for path in self.broken_chains.iter() {
+            write!(f, "\nfsck: broken chain {}", path)?;
+        }
+        for path in self.size_mismatches.iter() {
+            write!(f, "\nfsck: size mismatch {}", path)?;
+        }
+        if self.repaired {
+            write!(f, "\nfsck: repaired")?;
+        }
+        if self.is_clean() {
+            write!(f, "\nfsck: clean")?;
+        }
+        Ok(())
+    }
+}
+
+enum ChainEnd {
+    End,
+    CrossLinked,
+    Broken,
+}
+
+impl<D: Drive> Fat32Fs<D> {
+    // Checks the volume and, if repair is set, fixes the problems found:
+    // - chains are cut before a cluster of another chain or an invalid cluster
+    // - clusters past the size of files are freed, and files with missing clusters are shrunk
+    // - lost clusters are freed
+    // - the first FAT is copied over the others
+    // Fails if the drive can't be read, leaving a repair half done
+    pub fn check(&mut self, repair: bool) -> Result<FsckReport, FsError> {
+        let mut report = FsckReport::default();
+        let mut used = vec![false; self.cluster_count() as usize + 2];
+
+        // The first FAT is the one read by the driver, so it is used as the reference
+        self.check_fat_copies(&mut report, repair)?;
+
+        let root = self.root_cluster();
+        let root_valid = root == FIXED_ROOT_CLUSTER
+            || self
+                .check_chain(root, "/", &mut used, &mut report, repair)?
+                .is_some_and(|chain| !chain.is_empty());
+        if root_valid {
+            self.check_directories(root, &mut used, &mut report, repair)?;
+            self.check_lost_clusters(&used, &mut report, repair)?;
+        }
+
+        if repair && !report.is_clean() {
+            self.drive.flush()?;
+            self.next_free = 2;
+            report.repaired = true;
+        }
+        Ok(report)
+    }
+
+    fn check_fat_copies(&mut self, report: &mut FsckReport, repair: bool) -> Result<(), FsError> {
+        let fat_start = self.boot_sector.bpd.reserved_sector_count as u64;
+        let fat_size = self.boot_sector.fat_size();
+        let mut first = [0u8; SECTOR_SIZE];
+        let mut copy = [0u8; SECTOR_SIZE];
+        for sector in 0..fat_size {
+            self.drive
+                .read_sectors(fat_start + sector, 1, first.as_mut_ptr())?;
+            for table in 1..self.boot_sector.bpd.table_count as u64 {
+                let lba = fat_start + table * fat_size + sector;
+                self.drive.read_sectors(lba, 1, copy.as_mut_ptr())?;
+                if copy != first {
+                    report.fat_mismatches += 1;
+                    if repair {
+                        self.drive.write_sectors(lba, 1, first.as_ptr())?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Walks the directory tree starting from the directory at root
+    fn check_directories(
+        &mut self,
+        root: u32,
+        used: &mut [bool],
+        report: &mut FsckReport,
+        repair: bool,
+    ) -> Result<(), FsError> {
+        let cluster_size = self.cluster_size();
+        let mut pending = vec![(root, String::new())];
+        while let Some((cluster, path)) = pending.pop() {
+            report.directories += 1;
+            let mut directory = self.load_directory(cluster)?;
+            let mut modified = false;
+
+            for entry in Self::parse_directory(&directory) {
+                if entry.name == "."
+                    || entry.name == ".."
+                    || entry.attributes & StandardDirectoryAttributes::VolumeId as u8 != 0
+                {
+                    continue;
+                }
+
+                let path = format!("{}/{}", path, entry.name);
+                let chain = if entry.cluster == 0 {
+                    Some(vec![])
+                } else {
+                    self.check_chain(entry.cluster, &path, used, report, repair)?
+                };
+                // Chains that were left invalid can not be followed safely
+                let Some(chain) = chain else {
+                    continue;
+                };
+
+                if entry.is_directory() {
+                    if !chain.is_empty() {
+                        pending.push((entry.cluster, path));
+                    }
+                    continue;
+                }
+
+                report.files += 1;
+                let expected = self.clusters_for(entry.size as u64);
+                if chain.len() == expected {
+                    continue;
+                }
+                report.size_mismatches.push(path);
+                if !repair {
+                    continue;
+                }
+
+                let size = if chain.len() > expected {
+                    if expected > 0 {
+                        self.set_fat_entry(chain[expected - 1], END_OF_CHAIN)?;
+                    }
+                    for cluster in chain[expected..].iter() {
+                        self.set_fat_entry(*cluster, 0)?;
+                        used[*cluster as usize] = false;
+                    }
+                    entry.size
+                } else {
+                    chain.len() as u32 * cluster_size as u32
+                };
+                let first = if expected == 0 || chain.is_empty() {
+                    0
+                } else {
+                    chain[0]
+                };
+                directory.set_entry(entry.slot, first, size);
+                modified = true;
+            }
+
+            if modified {
+                self.store_directory(&directory)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Follows the chain starting at first, marking its clusters as used. Returns the chain, which
+    // is cut at the first invalid cluster if repair is set, or None if it was left invalid
+    fn check_chain(
+        &mut self,
+        first: u32,
+        path: &str,
+        used: &mut [bool],
+        report: &mut FsckReport,
+        repair: bool,
+    ) -> Result<Option<Vec<u32>>, FsError> {
+        let (chain, end) = self.walk_chain(first, used)?;
+        match end {
+            ChainEnd::End => return Ok(Some(chain)),
+            ChainEnd::CrossLinked => report.cross_linked.push(path.to_string()),
+            ChainEnd::Broken => report.broken_chains.push(path.to_string()),
+        }
+        if !repair {
+            return Ok(None);
+        }
+
+        // Shared clusters are left to the chain that reached them first
+        if let Some(last) = chain.last() {
+            self.set_fat_entry(*last, END_OF_CHAIN)?;
+        }
+        Ok(Some(chain))
+    }
+
+    fn walk_chain(&self, first: u32, used: &mut [bool]) -> Result<(Vec<u32>, ChainEnd), FsError> {
+        let end = self.cluster_count() + 2;
+        let mut chain = vec![];
+        let mut cluster = first;
+        loop {
+            if cluster < 2 || cluster >= end {
+                return Ok((chain, ChainEnd::Broken));
+            }
+            if used[cluster as usize] {
+                return Ok((chain, ChainEnd::CrossLinked));
+            }
+            used[cluster as usize] = true;
+            chain.push(cluster);
+
+            match self.get_fat_entry(cluster)? {
+                next if next >= 0x0fff_fff8 => return Ok((chain, ChainEnd::End)),
+                0 | BAD_CLUSTER => return Ok((chain, ChainEnd::Broken)),
+                next => cluster = next,
+            }
+        }
+    }
+
+    // Counts the allocated clusters that no chain reached. Whole FAT sectors are read at once, and
+    // lost clusters are only freed after the scan
+    fn check_lost_clusters(
+        &mut self,
+        used: &[bool],
+        report: &mut FsckReport,
+        repair: bool,
+    ) -> Result<(), FsError> {
+        let mut lost = vec![];
+        let mut index = u64::MAX;
+        let mut sectors = [0u8; 2 * SECTOR_SIZE];
+        for cluster in 2..self.cluster_count() + 2 {
+            if self.fat_offset(cluster) / SECTOR_SIZE as u64 != index {
+                index = self.fat_offset(cluster) / SECTOR_SIZE as u64;
+                sectors = self.read_fat_sectors(index)?;
+            }
+            let entry = self.decode_fat_entry(&sectors, index, cluster);
+            if entry != 0 && entry != BAD_CLUSTER && !used[cluster as usize] {
+                lost.push(cluster);
+            }
+        }
+
+        report.lost_clusters = lost.len();
+        if repair {
+            for cluster in lost {
+                self.set_fat_entry(cluster, 0)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,114 @@
 #![allow(unused)]
 
+use crate::block_cache::CacheStats;
+use crate::drive::DriveError;
 use crate::memory::*;
+use alloc::string::String;
 
+pub const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+
+// Identifies a file or a directory within a filesystem
+pub type Inode = u64;
+
+// Implemented by every filesystem that can be mounted in the VFS
 pub trait Fs {
-    fn read_file(&self, path: &str) -> Result<File, &str>;
+    fn root(&self) -> Inode;
+    // Returns the inode of the entry called name in the directory
+    fn lookup(&self, directory: Inode, name: &str) -> Result<Inode, FsError>;
+    fn stat(&self, inode: Inode) -> Result<Stat, FsError>;
+    // Returns the first entry at or after cursor in the directory, along with the cursor of the
+    // next one, or None past the last entry. Listings start at cursor 0, and cursors are only
+    // meaningful to the filesystem that returned them
+    fn read_dir(&self, directory: Inode, cursor: u64) -> Result<Option<(DirEntry, u64)>, FsError>;
+    // Reads from offset into buffer, and returns the number of bytes read, which is 0 past the end
+    // of the file
+    fn read(&self, file: Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>;
+    // Writes data at offset, growing the file if needed. A gap between the end of the file and
+    // offset is filled with zeros
+    fn write(&mut self, file: Inode, offset: u64, data: &[u8]) -> Result<(), FsError>;
+    // Shrinks or grows the file to size bytes. Grown files are padded with zeros
+    fn truncate(&mut self, file: Inode, size: u64) -> Result<(), FsError>;
+    // Creates an empty regular file called name in the directory
+    fn create(&mut self, directory: Inode, name: &str) -> Result<Inode, FsError>;
+    // Creates an empty directory called name in the directory
+    fn create_dir(&mut self, directory: Inode, name: &str) -> Result<Inode, FsError>;
+    // Deletes the regular file called name from the directory. Filesystems that allow it also delete
+    // empty directories
+    fn delete(&mut self, directory: Inode, name: &str) -> Result<(), FsError>;
+    // Counters of the block cache of the drive, for filesystems that use one
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    InvalidName,
+    NoSpace,
+    // The filesystem can not be modified
+    ReadOnly,
+    // The drive failed to transfer some sectors, or they are past its end
+    Io,
+    // The directory still has entries
+    NotEmpty,
+    // The drive has to be waited for. The operation is undone, and the syscall run again later
+    WouldBlock,
+}
+
+impl From<DriveError> for FsError {
+    fn from(e: DriveError) -> FsError {
+        match e {
+            DriveError::Io | DriveError::Timeout | DriveError::OutOfRange => FsError::Io,
+            DriveError::WouldBlock => FsError::WouldBlock,
+        }
+    }
 }
 
 pub struct File {
     pub mapping: VirtualMapping,
     pub size: u64,
 }
+
+pub struct DirEntry {
+    pub name: String,
+    pub stat: Stat,
+}
+
+// Attributes and timestamps use the FAT encoding
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Stat {
+    pub size: u64,
+    pub attributes: u8,
+    pub creation_time_hundredths: u8,
+    pub creation_time: u16,
+    pub creation_date: u16,
+    pub last_accessed_date: u16,
+    pub last_modification_time: u16,
+    pub last_modification_date: u16,
+}
+
+impl Stat {
+    pub fn is_directory(&self) -> bool {
+        self.attributes & ATTRIBUTE_DIRECTORY != 0
+    }
+
+    // Serializes the fields in the layout of the struct, with the padding zeroed, so that no kernel
+    // memory is copied to userspace
+    pub fn to_bytes(&self) -> [u8; size_of::<Stat>()] {
+        let mut bytes = [0u8; size_of::<Stat>()];
+        bytes[0..8].copy_from_slice(&self.size.to_le_bytes());
+        bytes[8] = self.attributes;
+        bytes[9] = self.creation_time_hundredths;
+        bytes[10..12].copy_from_slice(&self.creation_time.to_le_bytes());
+        bytes[12..14].copy_from_slice(&self.creation_date.to_le_bytes());
+        bytes[14..16].copy_from_slice(&self.last_accessed_date.to_le_bytes());
+        bytes[16..18].copy_from_slice(&self.last_modification_time.to_le_bytes());
+        bytes[18..20].copy_from_slice(&self.last_modification_date.to_le_bytes());
+        bytes
+    }
+}
@@ -74,6 +74,7 @@ pub fn init() -> Result<(), ()> {
         // TSS segment
         TSS.lock().privilege_stacks[0] = KERNEL_VALLOCATOR.lock().alloc_pages(1).vaddr;
         TSS.lock().interrupt_stacks[0] = KERNEL_VALLOCATOR.lock().alloc_pages(1).vaddr;
+        TSS.lock().interrupt_stacks[1] = KERNEL_VALLOCATOR.lock().alloc_pages(1).vaddr + 0x1000;
 
         let mut tss_descriptor = SystemSegmentDescriptor::new_tss_segment(&TSS.lock());
         gdt.0[TSS_SEGMENT_INDEX].0 = tss_descriptor.0;
@@ -63,6 +63,8 @@ pub fn init() -> Result<(), ()> {
         idt.0[32 + 0].set_interrupt_handler(timer_handler);
         idt.0[32 + 1].set_interrupt_handler(keyboard_handler);
         idt.0[32 + 12].set_interrupt_handler(mouse_handler);
+        idt.0[32 + 14].set_interrupt_handler(primary_ata_handler);
+        idt.0[32 + 15].set_interrupt_handler(secondary_ata_handler);
 
         // Syscalls
         idt.0[0x80].set_interrupt_handler(syscall_handler);
@@ -74,6 +76,11 @@ pub fn init() -> Result<(), ()> {
     Ok(())
 }
 
+// PCI interrupt lines are only known after enumeration, so their handlers are set by the drivers
+pub fn set_virtio_blk_irq(irq: u8) {
+    IDT.lock().0[32 + irq as usize].set_interrupt_handler(virtio_blk_handler);
+}
+
 #[repr(C, packed)]
 struct IdtDescriptor {
     size: u16,
@@ -118,7 +125,8 @@ impl IdtEntry {
         self.set_offset(handler as u64);
         self.set_segment_selector(KERNEL_CODE_SEGMENT_SELECTOR as u16);
         self.set_interrupt_stack(1);
-        self.set_gate_type(GateType::Trap);
+        // Interrupt gate, so that the timer can not preempt the handler, since they share IST1
+        self.set_gate_type(GateType::Interrupt);
         self.set_dpl(PrivilegeLevel::Ring0);
         self.set_present(true);
     }
@@ -130,7 +138,7 @@ impl IdtEntry {
         self.set_offset(handler as u64);
         self.set_segment_selector(KERNEL_CODE_SEGMENT_SELECTOR as u16);
         self.set_interrupt_stack(1);
-        self.set_gate_type(GateType::Trap);
+        self.set_gate_type(GateType::Interrupt);
         self.set_dpl(PrivilegeLevel::Ring0);
         self.set_present(true);
     }
@@ -1,8 +1,38 @@
 use super::println;
 use super::InterruptStackFrame;
+use crate::process::*;
 use core::arch::asm;
 
-pub extern "x86-interrupt" fn division_error(_stack_frame: InterruptStackFrame) {
+fn read_cr2() -> u64 {
+    let cr2: u64;
+    unsafe { asm!("mov {}, cr2", out(reg) cr2) };
+    cr2
+}
+
+// If the exception was raised in user mode, the faulting process is killed and the next one is
+// scheduled. Otherwise returns, so that the handler can panic
+fn kill_faulting_process(name: &str, stack_frame: &InterruptStackFrame, error_code: u64) {
+    if stack_frame.code_segment & 0b11 != 3 {
+        return;
+    }
+
+    let current_process = PROCESS_LIST.lock().current_process;
+    let pid = PROCESS_LIST.lock().processes[current_process].pid;
+    println!(
+        "{} in process {}, killing it\n\tRIP: 0x{:x}\n\tCR2: 0x{:x}\n\tError: 0x{:x}",
+        name,
+        pid,
+        stack_frame.instruction_ptr,
+        read_cr2(),
+        error_code
+    );
+
+    PROCESS_LIST.lock().kill(pid, ExitStatus::Faulted);
+    schedule();
+}
+
+pub extern "x86-interrupt" fn division_error(stack_frame: InterruptStackFrame) {
+    kill_faulting_process("Division by zero", &stack_frame, 0);
     panic!("Division by zero occurred");
 }
 
@@ -18,22 +48,26 @@ pub extern "x86-interrupt" fn breakpoint(stack_frame: InterruptStackFrame) {
     panic!("Breakpoint exception occurred");
 }
 
-pub extern "x86-interrupt" fn overflow(_stack_frame: InterruptStackFrame) {
+pub extern "x86-interrupt" fn overflow(stack_frame: InterruptStackFrame) {
+    kill_faulting_process("Overflow", &stack_frame, 0);
     panic!("Overflow occurred");
 }
 
-pub extern "x86-interrupt" fn bound_range_exceeded(_stack_frame: InterruptStackFrame) {
+pub extern "x86-interrupt" fn bound_range_exceeded(stack_frame: InterruptStackFrame) {
+    kill_faulting_process("Bound range exceeded", &stack_frame, 0);
     panic!("Bound range exceeded");
 }
 
 pub extern "x86-interrupt" fn invalid_opcode(stack_frame: InterruptStackFrame) {
+    kill_faulting_process("Invalid opcode", &stack_frame, 0);
     panic!(
         "Invalid opcode\n\tRIP: {:x}\n\tCS: {:x}",
         stack_frame.instruction_ptr, stack_frame.code_segment
     );
 }
 
-pub extern "x86-interrupt" fn device_not_available(_stack_frame: InterruptStackFrame) {
+pub extern "x86-interrupt" fn device_not_available(stack_frame: InterruptStackFrame) {
+    kill_faulting_process("Device not available", &stack_frame, 0);
     panic!("Device not available");
 }
 
@@ -50,16 +84,18 @@ pub extern "x86-interrupt" fn invalid_tss(_stack_frame: InterruptStackFrame, _er
 }
 
 pub extern "x86-interrupt" fn segment_not_present(
-    _stack_frame: InterruptStackFrame,
-    _error_code: u64,
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
 ) {
+    kill_faulting_process("Segment not present", &stack_frame, error_code);
     panic!("Segment not present");
 }
 
 pub extern "x86-interrupt" fn stack_segment_fault(
-    _stack_frame: InterruptStackFrame,
-    _error_code: u64,
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
 ) {
+    kill_faulting_process("Stack segment fault", &stack_frame, error_code);
     panic!("Stack segment fault");
 }
 
@@ -67,8 +103,9 @@ pub extern "x86-interrupt" fn general_protection_fault(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
-    let cr2: u64;
-    unsafe { asm!("mov {}, cr2", out(reg) cr2) };
+    kill_faulting_process("General protection fault", &stack_frame, error_code);
+
+    let cr2 = read_cr2();
     panic!(
         "General protection fault\n\tRIP: 0x{:x}\n\tCR2: 0x{:x}\n\tError: 0x{:x}",
         stack_frame.instruction_ptr, cr2, error_code
@@ -76,27 +113,31 @@ pub extern "x86-interrupt" fn general_protection_fault(
 }
 
 pub extern "x86-interrupt" fn page_fault(stack_frame: InterruptStackFrame, error_code: u64) {
-    let cr2: u64;
-    unsafe { asm!("mov {}, cr2", out(reg) cr2) };
+    kill_faulting_process("Page fault", &stack_frame, error_code);
+
+    let cr2 = read_cr2();
     panic!(
         "Page fault\n\tRIP: 0x{:x}\n\tCR2: 0x{:x}\n\tError: 0x{:x}",
         stack_frame.instruction_ptr, cr2, error_code
     );
 }
 
-pub extern "x86-interrupt" fn x87_floating_point_exception(_stack_frame: InterruptStackFrame) {
+pub extern "x86-interrupt" fn x87_floating_point_exception(stack_frame: InterruptStackFrame) {
+    kill_faulting_process("X87 floating point exception", &stack_frame, 0);
     panic!("X87 floating point exception occurred");
 }
 
-pub extern "x86-interrupt" fn alignment_check(_stack_frame: InterruptStackFrame, _error_code: u64) {
+pub extern "x86-interrupt" fn alignment_check(stack_frame: InterruptStackFrame, error_code: u64) {
+    kill_faulting_process("Alignment check exception", &stack_frame, error_code);
     panic!("Alignment check exception occurred");
 }
 
 pub extern "x86-interrupt" fn machine_check(_stack_frame: InterruptStackFrame) {
-    panic!("X87 floating point exception occurred");
+    panic!("Machine check exception occurred");
 }
 
-pub extern "x86-interrupt" fn simd_floating_point_exception(_stack_frame: InterruptStackFrame) {
+pub extern "x86-interrupt" fn simd_floating_point_exception(stack_frame: InterruptStackFrame) {
+    kill_faulting_process("Simd floating point exception", &stack_frame, 0);
     panic!("Simd floating point exception occurred");
 }
 
@@ -1,7 +1,9 @@
 use super::super::print;
 use super::syscalls::*;
 use super::*;
+use crate::ata;
 use crate::fat32::*;
+use crate::fs::{FsError, Stat};
 use crate::ipc;
 use crate::memory::*;
 use crate::mouse::*;
@@ -12,8 +14,11 @@ use crate::stdin::scancodes::*;
 use crate::stdin::*;
 use crate::stdout::*;
 use crate::utils::*;
+use crate::vfs::*;
+use crate::virtio_blk;
 use crate::Fs;
 use alloc::string::*;
+use alloc::vec::Vec;
 use core::arch::*;
 use core::fmt::Write;
 use core::str::FromStr;
@@ -24,25 +29,22 @@ pub extern "x86-interrupt" fn timer_handler(stack_frame: InterruptStackFrame) {
     ctx.rip = stack_frame.instruction_ptr;
     ctx.rflags = stack_frame.r_flags;
 
-    if PROCESS_LIST.lock().processes.len() == 0 {
-        panic!("Empty process list");
-    }
-
     let mut current_process = PROCESS_LIST.lock().current_process;
     if PROCESS_LIST.lock().jump_to_multitasking {
         PROCESS_LIST.lock().jump_to_multitasking = false;
-    } else {
+    } else if !PROCESS_LIST.lock().idle {
         PROCESS_LIST.lock().processes[current_process].context = ctx;
+        PROCESS_LIST.lock().processes[current_process].state = ProcessState::Ready;
+        PROCESS_LIST.lock().processes[current_process].cpu_ticks += 1;
     }
-    // PROCESS_LIST.lock().processes[current_process].invalidate_tlb();
 
     *MILLISECONDS_SINCE_STARTUP.lock() += 1;
     end_of_interrupt(0);
 
     // Switch task
-    current_process = (current_process + 1) % PROCESS_LIST.lock().processes.len();
-    PROCESS_LIST.lock().current_process = current_process;
-    PROCESS_LIST.lock().processes[current_process].reenter();
+    let now = *MILLISECONDS_SINCE_STARTUP.lock();
+    PROCESS_LIST.lock().wake_expired(now);
+    schedule();
 }
 
 pub extern "x86-interrupt" fn keyboard_handler(_stack_frame: InterruptStackFrame) {
@@ -59,6 +61,21 @@ pub extern "x86-interrupt" fn keyboard_handler(_stack_frame: InterruptStackFrame
     end_of_interrupt(1);
 }
 
+pub extern "x86-interrupt" fn primary_ata_handler(_stack_frame: InterruptStackFrame) {
+    ata::handle_irq(0);
+    end_of_interrupt(14);
+}
+
+pub extern "x86-interrupt" fn secondary_ata_handler(_stack_frame: InterruptStackFrame) {
+    ata::handle_irq(1);
+    end_of_interrupt(15);
+}
+
+pub extern "x86-interrupt" fn virtio_blk_handler(_stack_frame: InterruptStackFrame) {
+    let irq = virtio_blk::handle_irq();
+    end_of_interrupt(irq);
+}
+
 pub extern "x86-interrupt" fn mouse_handler(_stack_frame: InterruptStackFrame) {
     while inb(0x64) & 1 != 0 {
         let buttons = inb(0x60);
@@ -88,16 +105,15 @@ pub extern "x86-interrupt" fn mouse_handler(_stack_frame: InterruptStackFrame) {
     end_of_interrupt(12);
 }
 
-pub fn print(ctx: Context) {
-    let ptr = ctx.rcx as *const u8;
-    let len = ctx.rdx as usize;
-    unsafe {
-        let string = core::str::from_raw_parts(ptr, len);
-        STDOUT.lock().write_str(string);
-    }
+pub fn print(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    check_user_range(current_process, ctx.rcx, ctx.rdx, false)?;
+    let bytes = unsafe { core::slice::from_raw_parts(ctx.rcx as *const u8, ctx.rdx as usize) };
+    let string = core::str::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)?;
+    STDOUT.lock().write_str(string);
+    Ok(())
 }
 
-pub fn put_screen_buffer(ctx: Context) {
+pub fn put_screen_buffer(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
     let buffer = ctx.rcx as *const u32;
     let mut x = ctx.rdx;
     let mut y = ctx.r8;
@@ -108,12 +124,13 @@ pub fn put_screen_buffer(ctx: Context) {
     // Bounds checking
     x = u64::min(x, frame_buffer.width);
     y = u64::min(y, frame_buffer.height);
-    if x + w >= frame_buffer.width {
+    if x.saturating_add(w) >= frame_buffer.width {
         w = frame_buffer.width - x;
     }
-    if y + h >= frame_buffer.height {
+    if y.saturating_add(h) >= frame_buffer.height {
         h = frame_buffer.height - y;
     }
+    check_user_range(current_process, ctx.rcx, w * h * 4, false)?;
     let base = frame_buffer.base as *mut u32;
 
     for i in 0..h {
@@ -125,54 +142,229 @@ pub fn put_screen_buffer(ctx: Context) {
             );
         }
     }
+    Ok(())
 }
 
-pub fn get_screen_size(current_process: usize, ctx: Context) {
+pub fn get_screen_size(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
     PROCESS_LIST.lock().processes[current_process].context.rcx = STDOUT.lock().frame_buffer.width;
     PROCESS_LIST.lock().processes[current_process].context.rdx = STDOUT.lock().frame_buffer.height;
+    Ok(())
 }
 
-pub fn load_file(current_process: usize, ctx: Context) {
-    let path = {
-        let ptr = PROCESS_LIST.lock().processes[current_process].context.rcx;
-        let len = PROCESS_LIST.lock().processes[current_process].context.rdx;
-        unsafe { core::str::from_raw_parts(ptr as *const u8, len as usize) }
+// Flags of the open syscall
+const OPEN_CREATE: u64 = 1 << 0;
+const OPEN_TRUNCATE: u64 = 1 << 1;
+
+// Opens the file at the path in rcx, with the flags in r8. The file descriptor is returned in r8
+pub fn open(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let path = copy_string_from_user(current_process, ctx.rcx, ctx.rdx)?;
+
+    let handle = {
+        let mut vfs = VFS.lock();
+        if ctx.r8 & OPEN_CREATE != 0 {
+            match vfs.create(&path) {
+                Ok(_) | Err(FsError::AlreadyExists) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let handle = vfs.resolve(&path)?;
+        if vfs.stat(handle)?.is_directory() {
+            return Err(SyscallError::IsADirectory);
+        }
+        if ctx.r8 & OPEN_TRUNCATE != 0 {
+            vfs.truncate(handle, 0)?;
+        }
+        handle
     };
 
-    let mut ptr = 0;
-    let mut size = 0;
-    if let Ok(file) = FAT32.lock().as_ref().unwrap().read_file(path) {
-        ptr = file.mapping.vaddr;
-        size = file.size;
-        PROCESS_LIST.lock().processes[current_process]
-            .mappings
-            .push(file.mapping);
+    let fd =
+        PROCESS_LIST.lock().processes[current_process].add_file(OpenFile { handle, offset: 0 });
+    PROCESS_LIST.lock().processes[current_process].context.r8 = fd;
+    Ok(())
+}
+
+// Returns the handle and the offset of the open file with descriptor fd
+fn get_open_file(current_process: usize, fd: u64) -> Result<(Handle, u64), SyscallError> {
+    let mut process_list = PROCESS_LIST.lock();
+    let file = process_list.processes[current_process]
+        .get_file(fd)
+        .ok_or(SyscallError::BadFileDescriptor)?;
+    Ok((file.handle, file.offset))
+}
+
+fn set_file_offset(current_process: usize, fd: u64, offset: u64) {
+    if let Some(file) = PROCESS_LIST.lock().processes[current_process].get_file(fd) {
+        file.offset = offset;
     }
+}
+
+// Reads up to r8 bytes from the file rcx into the buffer in rdx. The number of bytes read is
+// returned in r8, and is 0 at the end of the file
+pub fn read(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let (handle, offset) = get_open_file(current_process, ctx.rcx)?;
+    check_user_range(current_process, ctx.rdx, ctx.r8, true)?;
+    let buffer = unsafe { core::slice::from_raw_parts_mut(ctx.rdx as *mut u8, ctx.r8 as usize) };
 
-    PROCESS_LIST.lock().processes[current_process].context.r8 = ptr;
-    PROCESS_LIST.lock().processes[current_process].context.r9 = size;
+    let read = VFS.lock().read(handle, offset, buffer)?;
+
+    set_file_offset(current_process, ctx.rcx, offset + read as u64);
+    PROCESS_LIST.lock().processes[current_process].context.r8 = read as u64;
+    Ok(())
 }
 
-pub fn alloc_pages(current_process: usize, ctx: Context) {
-    let page_count = PROCESS_LIST.lock().processes[current_process].context.rcx;
+// Writes r8 bytes from the buffer in rdx to the file rcx
+pub fn write(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let (handle, offset) = get_open_file(current_process, ctx.rcx)?;
+    check_user_range(current_process, ctx.rdx, ctx.r8, false)?;
+    let data = unsafe { core::slice::from_raw_parts(ctx.rdx as *const u8, ctx.r8 as usize) };
+
+    VFS.lock().write(handle, offset, data)?;
 
-    let mapping = KERNEL_VALLOCATOR.lock().alloc_pages(page_count);
-    let vaddr = mapping.vaddr;
+    set_file_offset(current_process, ctx.rcx, offset + data.len() as u64);
+    PROCESS_LIST.lock().processes[current_process].context.r8 = data.len() as u64;
+    Ok(())
+}
+
+// Moves the offset of the file rcx by the signed amount in rdx, starting from the beginning of the
+// file, the current offset or the end of the file if r8 is 0, 1 or 2. The new offset is returned
+// in r8
+pub fn seek(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let (handle, offset) = get_open_file(current_process, ctx.rcx)?;
+
+    let base = match ctx.r8 {
+        0 => 0,
+        1 => offset,
+        2 => VFS.lock().stat(handle)?.size,
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    let new_offset = base
+        .checked_add_signed(ctx.rdx as i64)
+        .ok_or(SyscallError::InvalidArgument)?;
+
+    set_file_offset(current_process, ctx.rcx, new_offset);
+    PROCESS_LIST.lock().processes[current_process].context.r8 = new_offset;
+    Ok(())
+}
+
+pub fn close(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
     PROCESS_LIST.lock().processes[current_process]
-        .mappings
-        .push(mapping);
+        .remove_file(ctx.rcx)
+        .ok_or(SyscallError::BadFileDescriptor)?;
+    Ok(())
+}
+
+pub fn create_file(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let path = copy_string_from_user(current_process, ctx.rcx, ctx.rdx)?;
+    VFS.lock().create(&path)?;
+    Ok(())
+}
+
+pub fn write_file(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let path = copy_string_from_user(current_process, ctx.rcx, ctx.rdx)?;
+    // The data is read in place, since it can be larger than the kernel heap
+    check_user_range(current_process, ctx.r8, ctx.r9, false)?;
+    let data = unsafe { core::slice::from_raw_parts(ctx.r8 as *const u8, ctx.r9 as usize) };
+
+    let mut vfs = VFS.lock();
+    let handle = vfs.resolve(&path)?;
+    vfs.write(handle, ctx.r10, data)?;
+    Ok(())
+}
+
+pub fn truncate_file(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let path = copy_string_from_user(current_process, ctx.rcx, ctx.rdx)?;
+    let mut vfs = VFS.lock();
+    let handle = vfs.resolve(&path)?;
+    vfs.truncate(handle, ctx.r8)?;
+    Ok(())
+}
+
+pub fn create_dir(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let path = copy_string_from_user(current_process, ctx.rcx, ctx.rdx)?;
+    VFS.lock().create_dir(&path)?;
+    Ok(())
+}
+
+pub fn delete_file(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let path = copy_string_from_user(current_process, ctx.rcx, ctx.rdx)?;
+    VFS.lock().delete(&path)?;
+    Ok(())
+}
+
+// Copies the name of the first entry at or after the cursor in r8 of the directory to the buffer in
+// r9, of capacity r10. Listings start at cursor 0. The length of the name is returned in r8 and the
+// cursor of the next entry in r10, and r9 is set to 0 past the last entry
+pub fn read_dir(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let path = copy_string_from_user(current_process, ctx.rcx, ctx.rdx)?;
+    let entry = {
+        let vfs = VFS.lock();
+        let handle = vfs.resolve(&path)?;
+        vfs.read_dir(handle, ctx.r8)?
+    };
+
+    match entry {
+        Some((entry, next)) => {
+            PROCESS_LIST.lock().processes[current_process].context.r8 = entry.name.len() as u64;
+            if entry.name.len() as u64 > ctx.r10 {
+                return Err(SyscallError::BufferTooSmall);
+            }
+            copy_to_user(current_process, ctx.r9, entry.name.as_bytes())?;
+            PROCESS_LIST.lock().processes[current_process].context.r9 = 1;
+            PROCESS_LIST.lock().processes[current_process].context.r10 = next;
+        }
+        None => {
+            PROCESS_LIST.lock().processes[current_process].context.r9 = 0;
+        }
+    }
+    Ok(())
+}
+
+pub fn stat(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let path = copy_string_from_user(current_process, ctx.rcx, ctx.rdx)?;
+    let stat = {
+        let vfs = VFS.lock();
+        let handle = vfs.resolve(&path)?;
+        vfs.stat(handle)?
+    };
+
+    copy_to_user(current_process, ctx.r8, &stat.to_bytes())
+}
+
+// Copies the block cache counters of the filesystem that holds path to the buffer in r8. Fails with
+// InvalidArgument if the filesystem has no block cache
+pub fn cache_stats(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let path = copy_string_from_user(current_process, ctx.rcx, ctx.rdx)?;
+    let stats = {
+        let vfs = VFS.lock();
+        let handle = vfs.resolve(&path)?;
+        vfs.cache_stats(handle)
+    };
+
+    let stats = stats.ok_or(SyscallError::InvalidArgument)?;
+    copy_to_user(current_process, ctx.r8, &stats.to_bytes())
+}
+
+pub fn alloc_pages(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let page_count = PROCESS_LIST.lock().processes[current_process].context.rcx;
+
+    let vaddr = PROCESS_LIST.lock().processes[current_process]
+        .alloc_pages(page_count)
+        .ok_or(SyscallError::NoSpace)?;
 
     PROCESS_LIST.lock().processes[current_process].context.rdx = vaddr;
+    Ok(())
 }
 
-pub fn get_mouse(current_process: usize, ctx: Context) {
+pub fn get_mouse(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
     PROCESS_LIST.lock().processes[current_process].context.rcx = MOUSE_POS.lock().0;
     PROCESS_LIST.lock().processes[current_process].context.rdx = MOUSE_POS.lock().1;
     PROCESS_LIST.lock().processes[current_process].context.r8 = MOUSE_POS.lock().2 as u64;
     PROCESS_LIST.lock().processes[current_process].context.r9 = MOUSE_POS.lock().3 as u64;
+    Ok(())
 }
 
-pub fn get_key(current_process: usize, ctx: Context) {
+pub fn get_key(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
     PROCESS_LIST.lock().processes[current_process].context.rcx =
         STDIN.lock().keyboard_int.is_some() as u64;
 
@@ -209,118 +401,255 @@ pub fn get_key(current_process: usize, ctx: Context) {
     PROCESS_LIST.lock().processes[current_process].context.rdx = c as u64;
     PROCESS_LIST.lock().processes[current_process].context.r8 = sc as u64;
     STDIN.lock().keyboard_int = None;
+    Ok(())
 }
 
-pub fn exec(current_process: usize, ctx: Context) {
-    let ptr = ctx.rcx as *const u8;
-    let len = ctx.rdx as usize;
-    let string = unsafe { core::str::from_raw_parts(ptr, len) };
-    let fat = FAT32.lock();
-    let file = fat.as_ref().unwrap().read_file(string);
-    match file {
-        Ok(f) => {
+pub fn exec(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let path = copy_string_from_user(current_process, ctx.rcx, ctx.rdx)?;
+    let mut partial = PROCESS_LIST.lock().processes[current_process]
+        .partial_read
+        .take();
+    let file = match VFS.lock().read_file(&path, &mut partial) {
+        Ok(file) => Some(file),
+        Err(FsError::WouldBlock) => {
+            PROCESS_LIST.lock().processes[current_process].partial_read = partial;
+            return Err(SyscallError::WouldBlock);
+        }
+        Err(_) => None,
+    };
+    let parent = PROCESS_LIST.lock().processes[current_process].pid;
+
+    // The frames of the new process are written through the identity map, which the mappings of
+    // the caller may shadow
+    let pid = with_kernel_plm4(|| {
+        let proc = crate::elf::ElfExecutable::new(file?);
+        let mut address_space = AddressSpace::new();
+        let mappings = proc.load_all(&mut address_space)?;
+        Some(PROCESS_LIST.lock().push_process(
+            path,
+            Some(parent),
+            address_space,
+            mappings,
+            proc.get_entry(),
+        ))
+    });
+    match pid {
+        Some(pid) => {
             PROCESS_LIST.lock().processes[current_process].context.r8 = 1;
-            let proc = crate::elf::ElfExecutable::new(f);
-            let pid = PROCESS_LIST
-                .lock()
-                .push_process(proc.load_all(), proc.get_entry());
             PROCESS_LIST.lock().processes[current_process].context.r9 = pid as u64;
         }
-        Err(e) => {
+        None => {
             PROCESS_LIST.lock().processes[current_process].context.r8 = 0;
         }
     }
-    unsafe {
-        FAT32.force_unlock();
-    }
+    Ok(())
 }
 
 // For some reason, if this function is automatically inlined, the kernel will throw a GP fault,
 // even if neither syscall_handler nor this specific routine have been called...
 #[inline(never)]
-pub fn get_shared_page(current_process: usize, ctx: Context) {
+pub fn get_shared_page(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
     PROCESS_LIST.lock().processes[current_process].context.rcx = *SHARED_PAGE.lock();
+    Ok(())
 }
 
-pub fn get_milliseconds_since_startup(current_process: usize, ctx: Context) {
+pub fn get_milliseconds_since_startup(
+    current_process: usize,
+    ctx: Context,
+) -> Result<(), SyscallError> {
     PROCESS_LIST.lock().processes[current_process].context.rcx = *MILLISECONDS_SINCE_STARTUP.lock();
+    Ok(())
+}
+
+pub fn sleep(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let until = MILLISECONDS_SINCE_STARTUP.lock().saturating_add(ctx.rcx);
+    PROCESS_LIST.lock().processes[current_process].state = ProcessState::Sleeping(until);
+    Ok(())
 }
 
-pub fn exit(current_process: usize, ctx: Context) {
+// The exit code is in rcx
+pub fn exit(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
     let mut pid = PROCESS_LIST.lock().processes[current_process].pid;
-    PROCESS_LIST.lock().kill(pid);
+    PROCESS_LIST
+        .lock()
+        .kill(pid, ExitStatus::Exited(ctx.rcx as u32));
+    Ok(())
 }
 
-pub fn kill(current_process: usize, ctx: Context) {
-    let mut pid = ctx.rcx as u32;
-    PROCESS_LIST.lock().kill(pid);
+// Ends the process with pid rcx. Fails if there is no such process
+pub fn kill(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let pid = ctx.rcx as u32;
+    let mut process_list = PROCESS_LIST.lock();
+    if !process_list.is_alive(pid) {
+        return Err(SyscallError::NotFound);
+    }
+    process_list.kill(pid, ExitStatus::Killed);
+    Ok(())
 }
 
-pub fn create_mail_box(current_process: usize, ctx: Context) {
-    let mut pid = PROCESS_LIST.lock().processes[current_process].pid;
-    let name = unsafe {
-        let ptr = ctx.rcx;
-        let len = ctx.rdx;
-        core::str::from_raw_parts(ptr as *const u8, len as usize)
+// Flags of the waitpid syscall
+const WAIT_ANY: u64 = u64::MAX; // Passed as pid to wait for any child
+const WAIT_NO_HANG: u64 = 1 << 0; // Return immediately if no child has ended yet
+
+// Waits for the child with pid rcx to end, or any child if rcx is WAIT_ANY, and reaps it. r8 is set
+// to 1 if a child was reaped, in which case r9 holds its pid, and r10 and r11 its exit reason and
+// code. Fails if the caller has no such child
+pub fn waitpid(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let pid = match ctx.rcx {
+        WAIT_ANY => None,
+        pid => Some(pid as u32),
+    };
+    let parent = PROCESS_LIST.lock().processes[current_process].pid;
+
+    let zombie = PROCESS_LIST.lock().reap(parent, pid);
+    if let Some(zombie) = zombie {
+        let (reason, code) = zombie.status.code();
+        let context = &mut PROCESS_LIST.lock().processes[current_process].context;
+        context.r8 = 1;
+        context.r9 = zombie.pid as u64;
+        context.r10 = reason;
+        context.r11 = code;
+        return Ok(());
+    }
+
+    if !PROCESS_LIST.lock().has_child(parent, pid) {
+        return Err(SyscallError::NotFound);
+    }
+    if ctx.rdx & WAIT_NO_HANG != 0 {
+        PROCESS_LIST.lock().processes[current_process].context.r8 = 0;
+    } else {
+        // The syscall is run again when a child ends
+        PROCESS_LIST.lock().processes[current_process].state =
+            ProcessState::Blocked(WaitEvent::Child(parent), None);
+    }
+    Ok(())
+}
+
+// Copies the pids of every process to the buffer at rcx, which has room for rdx of them. The number
+// of processes is returned in r8, also when the buffer is too small
+pub fn list_processes(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let pids: Vec<u32> = PROCESS_LIST
+        .lock()
+        .processes
+        .iter()
+        .map(|p| p.pid)
+        .collect();
+
+    PROCESS_LIST.lock().processes[current_process].context.r8 = pids.len() as u64;
+    if pids.len() as u64 > ctx.rdx {
+        return Err(SyscallError::BufferTooSmall);
+    }
+    let bytes: Vec<u8> = pids.iter().flat_map(|pid| pid.to_le_bytes()).collect();
+    copy_to_user(current_process, ctx.rcx, &bytes)
+}
+
+// Copies the ProcessInfo of the process with pid rcx to rdx, and its executable path to the buffer
+// at r8, of r9 bytes. The length of the path is returned in r9, also when the buffer is too small
+pub fn process_info(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let (info, path) = {
+        let list = PROCESS_LIST.lock();
+        let process = list
+            .processes
+            .iter()
+            .find(|p| p.pid == ctx.rcx as u32)
+            .ok_or(SyscallError::NotFound)?;
+        (process.info(), process.path.clone())
     };
-    let name = String::from(name);
+
+    PROCESS_LIST.lock().processes[current_process].context.r9 = path.len() as u64;
+    if path.len() as u64 > ctx.r9 {
+        return Err(SyscallError::BufferTooSmall);
+    }
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            &info as *const ProcessInfo as *const u8,
+            size_of::<ProcessInfo>(),
+        )
+    };
+    copy_to_user(current_process, ctx.rdx, bytes)?;
+    copy_to_user(current_process, ctx.r8, path.as_bytes())
+}
+
+pub fn create_mail_box(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let mut pid = PROCESS_LIST.lock().processes[current_process].pid;
+    let name = copy_string_from_user(current_process, ctx.rcx, ctx.rdx)?;
 
     ipc::create_mail_box(pid, name);
+    Ok(())
 }
 
-pub fn delete_mail_box(current_process: usize, ctx: Context) {
+pub fn delete_mail_box(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
     let mut pid = PROCESS_LIST.lock().processes[current_process].pid;
-    let name = unsafe {
-        let ptr = ctx.rcx;
-        let len = ctx.rdx;
-        core::str::from_raw_parts(ptr as *const u8, len as usize)
-    };
-    let name = String::from(name);
+    let name = copy_string_from_user(current_process, ctx.rcx, ctx.rdx)?;
 
     ipc::delete_mail_box(pid, name);
+    Ok(())
 }
 
-pub fn send_message(current_process: usize, ctx: Context) {
+pub fn send_message(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
     let mut pid = PROCESS_LIST.lock().processes[current_process].pid;
-    let name = unsafe {
-        let ptr = ctx.rcx;
-        let len = ctx.rdx;
-        core::str::from_raw_parts(ptr as *const u8, len as usize)
-    };
-    let name = String::from(name);
+    let name = copy_string_from_user(current_process, ctx.rcx, ctx.rdx)?;
+    let data = copy_from_user(current_process, ctx.r8, ctx.r9)?;
 
-    let data = unsafe {
-        let ptr = ctx.r8;
-        let len = ctx.r9;
-        core::slice::from_raw_parts(ptr as *const u8, len as usize)
-    };
+    ipc::send(pid, &name, &data);
+    Ok(())
+}
+
+pub fn try_receive_message(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let name = copy_string_from_user(current_process, ctx.rcx, ctx.rdx)?;
+    // Checked before receiving, so that the message is not lost
+    check_user_range(current_process, ctx.r8, ctx.r9, true)?;
 
-    ipc::send(pid, &name, data);
+    let result = ipc::try_receive(&name, ctx.r9 as usize);
+    return_message(current_process, &ctx, result)
 }
 
-// TODO figure out buffer size checking
-pub fn try_receive_message(current_process: usize, ctx: Context) {
-    let mut pid = PROCESS_LIST.lock().processes[current_process].pid;
-    let name = unsafe {
-        let ptr = ctx.rcx;
-        let len = ctx.rdx;
-        core::str::from_raw_parts(ptr as *const u8, len as usize)
+pub fn receive_message(current_process: usize, ctx: Context) -> Result<(), SyscallError> {
+    let name = copy_string_from_user(current_process, ctx.rcx, ctx.rdx)?;
+    check_user_range(current_process, ctx.r8, ctx.r9, true)?;
+
+    // Absolute deadline in milliseconds since startup, u64::MAX for none
+    let deadline = match ctx.r10 {
+        u64::MAX => None,
+        deadline => Some(deadline),
     };
-    let name = String::from(name);
 
-    if let Ok(msg) = ipc::try_receive(&name) {
-        if let Some(msg) = msg {
+    match ipc::try_receive(&name, ctx.r9 as usize) {
+        Err(ipc::ReceiveError::Empty) => {
+            if deadline.is_some_and(|d| d <= *MILLISECONDS_SINCE_STARTUP.lock()) {
+                return_message(current_process, &ctx, Err(ipc::ReceiveError::TimedOut))
+            } else {
+                // The syscall is run again when a message arrives or the deadline passes
+                PROCESS_LIST.lock().processes[current_process].state =
+                    ProcessState::Blocked(WaitEvent::MailBox(name), deadline);
+                Ok(())
+            }
+        }
+        result => return_message(current_process, &ctx, result),
+    }
+}
+
+// Copies a received message to the buffer in r8. The sender pid is returned in r8, the length of
+// the message in r9 and the status in r10
+fn return_message(
+    current_process: usize,
+    ctx: &Context,
+    result: Result<ipc::Message, ipc::ReceiveError>,
+) -> Result<(), SyscallError> {
+    match result {
+        Ok(msg) => {
+            copy_to_user(current_process, ctx.r8, &msg.data)?;
+
+            PROCESS_LIST.lock().processes[current_process].context.r8 = msg.from as u64;
+            PROCESS_LIST.lock().processes[current_process].context.r9 = msg.data.len() as u64;
             PROCESS_LIST.lock().processes[current_process].context.r10 = 0;
-            let dest = unsafe {
-                let ptr = ctx.r8;
-                let len = msg.data.len();
-                core::slice::from_raw_parts_mut(ptr as *mut u8, len as usize)
-            };
-            dest.copy_from_slice(&msg.data);
-        } else {
-            PROCESS_LIST.lock().processes[current_process].context.r10 = 1;
         }
-    } else {
-        PROCESS_LIST.lock().processes[current_process].context.r10 = 1;
+        Err(e) => {
+            if let ipc::ReceiveError::BufferTooSmall(len) = e {
+                PROCESS_LIST.lock().processes[current_process].context.r9 = len as u64;
+            }
+            PROCESS_LIST.lock().processes[current_process].context.r10 = e.code();
+        }
     }
+    Ok(())
 }
@@ -2,7 +2,96 @@
 
 use super::InterruptStackFrame;
 use super::{isr::*, println};
+use crate::fs::FsError;
 use crate::process::*;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// Returned in rax by every syscall, which is set to 0 on success
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyscallError {
+    UnknownSyscall = 1,
+    InvalidPointer,
+    InvalidArgument,
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    NoSpace,
+    Io,
+    BufferTooSmall,
+    BadFileDescriptor,
+    ReadOnly,
+    NotEmpty,
+    // Never returned to userspace. The process is blocked until the drive completes a read, and
+    // then the syscall is run again
+    WouldBlock,
+}
+
+impl From<FsError> for SyscallError {
+    fn from(e: FsError) -> SyscallError {
+        match e {
+            FsError::NotFound => SyscallError::NotFound,
+            FsError::AlreadyExists => SyscallError::AlreadyExists,
+            FsError::NotADirectory => SyscallError::NotADirectory,
+            FsError::IsADirectory => SyscallError::IsADirectory,
+            FsError::InvalidName => SyscallError::InvalidArgument,
+            FsError::NoSpace => SyscallError::NoSpace,
+            FsError::ReadOnly => SyscallError::ReadOnly,
+            FsError::Io => SyscallError::Io,
+            FsError::NotEmpty => SyscallError::NotEmpty,
+            FsError::WouldBlock => SyscallError::WouldBlock,
+        }
+    }
+}
+
+// Fails unless the calling process can access every byte in [ptr, ptr + len), according to its
+// page tables. Syscalls run in the address space of the caller, so after the check the range can
+// be accessed directly
+pub fn check_user_range(
+    current_process: usize,
+    ptr: u64,
+    len: u64,
+    writable: bool,
+) -> Result<(), SyscallError> {
+    if PROCESS_LIST.lock().processes[current_process]
+        .address_space
+        .is_user_range(ptr, len, writable)
+    {
+        Ok(())
+    } else {
+        Err(SyscallError::InvalidPointer)
+    }
+}
+
+pub fn copy_from_user(current_process: usize, ptr: u64, len: u64) -> Result<Vec<u8>, SyscallError> {
+    check_user_range(current_process, ptr, len, false)?;
+
+    let mut out = vec![0u8; len as usize];
+    unsafe {
+        core::ptr::copy_nonoverlapping(ptr as *const u8, out.as_mut_ptr(), len as usize);
+    }
+    Ok(out)
+}
+
+pub fn copy_to_user(current_process: usize, ptr: u64, data: &[u8]) -> Result<(), SyscallError> {
+    check_user_range(current_process, ptr, data.len() as u64, true)?;
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(data.as_ptr(), ptr as *mut u8, data.len());
+    }
+    Ok(())
+}
+
+pub fn copy_string_from_user(
+    current_process: usize,
+    ptr: u64,
+    len: u64,
+) -> Result<String, SyscallError> {
+    String::from_utf8(copy_from_user(current_process, ptr, len)?)
+        .map_err(|_| SyscallError::InvalidArgument)
+}
 
 #[inline(always)]
 pub fn enter_syscall(stack_frame: InterruptStackFrame) -> (usize, Context) {
@@ -18,23 +107,54 @@ pub fn enter_syscall(stack_frame: InterruptStackFrame) -> (usize, Context) {
 }
 
 #[inline(always)]
-pub fn exit_syscall() {
+pub fn exit_syscall(result: Result<(), SyscallError>) -> ! {
+    // The syscall may have blocked or killed the calling process, or asked to wait for the drive. A
+    // blocked process keeps rax, since the syscall will be run again
     let current_process = PROCESS_LIST.lock().current_process;
-    PROCESS_LIST.lock().processes[current_process].reenter();
+    let state = PROCESS_LIST
+        .lock()
+        .processes
+        .get(current_process)
+        .map(|p| p.state.clone());
+    if state == Some(ProcessState::Running) {
+        if result == Err(SyscallError::WouldBlock) {
+            PROCESS_LIST.lock().processes[current_process].state =
+                ProcessState::Blocked(WaitEvent::Disk, None);
+            schedule();
+        }
+        PROCESS_LIST.lock().processes[current_process].context.rax = match result {
+            Ok(()) => 0,
+            Err(e) => e as u64,
+        };
+        PROCESS_LIST.lock().processes[current_process].reenter();
+    }
+    schedule();
 }
 
 pub extern "x86-interrupt" fn syscall_handler(stack_frame: InterruptStackFrame) {
     let (mut current_process, ctx) = enter_syscall(stack_frame);
 
-    match ctx.rax {
-        0x10 => print(ctx),
-        0x11 => put_screen_buffer(ctx),
+    let result = match ctx.rax {
+        0x10 => print(current_process, ctx),
+        0x11 => put_screen_buffer(current_process, ctx),
         0x12 => get_screen_size(current_process, ctx),
 
         0x20 => get_key(current_process, ctx),
         0x21 => get_mouse(current_process, ctx),
 
-        0x30 => load_file(current_process, ctx),
+        0x31 => create_file(current_process, ctx),
+        0x32 => write_file(current_process, ctx),
+        0x33 => truncate_file(current_process, ctx),
+        0x34 => delete_file(current_process, ctx),
+        0x35 => read_dir(current_process, ctx),
+        0x36 => stat(current_process, ctx),
+        0x37 => open(current_process, ctx),
+        0x38 => read(current_process, ctx),
+        0x39 => write(current_process, ctx),
+        0x3a => seek(current_process, ctx),
+        0x3b => close(current_process, ctx),
+        0x3c => create_dir(current_process, ctx),
+        0x3d => cache_stats(current_process, ctx),
 
         0x40 => alloc_pages(current_process, ctx),
         0x41 => get_shared_page(current_process, ctx), // TODO remove
@@ -42,14 +162,19 @@ pub extern "x86-interrupt" fn syscall_handler(stack_frame: InterruptStackFrame)
         0x43 => delete_mail_box(current_process, ctx),
         0x44 => send_message(current_process, ctx),
         0x45 => try_receive_message(current_process, ctx),
+        0x46 => receive_message(current_process, ctx),
 
         0x50 => get_milliseconds_since_startup(current_process, ctx),
+        0x51 => sleep(current_process, ctx),
 
         0x60 => exec(current_process, ctx),
         0x61 => exit(current_process, ctx),
         0x62 => kill(current_process, ctx),
-        _ => panic!("Call to unknown syscall"),
-    }
+        0x63 => list_processes(current_process, ctx),
+        0x64 => process_info(current_process, ctx),
+        0x65 => waitpid(current_process, ctx),
+        _ => Err(SyscallError::UnknownSyscall),
+    };
 
-    exit_syscall();
+    exit_syscall(result);
 }
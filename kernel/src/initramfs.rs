@@ -0,0 +1,117 @@
+#![allow(unused)]
+
+use crate::fs::*;
+use crate::memory::MEMORY_MANAGER;
+use crate::ramfs::RamFs;
+use crate::vfs::VFS;
+use alloc::boxed::Box;
+
+// Path of the archive on the EFI partition
+pub const INITRAMFS_PATH: &str = "\\INITRD.TAR";
+// Maximum total size of the unpacked files
+const CAPACITY: u64 = 8 * 1024 * 1024;
+const BLOCK_SIZE: usize = 512;
+
+// Type flags of ustar headers. Other types, like links, are skipped
+const TYPE_FILE: u8 = b'0';
+const TYPE_FILE_OLD: u8 = 0;
+const TYPE_DIRECTORY: u8 = b'5';
+
+// Unpacks the ustar archive in a new ramfs mounted at path
+pub fn init(archive: &[u8], path: &str) -> Result<(), &'static str> {
+    let mut fs = RamFs::new(CAPACITY);
+
+    let mut offset = 0;
+    while offset + BLOCK_SIZE <= archive.len() {
+        let header = &archive[offset..offset + BLOCK_SIZE];
+        // The archive ends with zeroed blocks
+        if header[0] == 0 {
+            break;
+        }
+        if &header[257..262] != b"ustar" {
+            return Err("Not a ustar archive");
+        }
+
+        let size = parse_octal(&header[124..136]).ok_or("Invalid file size")? as usize;
+        let data = archive
+            .get(offset + BLOCK_SIZE..offset + BLOCK_SIZE + size)
+            .ok_or("Truncated archive")?;
+
+        // Names longer than 100 bytes are split between the prefix and the name fields
+        let prefix = field_str(&header[345..500])?;
+        let name = field_str(&header[0..100])?;
+        match header[156] {
+            TYPE_FILE | TYPE_FILE_OLD => {
+                let file = create_path(&mut fs, prefix, name, false)?;
+                fs.write(file, 0, data).map_err(|_| "Archive too large")?;
+            }
+            TYPE_DIRECTORY => {
+                create_path(&mut fs, prefix, name, true)?;
+            }
+            _ => {}
+        }
+
+        offset += BLOCK_SIZE + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+    }
+
+    VFS.lock()
+        .mount(path, Box::new(fs))
+        .map_err(|_| "Mount point already in use")
+}
+
+// Gives the pages of the archive back to the frame allocator, once it has been unpacked
+pub fn free(archive: &[u8]) {
+    let start = archive.as_ptr() as u64;
+    for page in 0..(archive.len() as u64).div_ceil(0x1000) {
+        MEMORY_MANAGER
+            .lock()
+            .physical_map
+            .dealloc_frame(start + page * 0x1000);
+    }
+}
+
+// Creates every missing directory along prefix/name, and then the last component, which is a
+// directory if directory is set. Existing entries are reused
+fn create_path(
+    fs: &mut RamFs,
+    prefix: &str,
+    name: &str,
+    directory: bool,
+) -> Result<Inode, &'static str> {
+    let mut components = prefix
+        .split('/')
+        .chain(name.split('/'))
+        .filter(|c| !c.is_empty() && *c != ".")
+        .peekable();
+
+    let mut inode = fs.root();
+    while let Some(component) = components.next() {
+        let last = components.peek().is_none();
+        inode = match fs.lookup(inode, component) {
+            Ok(inode) => Ok(inode),
+            Err(FsError::NotFound) if last && !directory => fs.create(inode, component),
+            Err(FsError::NotFound) => fs.create_dir(inode, component),
+            Err(e) => Err(e),
+        }
+        .map_err(|_| "Invalid path in archive")?;
+    }
+    Ok(inode)
+}
+
+// Fields are null terminated, unless they fill the whole field
+fn field_str(field: &[u8]) -> Result<&str, &'static str> {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).map_err(|_| "Invalid name in archive")
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for b in field {
+        match b {
+            b'0'..=b'7' => value = value.checked_mul(8)? + (b - b'0') as u64,
+            0 | b' ' => {}
+            _ => return None,
+        }
+    }
+    Some(value)
+}
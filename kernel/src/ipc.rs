@@ -1,6 +1,7 @@
 #![allow(unused)]
 
 use super::*;
+use crate::process::{WaitEvent, PROCESS_LIST};
 use alloc::boxed::*;
 use alloc::collections::VecDeque;
 use alloc::vec::*;
@@ -20,6 +21,26 @@ pub struct Message {
     pub data: Box<[u8]>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReceiveError {
+    Empty,
+    NoMailBox,
+    TimedOut,
+    BufferTooSmall(usize), // Length of the message
+}
+
+impl ReceiveError {
+    // Status code returned by the receive syscalls. 0 means success
+    pub fn code(&self) -> u64 {
+        match self {
+            ReceiveError::Empty => 1,
+            ReceiveError::NoMailBox => 2,
+            ReceiveError::TimedOut => 3,
+            ReceiveError::BufferTooSmall(_) => 4,
+        }
+    }
+}
+
 pub fn create_mail_box(owner: u32, name: String) -> Result<(), ()> {
     for mb in MAIL_BOXES.lock().iter_mut() {
         if mb.name == *name {
@@ -40,6 +61,10 @@ pub fn delete_mail_box(caller: u32, name: String) {
     for (i, mb) in mail_boxes.iter_mut().enumerate() {
         if mb.name == *name && mb.owner == caller {
             mail_boxes.remove(i);
+            drop(mail_boxes);
+
+            // Let blocked receivers find out that the mail box is gone
+            PROCESS_LIST.lock().wake(&WaitEvent::MailBox(name));
             return;
         }
     }
@@ -54,30 +79,30 @@ pub fn send(from: u32, mail_box: &String, data: &[u8]) -> Result<(), ()> {
             kdata.copy_from_slice(data);
 
             mb.queue.push_back(Message { from, data: kdata });
+            PROCESS_LIST
+                .lock()
+                .wake(&WaitEvent::MailBox(mail_box.clone()));
             return Ok(());
         }
     }
     return Err(());
 }
 
-pub fn try_receive(mail_box: &String) -> Result<Option<Message>, ()> {
+// Pops the first message of the mail box, unless it is longer than capacity, in which case it is
+// left in the queue
+pub fn try_receive(mail_box: &String, capacity: usize) -> Result<Message, ReceiveError> {
     for mb in MAIL_BOXES.lock().iter_mut() {
         if mb.name == *mail_box {
-            return Ok(mb.queue.pop_front());
-        }
-    }
-    return Err(());
-}
+            let len = match mb.queue.front() {
+                Some(msg) => msg.data.len(),
+                None => return Err(ReceiveError::Empty),
+            };
 
-/*pub fn receive(mail_box: &String) -> Result<Message, ()> {
-    loop {
-        let t = try_receive(&mail_box);
-        if let Ok(msg) = t {
-            if let Some(msg) = msg {
-                return Ok(msg);
+            if len > capacity {
+                return Err(ReceiveError::BufferTooSmall(len));
             }
-        } else {
-            return Err(());
+            return Ok(mb.queue.pop_front().unwrap());
         }
     }
-}*/
+    return Err(ReceiveError::NoMailBox);
+}
@@ -7,33 +7,43 @@
 extern crate alloc;
 use alloc::string::*;
 
+mod ahci;
 mod ata;
+mod block_cache;
 mod drive;
 mod elf;
+mod exfat;
+mod ext2;
 mod fat32;
 mod fs;
 mod gdt;
 mod idt;
+mod initramfs;
 mod ipc;
 mod memory;
 mod mouse;
+mod partition;
+mod pci;
 mod pic8259;
 mod pit;
 mod process;
+mod ramfs;
 mod stdin;
 mod stdout;
 mod uefi;
 mod utils;
+mod vfs;
+mod virtio_blk;
 
 use crate::memory::*;
 use core::arch::asm;
 use core::ffi::c_void;
 use elf::ElfExecutable;
-use fat32::*;
 use fs::*;
 use process::*;
 use spin::mutex::Mutex;
 use uefi::SystemTable;
+use vfs::*;
 
 use crate::uefi::exit_boot_services;
 
@@ -56,6 +66,12 @@ extern "efiapi" fn efi_main(image_handle: *const c_void, system_table: *const Sy
     stdout::init(system_table, None).expect("Failed to initialize console");
     println!("Console setup\t\t\t\t[ \\gSUCCESS\\w ]");
 
+    // Load the boot archive from the EFI partition, while boot services are still available
+    let initramfs = uefi::load_file(system_table, image_handle, initramfs::INITRAMFS_PATH).ok();
+    if initramfs.is_some() {
+        println!("Initramfs loaded\t\t\t\t[ \\gSUCCESS\\w ]");
+    }
+
     // Get memory map
     let memory_map_key = memory::init_physical(system_table).expect("Failed to get memory map");
     println!("Got memory map\t\t\t\t[ \\gSUCCESS\\w ]");
@@ -89,29 +105,49 @@ extern "efiapi" fn efi_main(image_handle: *const c_void, system_table: *const Sy
     pic8259::init().expect("Failed to initialize PIC");
     println!("PIC setup\t\t\t\t\t[ \\gSUCCESS\\w ]");
 
-    // Identify ATA drive
-    ata::init().expect("Failed to identify primary master drive");
-    println!("ATA drive identified\t\t[ \\gSUCCESS\\w ]");
+    // Enumerate PCI devices
+    pci::init().expect("Failed to enumerate PCI devices");
+    println!("PCI setup\t\t\t\t\t[ \\gSUCCESS\\w ]");
+
+    match initramfs {
+        // The disk is optional when booting from the initramfs, and is mounted at /disk
+        Some(archive) => {
+            initramfs::init(archive, "/").expect("Failed to unpack initramfs");
+            initramfs::free(archive);
+            println!("Initramfs setup\t\t\t\t[ \\gSUCCESS\\w ]");
+
+            if mount_disk("/disk").is_ok() {
+                println!("FAT32 setup\t\t\t\t\t[ \\gSUCCESS\\w ]");
+            }
+        }
+        None => {
+            mount_disk("/").expect("Failed to initialize FAT32 file system");
+            println!("FAT32 setup\t\t\t\t\t[ \\gSUCCESS\\w ]");
+        }
+    }
 
-    // Initialize FAT32 file system
-    fat32::init().expect("Failed to initialize FAT32 file system");
-    println!("FAT32 setup\t\t\t\t\t[ \\gSUCCESS\\w ]");
+    // Mount ramfs at /tmp
+    ramfs::init().expect("Failed to mount ramfs");
+    println!("Ramfs setup\t\t\t\t\t[ \\gSUCCESS\\w ]");
 
     // Initialize mouse
     mouse::init().expect("Failed to initialize mouse");
     println!("Mouse setup\t\t\t\t\t[ \\gSUCCESS\\w ]");
 
     // Start userspace program
-    let desktop = FAT32
-        .lock()
-        .as_ref()
-        .unwrap()
-        .read_file("USER/USER1")
-        .unwrap();
+    let desktop = VFS.lock().read_file("USER/USER1", &mut None).unwrap();
     let desktop = ElfExecutable::new(desktop);
-    PROCESS_LIST
-        .lock()
-        .push_process(desktop.load_all(), desktop.get_entry());
+    let mut address_space = AddressSpace::new();
+    let mappings = desktop
+        .load_all(&mut address_space)
+        .expect("Failed to load USER/USER1");
+    PROCESS_LIST.lock().push_process(
+        String::from("USER/USER1"),
+        None,
+        address_space,
+        mappings,
+        desktop.get_entry(),
+    );
 
     println!("Elf files loaded");
 
@@ -127,3 +163,54 @@ extern "efiapi" fn efi_main(image_handle: *const c_void, system_table: *const Sy
 
     utils::halt();
 }
+
+// Mounts the FAT32 file system of the first disk found at path. Virtio-blk devices are tried first,
+// then the legacy ATA bus, then the SATA ports of an AHCI controller
+fn mount_disk(path: &str) -> Result<(), &'static str> {
+    if let Some(drive) = virtio_blk::init()
+        .ok()
+        .and_then(|drives| drives.into_iter().next())
+    {
+        println!("Virtio-blk drive identified\t[ \\gSUCCESS\\w ]");
+        return mount_partitions(drive, path);
+    }
+
+    if let Ok(drive) = ata::init() {
+        println!("ATA drive identified\t\t[ \\gSUCCESS\\w ]");
+        if drive.controller().uses_dma() {
+            println!("ATA DMA setup\t\t\t\t[ \\gSUCCESS\\w ]");
+        }
+        return mount_partitions(drive, path);
+    }
+
+    let drive = ahci::init()?
+        .into_iter()
+        .next()
+        .ok_or("No SATA drive on the AHCI controller")?;
+    println!("AHCI drive identified\t\t[ \\gSUCCESS\\w ]");
+    mount_partitions(drive, path)
+}
+
+// Mounts the first FAT or exFAT partition of the drive at path, and the first ext2 partition at
+// /ext2. A drive that is not partitioned is a single partition
+fn mount_partitions<D: drive::Drive + Send + Sync + 'static>(
+    drive: D,
+    path: &str,
+) -> Result<(), &'static str> {
+    let mut fat = None;
+    let mut ext2 = false;
+    for partition in partition::partitions(drive) {
+        if fat.is_none() && fat32::is_fat(&partition) {
+            fat = Some(fat32::init(partition, path));
+        } else if fat.is_none() && exfat::is_exfat(&partition) {
+            fat = Some(exfat::init(partition, path));
+        } else if !ext2 && ext2::is_ext2(&partition) {
+            ext2 = ext2::init(partition, "/ext2").is_ok();
+            if ext2 {
+                println!("Ext2 setup\t\t\t\t\t[ \\gSUCCESS\\w ]");
+            }
+        }
+    }
+    fat.ok_or("No FAT volume on the drive")?
+        .map_err(|_| "Mount point already in use")
+}
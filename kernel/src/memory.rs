@@ -4,6 +4,7 @@ pub mod heap;
 mod paging;
 
 use super::{println, Mutex};
+use crate::alloc::vec;
 use crate::alloc::vec::*;
 use crate::stdout::STDOUT;
 use crate::uefi::*;
@@ -12,6 +13,9 @@ use core::arch::asm;
 use paging::*;
 
 const KERNEL_BASE: u64 = 0x3333_0000_0000;
+// End of the virtual memory handed out by KERNEL_VALLOCATOR
+const KERNEL_END: u64 = 0x4000_0000_0000;
+const CANONICAL_LOWER_HALF_END: u64 = 0x0000_8000_0000_0000;
 pub static MEMORY_MANAGER: Mutex<MemoryManager> = Mutex::new(MemoryManager::new());
 pub static KERNEL_VALLOCATOR: Mutex<VirtualAllocator> =
     Mutex::new(VirtualAllocator::new(KERNEL_BASE));
@@ -74,13 +78,118 @@ pub fn init_virtual(system_table: *const SystemTable) -> Result<(), Status> {
     // Unamp 0th page
     plm4.unmap(0);
 
+    MEMORY_MANAGER.lock().kernel_plm4 = plm4 as *const PageTable as u64;
     MEMORY_MANAGER.lock().set_plm4(plm4);
     Ok(())
 }
 
+// Runs f with the kernel plm4 loaded, then loads the previous plm4 again. Frames are written
+// through the identity map, which user mappings of the current address space may shadow
+pub fn with_kernel_plm4<T>(f: impl FnOnce() -> T) -> T {
+    let previous = MEMORY_MANAGER.lock().get_plm4();
+    let kernel_plm4 = MEMORY_MANAGER.lock().get_kernel_plm4();
+    MEMORY_MANAGER.lock().set_plm4(kernel_plm4);
+    let out = f();
+    MEMORY_MANAGER.lock().set_plm4(previous);
+    out
+}
+
+// Checks that [vaddr, vaddr + len) is in the lower half and does not overlap memory that the kernel
+// uses at the same address in every address space: its heap, the pages of KERNEL_VALLOCATOR, the
+// frame buffer and the identity mapped memory that is not handed out as frames, like the code and
+// stack of the kernel
+pub fn can_map_user(vaddr: u64, len: u64) -> bool {
+    let end = match vaddr.checked_add(len) {
+        Some(end) if end <= CANONICAL_LOWER_HALF_END => end,
+        _ => return false,
+    };
+    let overlaps = |start: u64, size: u64| vaddr < start + size && start < end;
+
+    let (fb_base, fb_size) = {
+        let s = STDOUT.lock();
+        let fb = &s.frame_buffer;
+        (fb.base, fb.pixels_per_scanline * fb.height * 4)
+    };
+    !overlaps(heap::KERNEL_HEAP, heap::KERNEL_HEAP_PAGE_COUNT * 0x1000)
+        && !overlaps(KERNEL_BASE, KERNEL_END - KERNEL_BASE)
+        && !overlaps(fb_base, fb_size)
+        && !MEMORY_MANAGER
+            .lock()
+            .physical_map
+            .overlaps_reserved(vaddr, end)
+}
+
+// Identity maps the device registers in [start, start + size) in the kernel address space, with
+// caching disabled. They are usually not part of the UEFI memory map
+pub fn map_mmio(start: u64, size: u64) {
+    let plm4 = MEMORY_MANAGER.lock().get_kernel_plm4();
+    for page in ((start & !0xfff)..start + size).step_by(0x1000) {
+        let pte = plm4.map(page, page, 3);
+        pte.set_flag(FlagsOffset::DisableCache, true);
+        unsafe { asm!("invlpg [{}]", in(reg) page) };
+    }
+}
+
+// Allocates a zeroed frame for a device, below 4 GiB if below_4g is set, for devices that only
+// take 32 bit addresses. Frames are identity mapped, so their addresses can be given as they are
+pub fn alloc_dma_frame(below_4g: bool) -> Result<u64, &'static str> {
+    let frame = MEMORY_MANAGER.lock().physical_map.alloc_frame();
+    if below_4g && frame + 0x1000 > 1 << 32 {
+        return Err("Frame above 4 GiB");
+    }
+    clear_page(frame);
+    Ok(frame)
+}
+
+pub const DMA_FRAMES: usize = 16;
+
+// Frames that devices transfer sectors to and from, in place of buffers of the kernel, which are
+// not physically contiguous
+pub struct DmaBuffer {
+    frames: [u64; DMA_FRAMES],
+}
+
+impl DmaBuffer {
+    pub const SIZE: usize = DMA_FRAMES * 0x1000;
+
+    pub fn new(below_4g: bool) -> Result<DmaBuffer, &'static str> {
+        let mut frames = [0; DMA_FRAMES];
+        for frame in frames.iter_mut() {
+            *frame = alloc_dma_frame(below_4g)?;
+        }
+        Ok(DmaBuffer { frames })
+    }
+
+    // Returns the address and the length of every frame that holds the first len bytes
+    pub fn regions(&self, len: usize) -> impl Iterator<Item = (u64, usize)> + '_ {
+        self.frames
+            .iter()
+            .enumerate()
+            .take_while(move |(i, _)| i * 0x1000 < len)
+            .map(move |(i, frame)| (*frame, usize::min(len - i * 0x1000, 0x1000)))
+    }
+
+    // Copies len bytes from data into the frames
+    pub fn copy_from(&self, data: *const u8, len: usize) {
+        for (i, (frame, size)) in self.regions(len).enumerate() {
+            unsafe { core::ptr::copy_nonoverlapping(data.add(i * 0x1000), frame as *mut u8, size) };
+        }
+    }
+
+    // Copies the first len bytes of the frames to data
+    pub fn copy_to(&self, data: *mut u8, len: usize) {
+        for (i, (frame, size)) in self.regions(len).enumerate() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(frame as *const u8, data.add(i * 0x1000), size)
+            };
+        }
+    }
+}
+
 pub struct MemoryManager {
     pub physical_map: PhysicalMemoryMap,
     pub kernel_alloc_count: u64,
+    kernel_plm4: u64,
 }
 
 impl MemoryManager {
@@ -88,9 +197,15 @@ impl MemoryManager {
         MemoryManager {
             physical_map: PhysicalMemoryMap::new(),
             kernel_alloc_count: 0,
+            kernel_plm4: 0,
         }
     }
 
+    // Returns the plm4 built by init_virtual, whose mappings are shared by every address space
+    pub fn get_kernel_plm4(&self) -> &'static mut PageTable {
+        unsafe { &mut *(self.kernel_plm4 as *mut PageTable) }
+    }
+
     pub fn get_plm4(&self) -> &'static mut PageTable {
         let out: u64;
         unsafe {
@@ -212,30 +327,87 @@ impl PhysicalMemoryMap {
     }
 
     pub fn alloc_frame(&mut self) -> u64 {
+        match self.try_alloc_frame() {
+            Some(frame) => frame,
+            None => panic!("No more usable memory"),
+        }
+    }
+
+    // Returns None instead of panicking when there are no free frames left
+    pub fn try_alloc_frame(&mut self) -> Option<u64> {
         let out = self.head as u64;
         if out == 0 {
-            panic!("No more usable memory");
+            return None;
         }
         self.head = unsafe { (*(self.head as *const PhysicalMemoryLinkedList)).next } as u64;
         self.available_pages -= 1;
-        out
+        Some(out)
     }
 
-    pub fn dealloc_frame(&mut self, vaddr: u64) {
+    pub fn available_pages(&self) -> u64 {
+        self.available_pages
+    }
+
+    // Checks whether [start, end) overlaps memory of the UEFI map that is not handed out as frames
+    fn overlaps_reserved(&self, start: u64, end: u64) -> bool {
+        let descriptor_count = self.mm_size / self.descriptor_size;
+        (0..descriptor_count).any(|i| {
+            let descriptor = unsafe {
+                &*((&self.map as *const MemoryDescriptor as u64
+                    + i as u64 * self.descriptor_size as u64)
+                    as *const MemoryDescriptor)
+            };
+            let descriptor_end = descriptor.physical_start + descriptor.number_of_pages * 0x1000;
+            descriptor.t != MemoryType::ConventionalMemory
+                && descriptor.physical_start < end
+                && start < descriptor_end
+        })
+    }
+
+    // Allocates count physically contiguous frames and returns the lowest one. Frames taken from the
+    // free list while looking for a long enough run are given back
+    pub fn alloc_contiguous_frames(&mut self, count: u64) -> u64 {
+        let mut skipped = 0 as *const PhysicalMemoryLinkedList;
+        let mut start = self.alloc_frame();
+        let mut len = 1;
+        while len < count {
+            // Frames of the same descriptor come out of the free list in descending order
+            let frame = self.alloc_frame();
+            if frame + 0x1000 == start {
+                start = frame;
+                len += 1;
+                continue;
+            }
+
+            for i in 0..len {
+                let page = start + i * 0x1000;
+                unsafe {
+                    *(page as *mut PhysicalMemoryLinkedList) =
+                        PhysicalMemoryLinkedList { next: skipped };
+                }
+                skipped = page as *const PhysicalMemoryLinkedList;
+            }
+            start = frame;
+            len = 1;
+        }
+
+        while !skipped.is_null() {
+            let next = unsafe { (*skipped).next };
+            self.dealloc_frame(skipped as u64);
+            skipped = next;
+        }
+        start
+    }
+
+    // Gives a frame returned by alloc_frame back to the free list
+    pub fn dealloc_frame(&mut self, frame: u64) {
         unsafe {
-            *(vaddr as *mut PhysicalMemoryLinkedList) = PhysicalMemoryLinkedList {
+            *(frame as *mut PhysicalMemoryLinkedList) = PhysicalMemoryLinkedList {
                 next: self.head as *const PhysicalMemoryLinkedList,
             };
         }
 
-        let pte = unsafe {
-            MEMORY_MANAGER
-                .lock()
-                .get_plm4()
-                .get_page_table_entry(vaddr, 3)
-        }
-        .expect("Cannot deallocate unmapped frame");
-        self.head = pte.get_physical_address();
+        self.head = frame;
         self.available_pages += 1;
     }
 }
@@ -250,6 +422,116 @@ impl VirtualMapping {
     pub fn new(vaddr: u64, frames: Vec<u64>) -> VirtualMapping {
         VirtualMapping { vaddr, frames }
     }
+
+    // Gives the frames back to the physical memory map
+    pub fn free_frames(&self) {
+        for frame in &self.frames {
+            MEMORY_MANAGER.lock().physical_map.dealloc_frame(*frame);
+        }
+    }
+
+    // Unmaps a mapping created by KERNEL_VALLOCATOR and frees its frames
+    pub fn free_kernel(self) {
+        let plm4 = MEMORY_MANAGER.lock().get_kernel_plm4();
+        plm4.unmap_mapping(&self);
+        self.free_frames();
+    }
+}
+
+// The address space of a user process. Its plm4 starts as a copy of the kernel plm4, so kernel
+// mappings are shared, while every table that user mappings go through is private to it
+pub struct AddressSpace {
+    plm4: u64,
+    tables: Vec<u64>, // Page table frames owned by this address space, plm4 included
+}
+
+impl AddressSpace {
+    pub fn new() -> AddressSpace {
+        let plm4 = MEMORY_MANAGER.lock().physical_map.alloc_frame();
+        let kernel_plm4 = MEMORY_MANAGER.lock().get_kernel_plm4();
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                kernel_plm4 as *const PageTable,
+                plm4 as *mut PageTable,
+                1,
+            );
+        }
+
+        AddressSpace {
+            plm4,
+            tables: vec![plm4],
+        }
+    }
+
+    pub fn get_plm4(&self) -> &'static mut PageTable {
+        unsafe { &mut *(self.plm4 as *mut PageTable) }
+    }
+
+    pub fn map_mapping_user(&mut self, mapping: &VirtualMapping) {
+        let plm4 = self.get_plm4();
+        plm4.map_mapping_private(mapping, &mut self.tables);
+    }
+
+    pub fn unmap_mapping(&mut self, mapping: &VirtualMapping) {
+        for i in 0..mapping.frames.len() {
+            self.unmap(mapping.vaddr + i as u64 * 0x1000);
+        }
+    }
+
+    pub fn unmap(&mut self, vaddr: u64) {
+        let plm4 = self.get_plm4();
+        plm4.get_private_entry(vaddr, 3, &mut self.tables)
+            .set_flag(FlagsOffset::Present, false);
+    }
+
+    // Loads the address space in cr3, unless it is already the current one
+    pub fn load(&self) {
+        if !self.is_loaded() {
+            MEMORY_MANAGER.lock().set_plm4(self.get_plm4());
+        }
+    }
+
+    // Checks that every page in [vaddr, vaddr + len) is mapped as user accessible, and writable if
+    // required, in this address space
+    pub fn is_user_range(&self, vaddr: u64, len: u64, writable: bool) -> bool {
+        let end = match vaddr.checked_add(len) {
+            Some(end) if end <= CANONICAL_LOWER_HALF_END => end,
+            _ => return false,
+        };
+
+        let plm4 = self.get_plm4();
+        let mut page = vaddr & !0xfff;
+        while page < end {
+            if !plm4.is_user_accessible(page, 3, writable) {
+                return false;
+            }
+            page += 0x1000;
+        }
+        true
+    }
+
+    // Switches back to the kernel plm4 if the address space is the current one
+    pub fn unload(&self) {
+        if self.is_loaded() {
+            let plm4 = MEMORY_MANAGER.lock().get_kernel_plm4();
+            MEMORY_MANAGER.lock().set_plm4(plm4);
+        }
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        MEMORY_MANAGER.lock().get_plm4() as *const PageTable as u64 == self.plm4
+    }
+}
+
+impl Drop for AddressSpace {
+    // Frees every page table created for this address space. Tables shared with the kernel plm4
+    // are never in the list, so they are left untouched
+    fn drop(&mut self) {
+        self.unload();
+        for table in &self.tables {
+            MEMORY_MANAGER.lock().physical_map.dealloc_frame(*table);
+        }
+    }
 }
 
 pub struct VirtualAllocator {
@@ -258,26 +540,55 @@ pub struct VirtualAllocator {
 }
 
 impl VirtualAllocator {
-    const fn new(vaddr: u64) -> VirtualAllocator {
+    pub const fn new(vaddr: u64) -> VirtualAllocator {
         VirtualAllocator {
             vaddr,
             alloc_count: 0,
         }
     }
 
-    pub fn alloc_pages(&mut self, page_count: u64) -> VirtualMapping {
-        let mut out = VirtualMapping::new(
-            self.vaddr + self.alloc_count * 0x1000,
-            Vec::with_capacity(page_count as usize),
-        );
-        for i in 0..page_count {
-            out.frames
-                .push(MEMORY_MANAGER.lock().physical_map.alloc_frame());
-            clear_page(out.frames[i as usize]);
+    // Reserves page_count pages of virtual memory, without backing them with frames
+    pub fn reserve(&mut self, page_count: u64) -> u64 {
+        let out = self.vaddr + self.alloc_count * 0x1000;
+        self.alloc_count += page_count;
+        out
+    }
+
+    // Returns None if physical memory runs out, after giving back the frames taken so far. The
+    // virtual memory is only reserved once every frame is allocated
+    fn alloc_frames(&mut self, page_count: u64) -> Option<VirtualMapping> {
+        let mut frames = Vec::with_capacity(page_count as usize);
+        for _ in 0..page_count {
+            let frame = MEMORY_MANAGER.lock().physical_map.try_alloc_frame();
+            let Some(frame) = frame else {
+                VirtualMapping::new(0, frames).free_frames();
+                return None;
+            };
+            clear_page(frame);
+            frames.push(frame);
         }
-        let plm4 = MEMORY_MANAGER.lock().get_plm4();
+        Some(VirtualMapping::new(self.reserve(page_count), frames))
+    }
+
+    // Allocates pages in the kernel plm4, visible from every address space
+    pub fn alloc_pages(&mut self, page_count: u64) -> VirtualMapping {
+        let Some(out) = self.alloc_frames(page_count) else {
+            panic!("No more usable memory");
+        };
+        let plm4 = MEMORY_MANAGER.lock().get_kernel_plm4();
         plm4.map_mapping(&out);
-        self.alloc_count += page_count;
         out
     }
+
+    // Allocates user accessible pages in the given address space only. Returns None if physical
+    // memory runs out
+    pub fn alloc_user_pages(
+        &mut self,
+        page_count: u64,
+        space: &mut AddressSpace,
+    ) -> Option<VirtualMapping> {
+        let out = self.alloc_frames(page_count)?;
+        space.map_mapping_user(&out);
+        Some(out)
+    }
 }
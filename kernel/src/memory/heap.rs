@@ -7,8 +7,8 @@ use crate::{
 #[global_allocator]
 static HEAP: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
 
-const KERNEL_HEAP_PAGE_COUNT: u64 = 100;
-const KERNEL_HEAP: u64 = 0x1111_0000_0000;
+pub const KERNEL_HEAP_PAGE_COUNT: u64 = 0x1000;
+pub const KERNEL_HEAP: u64 = 0x1111_0000_0000;
 pub fn init() -> Result<(), ()> {
     for i in 0..KERNEL_HEAP_PAGE_COUNT {
         let frame = MEMORY_MANAGER.lock().physical_map.alloc_frame();
@@ -1,4 +1,6 @@
 use super::{clear_page, println, VirtualMapping, MEMORY_MANAGER};
+use alloc::vec::Vec;
+use core::arch::asm;
 
 const PAGING_BASE: u64 = 0x2222_0000_0000;
 
@@ -100,11 +102,93 @@ impl PageTable {
         }
     }
 
+    // Checks that vaddr is mapped and that every level of the walk allows user access, and writes if
+    // requested. Huge pages end the walk early
+    pub fn is_user_accessible(&self, vaddr: u64, depth: u32, writable: bool) -> bool {
+        let entry = &self.0[(((vaddr >> 12) >> (9 * depth)) & 0x1ff) as usize];
+
+        if !entry.get_flag(FlagsOffset::Present)
+            || !entry.get_flag(FlagsOffset::UserAccessible)
+            || (writable && !entry.get_flag(FlagsOffset::Writable))
+        {
+            return false;
+        }
+
+        if depth == 0 || entry.get_flag(FlagsOffset::HugePage) {
+            return true;
+        }
+
+        unsafe {
+            (&*(entry.get_physical_address() as *const PageTable)).is_user_accessible(
+                vaddr,
+                depth - 1,
+                writable,
+            )
+        }
+    }
+
+    // Same as get_page_table_entry, but missing tables are created and every table that is not
+    // listed in owned is copied before descending into it, so that changes made through the
+    // returned entry are only visible through this plm4. New tables are added to owned
+    pub fn get_private_entry(
+        &mut self,
+        vaddr: u64,
+        depth: u32,
+        owned: &mut Vec<u64>,
+    ) -> &mut PageTableEntry {
+        let index = ((vaddr >> 12) >> (9 * depth)) & 0x1ff;
+
+        if depth == 0 {
+            return &mut self.0[index as usize];
+        }
+
+        if !self.0[index as usize].get_flag(FlagsOffset::Present) {
+            let new_table = MEMORY_MANAGER.lock().physical_map.alloc_frame();
+            clear_page(new_table);
+
+            let mut entry = PageTableEntry::new();
+            entry.set_flag(FlagsOffset::Writable, true);
+            entry.set_flag(FlagsOffset::Present, true);
+            entry.set_flag(FlagsOffset::UserAccessible, true);
+            entry.set_physical_address(new_table);
+
+            self.0[index as usize] = entry;
+            owned.push(new_table);
+        } else if !owned.contains(&self.0[index as usize].get_physical_address()) {
+            let new_table = MEMORY_MANAGER.lock().physical_map.alloc_frame();
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    self.0[index as usize].get_physical_address() as *const PageTable,
+                    new_table as *mut PageTable,
+                    1,
+                );
+            }
+
+            self.0[index as usize].set_physical_address(new_table);
+            owned.push(new_table);
+        }
+
+        unsafe {
+            (&mut *(self.0[index as usize].get_physical_address() as *mut PageTable))
+                .get_private_entry(vaddr, depth - 1, owned)
+        }
+    }
+
     pub fn unmap(&mut self, vaddr: u64) {
         let pte = self.get_page_table_entry(vaddr, 3);
         pte.unwrap().set_flag(FlagsOffset::Present, false);
     }
 
+    pub fn unmap_mapping(&mut self, mapping: &VirtualMapping) {
+        for i in 0..mapping.frames.len() {
+            let vaddr = mapping.vaddr + i as u64 * 0x1000;
+            self.unmap(vaddr);
+            unsafe {
+                asm!("invlpg [{}]", in(reg) vaddr);
+            }
+        }
+    }
+
     pub fn map_mapping(&mut self, mapping: &VirtualMapping) {
         for (i, frame) in mapping.frames.iter().enumerate() {
             self.map(*frame, mapping.vaddr + i as u64 * 0x1000, 3);
@@ -117,6 +201,18 @@ impl PageTable {
             pte.set_flag(FlagsOffset::UserAccessible, true);
         }
     }
+
+    // Maps the mapping as user accessible, without touching tables that are not in owned
+    pub fn map_mapping_private(&mut self, mapping: &VirtualMapping, owned: &mut Vec<u64>) {
+        for (i, frame) in mapping.frames.iter().enumerate() {
+            let pte = self.get_private_entry(mapping.vaddr + i as u64 * 0x1000, 3, owned);
+            *pte = PageTableEntry::new();
+            pte.set_flag(FlagsOffset::Writable, true);
+            pte.set_flag(FlagsOffset::Present, true);
+            pte.set_flag(FlagsOffset::UserAccessible, true);
+            pte.set_physical_address(*frame);
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -0,0 +1,215 @@
+#![allow(unused)]
+
+use crate::drive::*;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+const MBR_SIGNATURE: u16 = 0xaa55;
+const MBR_ENTRIES: usize = 446;
+const MBR_TYPE_PROTECTIVE: u8 = 0xee;
+
+const GPT_HEADER_LBA: u64 = 1;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartitionType {
+    Gpt([u8; 16]),
+    Mbr(u8),
+    // The whole drive, which has no partition table
+    Whole,
+}
+
+// A range of sectors of a drive, which is itself used as a drive. Sectors are relative to the
+// start of the partition, and accesses past its end fail with OutOfRange
+pub struct Partition<D: Drive> {
+    drive: Arc<D>,
+    pub start: u64,
+    pub sectors: u64,
+    pub partition_type: PartitionType,
+}
+
+impl<D: Drive> Partition<D> {
+    fn check_range(&self, lba: u64, sector_count: u64) -> Result<(), DriveError> {
+        if lba
+            .checked_add(sector_count)
+            .is_none_or(|end| end > self.sectors)
+        {
+            return Err(DriveError::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+impl<D: Drive> Drive for Partition<D> {
+    fn read_sectors(&self, lba: u64, sector_count: u64, buffer: *mut u8) -> Result<(), DriveError> {
+        self.check_range(lba, sector_count)?;
+        self.drive
+            .read_sectors(self.start + lba, sector_count, buffer)
+    }
+
+    fn write_sectors(
+        &self,
+        lba: u64,
+        sector_count: u64,
+        buffer: *const u8,
+    ) -> Result<(), DriveError> {
+        self.check_range(lba, sector_count)?;
+        self.drive
+            .write_sectors(self.start + lba, sector_count, buffer)
+    }
+
+    fn flush(&self) -> Result<(), DriveError> {
+        self.drive.flush()
+    }
+}
+
+// Splits the drive in its partitions, read from the GPT, or from the MBR if there is no valid GPT.
+// A drive without a partition table, like a FAT volume formatted on the whole disk, is returned as
+// a single partition. A drive whose first sector can't be read has no partitions
+pub fn partitions<D: Drive>(drive: D) -> Vec<Partition<D>> {
+    let drive = Arc::new(drive);
+    let mut mbr = [0u8; SECTOR_SIZE];
+    if drive.read_sectors(0, 1, mbr.as_mut_ptr()).is_err() {
+        return Vec::new();
+    }
+
+    let ranges = match parse_mbr(&mbr) {
+        Some(entries) if entries.iter().any(|e| e.2 == MBR_TYPE_PROTECTIVE) => {
+            read_gpt(&*drive).unwrap_or_else(|| mbr_ranges(&entries))
+        }
+        Some(entries) => mbr_ranges(&entries),
+        None => Vec::new(),
+    };
+
+    if ranges.is_empty() {
+        return alloc::vec![Partition {
+            drive,
+            start: 0,
+            sectors: u64::MAX,
+            partition_type: PartitionType::Whole,
+        }];
+    }
+    ranges
+        .into_iter()
+        .map(|(start, sectors, partition_type)| Partition {
+            drive: drive.clone(),
+            start,
+            sectors,
+            partition_type,
+        })
+        .collect()
+}
+
+// Returns the start, size and type of the used MBR entries. Boot sectors of volumes without a
+// partition table end with the same signature, so they are told apart by the file system type
+// label of FAT boot sectors, and by invalid status bytes in the entries, which hold boot code
+fn parse_mbr(mbr: &[u8; SECTOR_SIZE]) -> Option<Vec<(u64, u64, u8)>> {
+    if u16::from_le_bytes([mbr[510], mbr[511]]) != MBR_SIGNATURE
+        || &mbr[54..57] == b"FAT"
+        || &mbr[82..87] == b"FAT32"
+    {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    for i in 0..4 {
+        let entry = &mbr[MBR_ENTRIES + i * 16..MBR_ENTRIES + (i + 1) * 16];
+        if entry[0] != 0x00 && entry[0] != 0x80 {
+            return None;
+        }
+        let partition_type = entry[4];
+        let start = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+        let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+        if partition_type != 0 && sectors != 0 {
+            entries.push((start, sectors, partition_type));
+        }
+    }
+    Some(entries)
+}
+
+fn mbr_ranges(entries: &[(u64, u64, u8)]) -> Vec<(u64, u64, PartitionType)> {
+    entries
+        .iter()
+        .filter(|e| e.2 != MBR_TYPE_PROTECTIVE)
+        .map(|(start, sectors, t)| (*start, *sectors, PartitionType::Mbr(*t)))
+        .collect()
+}
+
+// Reads the primary GPT. Returns None if its header or entry array is corrupted or unreadable
+fn read_gpt<D: Drive>(drive: &D) -> Option<Vec<(u64, u64, PartitionType)>> {
+    let mut header = [0u8; SECTOR_SIZE];
+    drive
+        .read_sectors(GPT_HEADER_LBA, 1, header.as_mut_ptr())
+        .ok()?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return None;
+    }
+
+    // The header CRC is computed with its own field zeroed
+    let header_size = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+    let header_crc = u32::from_le_bytes(header[16..20].try_into().unwrap());
+    if !(92..=SECTOR_SIZE).contains(&header_size) {
+        return None;
+    }
+    let mut copy = header;
+    copy[16..20].fill(0);
+    if crc32(&copy[..header_size]) != header_crc {
+        return None;
+    }
+
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let entry_count = u32::from_le_bytes(header[80..84].try_into().unwrap()) as usize;
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    let entries_crc = u32::from_le_bytes(header[88..92].try_into().unwrap());
+    // Entries are 128 bytes times a power of two. Larger ones than a sector are not supported, so
+    // that the array takes at most 1024 sectors
+    if !(128..=SECTOR_SIZE).contains(&entry_size)
+        || !entry_size.is_power_of_two()
+        || entry_count > 1024
+    {
+        return None;
+    }
+
+    let size = entry_count * entry_size;
+    let mut entries = alloc::vec![0u8; size.div_ceil(SECTOR_SIZE) * SECTOR_SIZE];
+    drive
+        .read_sectors(
+            entries_lba,
+            (entries.len() / SECTOR_SIZE) as u64,
+            entries.as_mut_ptr(),
+        )
+        .ok()?;
+    if crc32(&entries[..size]) != entries_crc {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    for entry in entries[..size].chunks(entry_size) {
+        let partition_type: [u8; 16] = entry[0..16].try_into().unwrap();
+        if partition_type == [0; 16] {
+            continue;
+        }
+        let first = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        let last = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        if last >= first {
+            ranges.push((first, last - first + 1, PartitionType::Gpt(partition_type)));
+        }
+    }
+    Some(ranges)
+}
+
+// CRC32 with the reflected 0x04c11db7 polynomial, used by the GPT
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
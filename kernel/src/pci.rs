@@ -0,0 +1,396 @@
+#![allow(unused)]
+
+use super::{print, println, Mutex};
+use crate::utils::{ind, outd};
+use alloc::vec::Vec;
+
+// Configuration mechanism #1 only reaches the first 256 bytes of the configuration space of
+// functions on segment 0. ECAM, described by the ACPI MCFG table, is not supported yet
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+// Offsets in the configuration space
+const VENDOR_ID: u8 = 0x00;
+const COMMAND: u8 = 0x04;
+const CLASS: u8 = 0x08;
+const HEADER_TYPE: u8 = 0x0c;
+const BAR0: u8 = 0x10;
+const SECONDARY_BUS: u8 = 0x18; // Bridges only
+const CAPABILITIES: u8 = 0x34;
+const INTERRUPT: u8 = 0x3c;
+
+const COMMAND_IO_SPACE: u32 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+const STATUS_CAPABILITIES: u32 = 1 << 20; // In the dword of the command register
+
+const HEADER_MULTIFUNCTION: u8 = 1 << 7;
+const HEADER_BRIDGE: u8 = 0x01;
+
+// Every function found at boot
+pub static PCI_DEVICES: Mutex<Vec<PciInfo>> = Mutex::new(Vec::new());
+
+// Scans the buses reachable from the host bridges, and prints every function found
+pub fn init() -> Result<(), &'static str> {
+    let mut devices = Vec::new();
+    let host = PciDevice {
+        bus: 0,
+        device: 0,
+        function: 0,
+    };
+    if host.vendor_id() == 0xffff {
+        return Err("No PCI host bridge");
+    }
+
+    // Every function of a multifunction host bridge is the host bridge of the bus with its number
+    if host.header_type() & HEADER_MULTIFUNCTION == 0 {
+        scan_bus(0, &mut devices);
+    } else {
+        for function in 0..8 {
+            let host = PciDevice { function, ..host };
+            if host.vendor_id() != 0xffff {
+                scan_bus(function, &mut devices);
+            }
+        }
+    }
+
+    for info in devices.iter() {
+        println!("{}", info);
+    }
+    *PCI_DEVICES.lock() = devices;
+    Ok(())
+}
+
+fn scan_bus(bus: u8, devices: &mut Vec<PciInfo>) {
+    for device in 0..32 {
+        for function in 0..8 {
+            let pci_device = PciDevice {
+                bus,
+                device,
+                function,
+            };
+            if pci_device.vendor_id() == 0xffff {
+                // Functions other than 0 are only present on multifunction devices
+                if function == 0 {
+                    break;
+                }
+                continue;
+            }
+
+            let header_type = pci_device.header_type();
+            devices.push(PciInfo::new(pci_device));
+            if header_type & !HEADER_MULTIFUNCTION == HEADER_BRIDGE {
+                let secondary = (pci_device.read(SECONDARY_BUS) >> 8) as u8;
+                if secondary > bus {
+                    scan_bus(secondary, devices);
+                }
+            }
+            if function == 0 && header_type & HEADER_MULTIFUNCTION == 0 {
+                break;
+            }
+        }
+    }
+}
+
+// A function of a device on a PCI bus, accessed through configuration mechanism #1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciDevice {
+    // Reads the aligned dword at offset in the configuration space
+    pub fn read(&self, offset: u8) -> u32 {
+        outd(CONFIG_ADDRESS, self.address(offset));
+        ind(CONFIG_DATA)
+    }
+
+    pub fn write(&self, offset: u8, value: u32) {
+        outd(CONFIG_ADDRESS, self.address(offset));
+        outd(CONFIG_DATA, value);
+    }
+
+    fn address(&self, offset: u8) -> u32 {
+        1 << 31
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xfc) as u32
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read(VENDOR_ID) as u16
+    }
+
+    pub fn class(&self) -> u8 {
+        (self.read(CLASS) >> 24) as u8
+    }
+
+    pub fn subclass(&self) -> u8 {
+        (self.read(CLASS) >> 16) as u8
+    }
+
+    pub fn prog_if(&self) -> u8 {
+        (self.read(CLASS) >> 8) as u8
+    }
+
+    pub fn header_type(&self) -> u8 {
+        (self.read(HEADER_TYPE) >> 16) as u8
+    }
+
+    pub fn bar(&self, index: u8) -> u32 {
+        self.read(BAR0 + index * 4)
+    }
+
+    // Allows the device to access memory on its own, which is needed for DMA
+    pub fn enable_bus_master(&self) {
+        self.write(COMMAND, self.read(COMMAND) | COMMAND_BUS_MASTER);
+    }
+
+    // Makes the device answer to accesses to its I/O and memory BARs
+    pub fn enable_decoding(&self) {
+        self.write(
+            COMMAND,
+            self.read(COMMAND) | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE,
+        );
+    }
+
+    // Decodes the BARs, and finds their sizes by writing ones to them. Decoding is turned off
+    // meanwhile, so the device never answers at the temporary addresses
+    fn decode_bars(&self, count: u8) -> [Option<Bar>; 6] {
+        let mut bars = [None; 6];
+        let command = self.read(COMMAND);
+        self.write(
+            COMMAND,
+            command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+        );
+
+        let mut index = 0;
+        while index < count {
+            let offset = BAR0 + index * 4;
+            let value = self.read(offset);
+            self.write(offset, u32::MAX);
+            let mask = self.read(offset);
+            self.write(offset, value);
+
+            if value & 1 == 1 {
+                let size = !(mask & !0x3) as u16 as u32 + 1;
+                if mask != 0 {
+                    bars[index as usize] = Some(Bar::Io {
+                        port: (value & !0x3) as u16,
+                        size,
+                    });
+                }
+                index += 1;
+                continue;
+            }
+
+            // 64 bit BARs take two slots, with the high half in the second one
+            let prefetchable = value & (1 << 3) != 0;
+            let mut address = (value & !0xf) as u64;
+            let mut mask = (mask & !0xf) as u64 | 0xffff_ffff_0000_0000;
+            if (value >> 1) & 0x3 == 0x2 && index + 1 < count {
+                let high = self.read(offset + 4);
+                self.write(offset + 4, u32::MAX);
+                let high_mask = self.read(offset + 4);
+                self.write(offset + 4, high);
+                address |= (high as u64) << 32;
+                mask = mask as u32 as u64 | (high_mask as u64) << 32;
+                index += 1;
+            }
+            if mask as u32 != 0 {
+                bars[(offset - BAR0) as usize / 4] = Some(Bar::Memory {
+                    address,
+                    size: !mask + 1,
+                    prefetchable,
+                });
+            }
+            index += 1;
+        }
+
+        self.write(COMMAND, command);
+        bars
+    }
+
+    // Returns the id and offset of every entry of the capability list
+    fn capabilities(&self) -> Vec<(u8, u8)> {
+        let mut capabilities = Vec::new();
+        if self.read(COMMAND) & STATUS_CAPABILITIES == 0 {
+            return capabilities;
+        }
+
+        let mut offset = self.read(CAPABILITIES) as u8 & !0x3;
+        // The list is limited to the configuration space, which also stops malformed loops
+        while offset != 0 && capabilities.len() < 48 {
+            let header = self.read(offset);
+            capabilities.push((header as u8, offset));
+            offset = (header >> 8) as u8 & !0x3;
+        }
+        capabilities
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bar {
+    Io {
+        port: u16,
+        size: u32,
+    },
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+}
+
+// A function found by the scan at boot
+#[derive(Debug, Clone)]
+pub struct PciInfo {
+    pub device: PciDevice,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: u8,
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<(u8, u8)>,
+    pub interrupt_line: u8,
+    // 1 to 4 for INTA# to INTD#, 0 if the function does not use an interrupt pin
+    pub interrupt_pin: u8,
+    // Name of the driver bound to the function
+    pub driver: Option<&'static str>,
+}
+
+impl PciInfo {
+    fn new(device: PciDevice) -> PciInfo {
+        let id = device.read(VENDOR_ID);
+        let class = device.read(CLASS);
+        let header_type = device.header_type();
+        let interrupt = device.read(INTERRUPT);
+        // Bridges only have two BARs, and other header types none
+        let bar_count = match header_type & !HEADER_MULTIFUNCTION {
+            0x00 => 6,
+            HEADER_BRIDGE => 2,
+            _ => 0,
+        };
+
+        PciInfo {
+            device,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            header_type,
+            bars: device.decode_bars(bar_count),
+            capabilities: device.capabilities(),
+            interrupt_line: interrupt as u8,
+            interrupt_pin: (interrupt >> 8) as u8,
+            driver: None,
+        }
+    }
+}
+
+impl core::fmt::Display for PciInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+            self.device.bus,
+            self.device.device,
+            self.device.function,
+            self.vendor_id,
+            self.device_id,
+            self.class,
+            self.subclass,
+            self.prog_if
+        )?;
+        if self.interrupt_pin != 0 {
+            write!(f, " irq {}", self.interrupt_line)?;
+        }
+        if let Some(driver) = self.driver {
+            write!(f, " ({})", driver)?;
+        }
+
+        for (i, bar) in self.bars.iter().enumerate() {
+            match bar {
+                Some(Bar::Io { port, size }) => {
+                    write!(f, "\n    bar {} io 0x{:x} size 0x{:x}", i, port, size)?
+                }
+                Some(Bar::Memory {
+                    address,
+                    size,
+                    prefetchable,
+                }) => {
+                    write!(f, "\n    bar {} mem 0x{:x} size 0x{:x}", i, address, size)?;
+                    if *prefetchable {
+                        write!(f, " prefetchable")?;
+                    }
+                }
+                None => {}
+            }
+        }
+        if !self.capabilities.is_empty() {
+            write!(f, "\n    capabilities")?;
+            for (id, offset) in self.capabilities.iter() {
+                write!(f, " {:02x}@{:02x}", id, offset)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PciMatch {
+    // Vendor and device id
+    Id(u16, u16),
+    // Class and subclass
+    Class(u8, u8),
+}
+
+impl PciMatch {
+    fn matches(&self, info: &PciInfo) -> bool {
+        match *self {
+            PciMatch::Id(vendor, device) => info.vendor_id == vendor && info.device_id == device,
+            PciMatch::Class(class, subclass) => info.class == class && info.subclass == subclass,
+        }
+    }
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [PciMatch],
+    // Called for every unbound function that matches. The function is bound to the driver if it
+    // returns Ok
+    pub probe: fn(&PciInfo) -> Result<(), &'static str>,
+}
+
+// Probes the driver on every matching function found at boot. Returns the number of functions
+// bound to it
+pub fn register_driver(driver: &'static PciDriver) -> usize {
+    // The list is not locked while probing, so drivers can look up other functions
+    let candidates: Vec<PciInfo> = PCI_DEVICES
+        .lock()
+        .iter()
+        .filter(|info| info.driver.is_none() && driver.matches.iter().any(|m| m.matches(info)))
+        .cloned()
+        .collect();
+
+    let mut bound = 0;
+    for info in candidates {
+        if (driver.probe)(&info).is_ok() {
+            if let Some(info) = PCI_DEVICES
+                .lock()
+                .iter_mut()
+                .find(|i| i.device == info.device)
+            {
+                info.driver = Some(driver.name);
+            }
+            bound += 1;
+        }
+    }
+    bound
+}
@@ -63,13 +63,23 @@ fn enable_pic() {
 pub fn enable_irq(irq: u8) {
     if irq >= 8 {
         let m = inb(SLAVE_PIC_DATA);
-        outb(SLAVE_PIC_DATA, m & !(1 << irq));
+        outb(SLAVE_PIC_DATA, m & !(1 << (irq - 8)));
     } else {
         let m = inb(MASTER_PIC_DATA);
         outb(MASTER_PIC_DATA, m & !(1 << irq));
     }
 }
 
+// Returns the masks of the master and the slave PIC
+pub fn get_masks() -> (u8, u8) {
+    (inb(MASTER_PIC_DATA), inb(SLAVE_PIC_DATA))
+}
+
+pub fn set_masks(master: u8, slave: u8) {
+    outb(MASTER_PIC_DATA, master);
+    outb(SLAVE_PIC_DATA, slave);
+}
+
 fn disable_pic() {
     // Set masks
     outb(MASTER_PIC_DATA, 0xff);
@@ -5,6 +5,8 @@ use super::Mutex;
 use crate::gdt::*;
 use crate::memory::*;
 use crate::utils::*;
+use crate::vfs::{OpenFile, PartialRead};
+use alloc::string::String;
 use alloc::vec::*;
 use core::arch::asm;
 
@@ -13,11 +15,17 @@ pub static SHARED_PAGE: Mutex<u64> = Mutex::new(0);
 
 const USER_STACK_BASE: u64 = 0x1000_0000;
 const USER_STACK_PAGE_COUNT: u64 = 0x1000;
+const USER_HEAP_BASE: u64 = 0x4444_0000_0000;
+// Most pages a single alloc_pages syscall can ask for, 256 MiB
+const MAX_ALLOC_PAGES: u64 = 0x10000;
 
 pub struct ProcessList {
     pub processes: Vec<Process>,
+    // Processes that have ended, kept until their parent waits for them
+    pub zombies: Vec<Zombie>,
     pub current_process: usize,
     pub jump_to_multitasking: bool,
+    pub idle: bool, // No process is running, the timer interrupted the idle loop
 
     pid_counter: u32,
 }
@@ -26,50 +34,268 @@ impl ProcessList {
     const fn new() -> ProcessList {
         ProcessList {
             processes: Vec::new(),
+            zombies: Vec::new(),
             current_process: 0,
             jump_to_multitasking: false,
+            idle: false,
             pid_counter: 0,
         }
     }
 
-    pub fn push_process(&mut self, mappings: Vec<VirtualMapping>, entry_point: u64) -> u32 {
-        self.processes
-            .push(Process::new(mappings, entry_point, self.pid_counter));
+    pub fn push_process(
+        &mut self,
+        path: String,
+        parent: Option<u32>,
+        address_space: AddressSpace,
+        mappings: Vec<VirtualMapping>,
+        entry_point: u64,
+    ) -> u32 {
+        self.processes.push(Process::new(
+            path,
+            parent,
+            address_space,
+            mappings,
+            entry_point,
+            self.pid_counter,
+        ));
         self.pid_counter += 1;
         self.pid_counter - 1
     }
 
-    pub fn kill(&mut self, pid: u32) {
+    // Ends the process. If its parent is still running, a zombie with the exit status is kept until
+    // the parent waits for it. Children of the process are orphaned, and their zombies dropped
+    pub fn kill(&mut self, pid: u32, status: ExitStatus) {
         for (i, proc) in self.processes.iter().enumerate() {
             if proc.pid == pid {
+                let parent = proc.parent;
                 self.processes.remove(i);
+
+                for child in self.processes.iter_mut() {
+                    if child.parent == Some(pid) {
+                        child.parent = None;
+                    }
+                }
+                self.zombies.retain(|zombie| zombie.parent != pid);
+                // Parents always outlive the link to their children, which is cleared when they end
+                if let Some(parent) = parent {
+                    self.zombies.push(Zombie {
+                        pid,
+                        parent,
+                        status,
+                    });
+                    self.wake(&WaitEvent::Child(parent));
+                }
+
+                // Keep current_process on the same process. If it was the one removed, move it to
+                // the previous one, so that the scheduler continues with the one that followed it
                 if self.processes.len() == 0 {
                     self.current_process = 0;
-                } else {
-                    self.current_process = self.current_process % self.processes.len();
+                } else if i <= self.current_process {
+                    self.current_process =
+                        (self.current_process + self.processes.len() - 1) % self.processes.len();
                 }
                 break;
             }
         }
+    }
+
+    pub fn is_alive(&self, pid: u32) -> bool {
+        self.processes.iter().any(|p| p.pid == pid)
+    }
+
+    // Checks whether parent has a running child, the one with pid if given
+    pub fn has_child(&self, parent: u32, pid: Option<u32>) -> bool {
+        self.processes
+            .iter()
+            .any(|p| p.parent == Some(parent) && pid.is_none_or(|pid| p.pid == pid))
+    }
+
+    // Removes and returns the zombie of a child of parent, the one with pid if given
+    pub fn reap(&mut self, parent: u32, pid: Option<u32>) -> Option<Zombie> {
+        let index = self
+            .zombies
+            .iter()
+            .position(|z| z.parent == parent && pid.is_none_or(|pid| z.pid == pid))?;
+        Some(self.zombies.remove(index))
+    }
+
+    // Returns the index of the first ready process after the current one, wrapping around
+    fn next_ready(&self) -> Option<usize> {
+        let len = self.processes.len();
+        for i in 1..=len {
+            let index = (self.current_process + i) % len;
+            if self.processes[index].state == ProcessState::Ready {
+                return Some(index);
+            }
+        }
+        None
+    }
+
+    // Makes ready every process blocked on event
+    pub fn wake(&mut self, event: &WaitEvent) {
+        for proc in self.processes.iter_mut() {
+            if let ProcessState::Blocked(e, _) = &proc.state {
+                if e == event {
+                    proc.restart_syscall();
+                }
+            }
+        }
+    }
+
+    // Makes ready every sleeping process whose time is up and every blocked process whose deadline
+    // has passed
+    pub fn wake_expired(&mut self, now: u64) {
+        for proc in self.processes.iter_mut() {
+            match proc.state {
+                ProcessState::Sleeping(until) if until <= now => {
+                    proc.state = ProcessState::Ready;
+                }
+                ProcessState::Blocked(_, Some(deadline)) if deadline <= now => {
+                    proc.restart_syscall();
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// Pid of the process whose syscall is running, or None during boot
+pub fn current_pid() -> Option<u32> {
+    let list = PROCESS_LIST.lock();
+    list.processes
+        .get(list.current_process)
+        .filter(|p| p.state == ProcessState::Running)
+        .map(|p| p.pid)
+}
+
+// Jumps to the next ready process. If there is none, idles until an interrupt makes one ready
+pub fn schedule() -> ! {
+    let mut list = PROCESS_LIST.lock();
+    if let Some(next) = list.next_ready() {
+        list.current_process = next;
+        list.idle = false;
+        list.processes[next].state = ProcessState::Running;
+        list.processes[next].reenter();
+    }
+
+    list.idle = true;
+    drop(list);
+    idle();
+}
+
+fn idle() -> ! {
+    // The last address space may belong to a process that has been freed
+    let plm4 = MEMORY_MANAGER.lock().get_kernel_plm4();
+    MEMORY_MANAGER.lock().set_plm4(plm4);
+
+    // This runs on the IST1 stack of the handler that called schedule, which the next interrupt
+    // reuses from the top. The loop keeps nothing on the stack, so that is harmless. Other
+    // interrupts return into the loop, which only the timer handler leaves by scheduling a process
+    unsafe {
+        asm!("2:", "sti", "hlt", "jmp 2b", options(noreturn));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProcessState {
+    Running,
+    Ready,
+    Sleeping(u64), // Until MILLISECONDS_SINCE_STARTUP reaches the value
+    Blocked(WaitEvent, Option<u64>), // Until the event happens or the optional deadline passes
+}
+
+impl ProcessState {
+    // Encoding of the state in ProcessInfo
+    fn code(&self) -> u32 {
+        match self {
+            ProcessState::Running => 0,
+            ProcessState::Ready => 1,
+            ProcessState::Sleeping(_) => 2,
+            ProcessState::Blocked(_, _) => 3,
+        }
+    }
+}
 
-        if self.processes.len() == 0 {
-            panic!("Empty process list");
+// Events processes can block on. A process is blocked from inside a syscall, and when it is woken
+// up the syscall is executed again, so that it can complete or block again
+#[derive(Debug, Clone, PartialEq)]
+pub enum WaitEvent {
+    MailBox(String), // A message is sent to the mail box, or the mail box is deleted
+    Disk,            // A read the process may have queued on a drive completes
+    Child(u32),      // A child of the process with the pid ends
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitStatus {
+    Exited(u32), // With the code passed to the exit syscall
+    Killed,
+    Faulted, // By an exception
+}
+
+impl ExitStatus {
+    // Encoding of the status returned by the waitpid syscall, as a reason and a code
+    pub fn code(&self) -> (u64, u64) {
+        match self {
+            ExitStatus::Exited(code) => (0, *code as u64),
+            ExitStatus::Killed => (1, 0),
+            ExitStatus::Faulted => (2, 0),
         }
     }
 }
 
+// Exit status of a process that has ended, kept for its parent
+#[derive(Debug, Clone)]
+pub struct Zombie {
+    pub pid: u32,
+    pub parent: u32,
+    pub status: ExitStatus,
+}
+
 pub struct Process {
     pub mappings: Vec<VirtualMapping>,
+    pub address_space: AddressSpace,
+    pub vallocator: VirtualAllocator,
     pub context: Context,
+    pub state: ProcessState,
+    pub pid: u32,
+    pub parent: Option<u32>, // None for the first process, and for orphans
+    pub files: Vec<Option<OpenFile>>, // Indexed by file descriptor
+    pub path: String,        // Of the executable the process was started from
+    pub cpu_ticks: u64,      // Timer ticks during which the process was running
+    pub partial_read: Option<PartialRead>, // Of an exec that waits for the drive
+}
+
+// Copied as is to userspace. The executable path is copied separately
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct ProcessInfo {
     pub pid: u32,
+    pub state: u32,
+    pub cpu_ticks: u64,
+    pub resident_pages: u64,
 }
 
 impl Process {
-    pub fn new(mappings: Vec<VirtualMapping>, entry_point: u64, pid: u32) -> Process {
+    pub fn new(
+        path: String,
+        parent: Option<u32>,
+        address_space: AddressSpace,
+        mappings: Vec<VirtualMapping>,
+        entry_point: u64,
+        pid: u32,
+    ) -> Process {
         let mut tmp = Process {
             mappings,
+            address_space,
+            vallocator: VirtualAllocator::new(USER_HEAP_BASE),
             context: Context::new(USER_STACK_BASE + USER_STACK_PAGE_COUNT * 0x1000),
+            state: ProcessState::Ready,
             pid,
+            parent,
+            files: Vec::new(),
+            path,
+            cpu_ticks: 0,
+            partial_read: None,
         };
 
         // Allocate stack
@@ -78,46 +304,100 @@ impl Process {
             stack
                 .frames
                 .push(MEMORY_MANAGER.lock().physical_map.alloc_frame());
-            clear_page(stack.frames[0]);
+            clear_page(stack.frames[i as usize]);
         }
+        tmp.address_space.map_mapping_user(&stack);
         tmp.mappings.push(stack);
 
+        // Create stack guard page
+        tmp.address_space.unmap(USER_STACK_BASE - 0x1000);
+
         tmp.context.rip = entry_point;
         tmp
     }
 
-    #[inline(always)]
-    pub fn reenter(&mut self) {
-        // Load memory mappings
-        let plm4 = MEMORY_MANAGER.lock().get_plm4();
-        for m in &self.mappings {
-            plm4.map_mapping_user(m);
+    // Allocates zeroed pages in the process' address space and returns their virtual address
+    // Returns None if page_count is above MAX_ALLOC_PAGES, or if there are not enough free frames
+    // left for the pages and the page tables that map them
+    pub fn alloc_pages(&mut self, page_count: u64) -> Option<u64> {
+        let table_count = page_count.div_ceil(512) + 3;
+        let available = MEMORY_MANAGER.lock().physical_map.available_pages();
+        if page_count > MAX_ALLOC_PAGES || page_count + table_count > available {
+            return None;
         }
-        // Flush cr3
-        MEMORY_MANAGER.lock().set_plm4(plm4);
 
-        // Create stack guard page
-        plm4.unmap(USER_STACK_BASE - 0x1000);
+        let mapping = self
+            .vallocator
+            .alloc_user_pages(page_count, &mut self.address_space)?;
+        let vaddr = mapping.vaddr;
+        self.mappings.push(mapping);
+        Some(vaddr)
+    }
+
+    // Pages are counted from the mappings of the process, so the shared page is not included
+    pub fn info(&self) -> ProcessInfo {
+        ProcessInfo {
+            pid: self.pid,
+            state: self.state.code(),
+            cpu_ticks: self.cpu_ticks,
+            resident_pages: self.mappings.iter().map(|m| m.frames.len() as u64).sum(),
+        }
+    }
+
+    // Stores the file in the first free slot of the open file table, and returns its descriptor
+    pub fn add_file(&mut self, file: OpenFile) -> u64 {
+        match self.files.iter().position(|f| f.is_none()) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                fd as u64
+            }
+            None => {
+                self.files.push(Some(file));
+                self.files.len() as u64 - 1
+            }
+        }
+    }
+
+    pub fn get_file(&mut self, fd: u64) -> Option<&mut OpenFile> {
+        self.files.get_mut(fd as usize)?.as_mut()
+    }
+
+    pub fn remove_file(&mut self, fd: u64) -> Option<OpenFile> {
+        self.files.get_mut(fd as usize)?.take()
+    }
+
+    // Makes the process ready and rewinds it to the int 0x80 instruction (2 bytes long) of the
+    // syscall it is blocked in
+    fn restart_syscall(&mut self) {
+        self.state = ProcessState::Ready;
+        self.context.rip -= 2;
+    }
+
+    #[inline(always)]
+    pub fn reenter(&mut self) -> ! {
+        // Switch to the process' address space
+        self.address_space.load();
 
         // Load registers and jump
         self.context.load_regs();
 
         unsafe {
             PROCESS_LIST.force_unlock();
-            asm!("iretq");
+            asm!("iretq", options(noreturn));
         }
     }
+}
 
-    pub fn invalidate_tlb(&self) {
-        for m in &self.mappings {
-            for i in 0..m.frames.len() {
-                unsafe {
-                    asm!(
-                        "invlpg [{}]",
-                        in(reg) m.vaddr + i as u64 * 0x1000
-                    );
-                }
-            }
+impl Drop for Process {
+    // Unmaps and frees everything the process owns: stack, ELF segments and pages from alloc_pages.
+    // The page tables are freed afterwards, when address_space is dropped
+    fn drop(&mut self) {
+        // Frames are freed through the identity map, which user mappings may shadow
+        self.address_space.unload();
+
+        for mapping in &self.mappings {
+            self.address_space.unmap_mapping(mapping);
+            mapping.free_frames();
         }
     }
 }
@@ -0,0 +1,205 @@
+#![allow(unused)]
+
+use crate::fs::*;
+use crate::vfs::VFS;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const ROOT_INODE: Inode = 0;
+// Maximum total size of the files in /tmp, which are stored on the kernel heap
+const TMP_CAPACITY: u64 = 4 * 1024 * 1024;
+
+// Mounts an empty ramfs at /tmp
+pub fn init() -> Result<(), ()> {
+    VFS.lock()
+        .mount("/tmp", Box::new(RamFs::new(TMP_CAPACITY)))
+        .map_err(|_| ())
+}
+
+enum Node {
+    File(Vec<u8>),
+    Directory(Vec<(String, Inode)>),
+}
+
+// A filesystem that only lives in memory, and is lost on reboot
+pub struct RamFs {
+    nodes: BTreeMap<Inode, Node>,
+    next_inode: Inode,
+    used: u64,
+    capacity: u64,
+}
+
+impl RamFs {
+    pub fn new(capacity: u64) -> RamFs {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(ROOT_INODE, Node::Directory(Vec::new()));
+        RamFs {
+            nodes,
+            next_inode: ROOT_INODE + 1,
+            used: 0,
+            capacity,
+        }
+    }
+
+    fn entries(&self, directory: Inode) -> Result<&Vec<(String, Inode)>, FsError> {
+        match self.nodes.get(&directory) {
+            Some(Node::Directory(entries)) => Ok(entries),
+            Some(Node::File(_)) => Err(FsError::NotADirectory),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn data(&self, file: Inode) -> Result<&Vec<u8>, FsError> {
+        match self.nodes.get(&file) {
+            Some(Node::File(data)) => Ok(data),
+            Some(Node::Directory(_)) => Err(FsError::IsADirectory),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn data_mut(&mut self, file: Inode) -> Result<&mut Vec<u8>, FsError> {
+        match self.nodes.get_mut(&file) {
+            Some(Node::File(data)) => Ok(data),
+            Some(Node::Directory(_)) => Err(FsError::IsADirectory),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    // Resizes the file to size bytes, padding it with zeros. Fails without changing the file if the
+    // capacity is exceeded or the heap is full
+    fn resize(&mut self, file: Inode, size: u64) -> Result<(), FsError> {
+        let old_size = self.data(file)?.len() as u64;
+        let used = (self.used - old_size)
+            .checked_add(size)
+            .filter(|used| *used <= self.capacity)
+            .ok_or(FsError::NoSpace)?;
+
+        let data = self.data_mut(file)?;
+        if size > old_size {
+            data.try_reserve((size - old_size) as usize)
+                .map_err(|_| FsError::NoSpace)?;
+        }
+        data.resize(size as usize, 0);
+        if size < old_size {
+            data.shrink_to_fit();
+        }
+
+        self.used = used;
+        Ok(())
+    }
+
+    // Adds node to the directory under name, and returns its inode
+    fn insert(&mut self, directory: Inode, name: &str, node: Node) -> Result<Inode, FsError> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(FsError::InvalidName);
+        }
+        if self.entries(directory)?.iter().any(|(n, _)| n == name) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.nodes.insert(inode, node);
+        if let Some(Node::Directory(entries)) = self.nodes.get_mut(&directory) {
+            entries.push((String::from(name), inode));
+        }
+        Ok(inode)
+    }
+}
+
+impl Fs for RamFs {
+    fn root(&self) -> Inode {
+        ROOT_INODE
+    }
+
+    fn lookup(&self, directory: Inode, name: &str) -> Result<Inode, FsError> {
+        self.entries(directory)?
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, inode)| *inode)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn stat(&self, inode: Inode) -> Result<Stat, FsError> {
+        match self.nodes.get(&inode) {
+            Some(Node::File(data)) => Ok(Stat {
+                size: data.len() as u64,
+                ..Stat::default()
+            }),
+            Some(Node::Directory(_)) => Ok(Stat {
+                attributes: ATTRIBUTE_DIRECTORY,
+                ..Stat::default()
+            }),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    // Cursors are indices in the entries of the directory
+    fn read_dir(&self, directory: Inode, cursor: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        match self.entries(directory)?.get(cursor as usize) {
+            Some((name, inode)) => Ok(Some((
+                DirEntry {
+                    name: name.clone(),
+                    stat: self.stat(*inode)?,
+                },
+                cursor + 1,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    fn read(&self, file: Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let data = self.data(file)?;
+        if offset >= data.len() as u64 {
+            return Ok(0);
+        }
+
+        let start = offset as usize;
+        let len = usize::min(buffer.len(), data.len() - start);
+        buffer[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write(&mut self, file: Inode, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or(FsError::NoSpace)?;
+        if end > self.data(file)?.len() as u64 {
+            self.resize(file, end)?;
+        }
+
+        self.data_mut(file)?[offset as usize..end as usize].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn truncate(&mut self, file: Inode, size: u64) -> Result<(), FsError> {
+        self.resize(file, size)
+    }
+
+    fn create(&mut self, directory: Inode, name: &str) -> Result<Inode, FsError> {
+        self.insert(directory, name, Node::File(Vec::new()))
+    }
+
+    fn create_dir(&mut self, directory: Inode, name: &str) -> Result<Inode, FsError> {
+        self.insert(directory, name, Node::Directory(Vec::new()))
+    }
+
+    fn delete(&mut self, directory: Inode, name: &str) -> Result<(), FsError> {
+        let inode = self.lookup(directory, name)?;
+        let size = match self.nodes.get(&inode) {
+            Some(Node::File(data)) => data.len() as u64,
+            Some(Node::Directory(entries)) if entries.is_empty() => 0,
+            Some(Node::Directory(_)) => return Err(FsError::NotEmpty),
+            None => return Err(FsError::NotFound),
+        };
+
+        self.nodes.remove(&inode);
+        if let Some(Node::Directory(entries)) = self.nodes.get_mut(&directory) {
+            entries.retain(|(_, i)| *i != inode);
+        }
+        self.used -= size;
+        Ok(())
+    }
+}
@@ -18,6 +18,104 @@ pub fn exit_boot_services(
     }
 }
 
+// Reads the whole file at path, relative to the root of the partition the kernel was loaded from,
+// into pages of LoaderData memory. Must be called before getting the memory map
+pub fn load_file(
+    system_table: *const SystemTable,
+    image_handle: *const c_void,
+    path: &str,
+) -> Result<&'static [u8], Status> {
+    let boot_services = unsafe { &*(*system_table).boot_services };
+
+    // The path is converted to a null terminated UCS-2 string
+    let mut path_ucs2 = [0u16; 128];
+    if path.len() >= path_ucs2.len() {
+        return Err(Status::INVALID_PARAMETER);
+    }
+    for (i, c) in path.bytes().enumerate() {
+        path_ucs2[i] = c as u16;
+    }
+
+    let mut loaded_image = 0 as *const LoadedImageProtocol;
+    let status = (boot_services.handle_protocol)(
+        image_handle,
+        &LOADED_IMAGE_PROTOCOL_GUID as *const Guid,
+        &mut loaded_image as *mut *const LoadedImageProtocol as *mut *const c_void,
+    );
+    if status != Status::SUCCESS {
+        return Err(status);
+    }
+
+    let mut file_system = 0 as *const SimpleFileSystemProtocol;
+    let status = (boot_services.handle_protocol)(
+        unsafe { (*loaded_image).device_handle },
+        &SIMPLE_FILE_SYSTEM_PROTOCOL_GUID as *const Guid,
+        &mut file_system as *mut *const SimpleFileSystemProtocol as *mut *const c_void,
+    );
+    if status != Status::SUCCESS {
+        return Err(status);
+    }
+
+    let mut root = 0 as *mut FileProtocol;
+    let status = unsafe { ((*file_system).open_volume)(file_system, &mut root) };
+    if status != Status::SUCCESS {
+        return Err(status);
+    }
+
+    let mut file = 0 as *mut FileProtocol;
+    let status = unsafe { ((*root).open)(root, &mut file, path_ucs2.as_ptr(), FILE_MODE_READ, 0) };
+    unsafe { ((*root).close)(root) };
+    if status != Status::SUCCESS {
+        return Err(status);
+    }
+
+    let result = unsafe { read_whole_file(boot_services, file) };
+    unsafe { ((*file).close)(file) };
+    result
+}
+
+// Reads the open file into new pages. Setting the position to u64::MAX moves it to the end of the
+// file, which gives its size
+unsafe fn read_whole_file(
+    boot_services: &BootServices,
+    file: *mut FileProtocol,
+) -> Result<&'static [u8], Status> {
+    // Seeking to u64::MAX moves to the end of the file, which gives its size
+    let mut size = 0u64;
+    let status = ((*file).set_position)(file, u64::MAX);
+    if status != Status::SUCCESS {
+        return Err(status);
+    }
+    let status = ((*file).get_position)(file, &mut size);
+    if status != Status::SUCCESS {
+        return Err(status);
+    }
+    let status = ((*file).set_position)(file, 0);
+    if status != Status::SUCCESS {
+        return Err(status);
+    }
+
+    let mut buffer = 0u64;
+    let pages = (size as usize + 0xfff) / 0x1000;
+    let status = (boot_services.allocate_pages)(
+        AllocateType::AnyPages,
+        MemoryType::LoaderData,
+        pages,
+        &mut buffer,
+    );
+    if status != Status::SUCCESS {
+        return Err(status);
+    }
+
+    let mut read = size as usize;
+    let status = ((*file).read)(file, &mut read, buffer as *mut c_void);
+    if status != Status::SUCCESS {
+        (boot_services.free_pages)(buffer, pages);
+        return Err(status);
+    }
+    Ok(core::slice::from_raw_parts(buffer as *const u8, read))
+}
+
 #[repr(C)]
 pub struct SystemTable {
     pub hdr: TableHeader,
@@ -71,8 +169,13 @@ pub struct BootServices {
     pub raise_tpl: *const c_void,
     pub restore_tpl: *const c_void,
 
-    pub allocate_pages: *const c_void,
-    pub free_pages: *const c_void,
+    pub allocate_pages: extern "efiapi" fn(
+        allocate_type: AllocateType,
+        memory_type: MemoryType,
+        pages: usize,
+        memory: *mut u64,
+    ) -> Status,
+    pub free_pages: extern "efiapi" fn(memory: u64, pages: usize) -> Status,
     pub get_memory_map: extern "efiapi" fn(
         *mut usize,
         *mut MemoryDescriptor,
@@ -93,7 +196,11 @@ pub struct BootServices {
     pub install_protocol_interface: *const c_void,
     pub reinstall_protocol_interface: *const c_void,
     pub uninstall_protocol_interface: *const c_void,
-    pub handle_protocol: *const c_void,
+    pub handle_protocol: extern "efiapi" fn(
+        handle: *const c_void,
+        protocol: *const Guid,
+        interface: *mut *const c_void,
+    ) -> Status,
     pub reserved: *const c_void,
     pub register_protocol_notify: *const c_void,
     pub locate_handle: *const c_void,
@@ -167,6 +274,13 @@ pub struct LoadFileProtocol {
     ) -> Status,
 }
 
+#[repr(u32)]
+pub enum AllocateType {
+    AnyPages = 0,
+    MaxAddress,
+    Address,
+}
+
 #[repr(u32)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MemoryType {
@@ -249,6 +363,67 @@ pub const GOP_GUID: Guid = Guid::new(
     0x9042a9de, 0x23dc, 0x4a38, 0x96, 0xfb, 0x7a, 0xde, 0xd0, 0x80, 0x51, 0x6a,
 );
 
+pub const LOADED_IMAGE_PROTOCOL_GUID: Guid = Guid::new(
+    0x5b1b31a1, 0x9562, 0x11d2, 0x8e, 0x3f, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b,
+);
+
+pub const SIMPLE_FILE_SYSTEM_PROTOCOL_GUID: Guid = Guid::new(
+    0x964e5b22, 0x6459, 0x11d2, 0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b,
+);
+
+const FILE_MODE_READ: u64 = 1;
+
+#[repr(C)]
+pub struct LoadedImageProtocol {
+    pub revision: u32,
+    pub parent_handle: *const c_void,
+    pub system_table: *const SystemTable,
+    pub device_handle: *const c_void,
+    pub file_path: *const c_void,
+    pub reserved: *const c_void,
+    pub load_options_size: u32,
+    pub load_options: *const c_void,
+    pub image_base: *const c_void,
+    pub image_size: u64,
+    pub image_code_type: MemoryType,
+    pub image_data_type: MemoryType,
+    pub unload: *const c_void,
+}
+
+#[repr(C)]
+pub struct SimpleFileSystemProtocol {
+    pub revision: u64,
+    pub open_volume: extern "efiapi" fn(
+        this: *const SimpleFileSystemProtocol,
+        root: *mut *mut FileProtocol,
+    ) -> Status,
+}
+
+#[repr(C)]
+pub struct FileProtocol {
+    pub revision: u64,
+    pub open: extern "efiapi" fn(
+        this: *mut FileProtocol,
+        new_handle: *mut *mut FileProtocol,
+        file_name: *const u16,
+        open_mode: u64,
+        attributes: u64,
+    ) -> Status,
+    pub close: extern "efiapi" fn(this: *mut FileProtocol) -> Status,
+    pub delete: *const c_void,
+    pub read: extern "efiapi" fn(
+        this: *mut FileProtocol,
+        buffer_size: *mut usize,
+        buffer: *mut c_void,
+    ) -> Status,
+    pub write: *const c_void,
+    pub get_position: extern "efiapi" fn(this: *mut FileProtocol, position: *mut u64) -> Status,
+    pub set_position: extern "efiapi" fn(this: *mut FileProtocol, position: u64) -> Status,
+    pub get_info: *const c_void,
+    pub set_info: *const c_void,
+    pub flush: *const c_void,
+}
+
 #[repr(C)]
 pub struct GraphicsOutputProtocol {
     pub query_mode: *const c_void,
@@ -10,6 +10,17 @@ pub fn halt() -> ! {
     }
 }
 
+// Runs f with interrupts disabled, and enables them again afterwards if they were enabled
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let rflags: u64;
+    unsafe { asm!("pushfq", "pop {}", "cli", out(reg) rflags) };
+    let result = f();
+    if rflags & (1 << 9) != 0 {
+        unsafe { asm!("sti") };
+    }
+    result
+}
+
 pub fn clear_page(page: u64) {
     for i in 0..4096 / 8 {
         unsafe {
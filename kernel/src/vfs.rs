@@ -0,0 +1,236 @@
+#![allow(unused)]
+
+use super::Mutex;
+use crate::block_cache::CacheStats;
+use crate::fs::*;
+use crate::memory::{VirtualMapping, KERNEL_VALLOCATOR};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub static VFS: Mutex<Vfs> = Mutex::new(Vfs::new());
+
+// Largest read passed to a filesystem at once
+const READ_CHUNK: usize = 0x10000;
+// Largest write passed to a filesystem at once, so that the sectors written by one operation fit
+// in the block cache until it is flushed
+const WRITE_CHUNK: usize = 0x10000;
+
+// A file or a directory in one of the mounted filesystems
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Handle {
+    mount: usize,
+    inode: Inode,
+}
+
+// An entry of the open file table of a process
+pub struct OpenFile {
+    pub handle: Handle,
+    pub offset: u64,
+}
+
+struct Mount {
+    path: Vec<String>, // Components of the mount point, empty for /
+    fs: Box<dyn Fs + Send>,
+}
+
+pub struct Vfs {
+    mounts: Vec<Mount>,
+}
+
+// Paths are always absolute, but the leading / can be omitted
+fn components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|c| !c.is_empty()).collect()
+}
+
+impl Vfs {
+    const fn new() -> Vfs {
+        Vfs { mounts: Vec::new() }
+    }
+
+    pub fn mount(&mut self, path: &str, fs: Box<dyn Fs + Send>) -> Result<(), FsError> {
+        let path: Vec<String> = components(path).into_iter().map(String::from).collect();
+        if self.mounts.iter().any(|mount| mount.path == path) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        self.mounts.push(Mount { path, fs });
+        Ok(())
+    }
+
+    // Walks the path from the root of the mount point that is its longest prefix
+    pub fn resolve(&self, path: &str) -> Result<Handle, FsError> {
+        let components = components(path);
+        let (mount, mount_point) = self
+            .mounts
+            .iter()
+            .enumerate()
+            .filter(|(_, mount)| {
+                mount.path.len() <= components.len()
+                    && mount.path.iter().zip(&components).all(|(a, b)| a == b)
+            })
+            .max_by_key(|(_, mount)| mount.path.len())
+            .ok_or(FsError::NotFound)?;
+
+        let fs = &mount_point.fs;
+        let mut inode = fs.root();
+        for name in &components[mount_point.path.len()..] {
+            inode = fs.lookup(inode, name)?;
+        }
+        Ok(Handle { mount, inode })
+    }
+
+    // Splits path into the handle of its parent directory and its last component
+    fn resolve_parent<'a>(&self, path: &'a str) -> Result<(Handle, &'a str), FsError> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() {
+            return Err(FsError::InvalidName);
+        }
+        Ok((self.resolve(parent)?, name))
+    }
+
+    pub fn stat(&self, handle: Handle) -> Result<Stat, FsError> {
+        self.mounts[handle.mount].fs.stat(handle.inode)
+    }
+
+    pub fn read_dir(
+        &self,
+        handle: Handle,
+        cursor: u64,
+    ) -> Result<Option<(DirEntry, u64)>, FsError> {
+        self.mounts[handle.mount].fs.read_dir(handle.inode, cursor)
+    }
+
+    pub fn cache_stats(&self, handle: Handle) -> Option<CacheStats> {
+        self.mounts[handle.mount].fs.cache_stats()
+    }
+
+    // Reads in chunks. If the drive has to be waited for after some chunks, the bytes read so far
+    // are returned, instead of starting over when the syscall is run again
+    pub fn read(&self, handle: Handle, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let fs = &self.mounts[handle.mount].fs;
+        let mut done = 0;
+        for chunk in buffer.chunks_mut(READ_CHUNK) {
+            match fs.read(handle.inode, offset + done as u64, chunk) {
+                Ok(read) => {
+                    done += read;
+                    if read < chunk.len() {
+                        break;
+                    }
+                }
+                Err(FsError::WouldBlock) if done > 0 => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(done)
+    }
+
+    // Writes in chunks. If the drive has to be waited for, the syscall is run again and writes the
+    // chunks that were already written a second time
+    pub fn write(&mut self, handle: Handle, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let fs = &mut self.mounts[handle.mount].fs;
+        let mut done = 0;
+        for chunk in data.chunks(WRITE_CHUNK) {
+            fs.write(handle.inode, offset + done as u64, chunk)?;
+            done += chunk.len();
+        }
+        Ok(())
+    }
+
+    // Grows files in chunks, since the filesystem writes the zeros that pad them
+    pub fn truncate(&mut self, handle: Handle, size: u64) -> Result<(), FsError> {
+        let fs = &mut self.mounts[handle.mount].fs;
+        let mut current = fs.stat(handle.inode)?.size;
+        while current + (WRITE_CHUNK as u64) < size {
+            current += WRITE_CHUNK as u64;
+            fs.truncate(handle.inode, current)?;
+        }
+        fs.truncate(handle.inode, size)
+    }
+
+    pub fn create(&mut self, path: &str) -> Result<Handle, FsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let inode = self.mounts[parent.mount].fs.create(parent.inode, name)?;
+        Ok(Handle {
+            mount: parent.mount,
+            inode,
+        })
+    }
+
+    pub fn create_dir(&mut self, path: &str) -> Result<Handle, FsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let inode = self.mounts[parent.mount]
+            .fs
+            .create_dir(parent.inode, name)?;
+        Ok(Handle {
+            mount: parent.mount,
+            inode,
+        })
+    }
+
+    pub fn delete(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        self.mounts[parent.mount].fs.delete(parent.inode, name)
+    }
+
+    // Reads the whole file into a new mapping in the kernel address space. If the drive has to be
+    // waited for, the bytes read so far are kept in partial, and the read continues from there
+    // when partial is passed back for the same file
+    pub fn read_file(
+        &self,
+        path: &str,
+        partial: &mut Option<PartialRead>,
+    ) -> Result<File, FsError> {
+        let handle = self.resolve(path)?;
+        let stat = self.stat(handle)?;
+        if stat.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+
+        let mut read = match partial.take() {
+            Some(read) if read.path == path && read.size == stat.size => read,
+            _ => PartialRead {
+                path: String::from(path),
+                mapping: KERNEL_VALLOCATOR.lock().alloc_pages(stat.size / 0x1000 + 1),
+                size: stat.size,
+                done: 0,
+            },
+        };
+        let buffer = unsafe {
+            core::slice::from_raw_parts_mut(read.mapping.vaddr as *mut u8, stat.size as usize)
+        };
+        while read.done < buffer.len() {
+            match self.read(handle, read.done as u64, &mut buffer[read.done..]) {
+                Ok(0) => break,
+                Ok(count) => read.done += count,
+                Err(FsError::WouldBlock) => {
+                    *partial = Some(read);
+                    return Err(FsError::WouldBlock);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(File {
+            mapping: core::mem::replace(&mut read.mapping, VirtualMapping::new(0, Vec::new())),
+            size: stat.size,
+        })
+    }
+}
+
+// A read_file that stopped to wait for the drive, kept by the process that runs it
+pub struct PartialRead {
+    path: String,
+    mapping: VirtualMapping,
+    size: u64,
+    done: usize,
+}
+
+impl Drop for PartialRead {
+    // The mapping is freed, unless the read has completed and handed it over to the file
+    fn drop(&mut self) {
+        let mapping = core::mem::replace(&mut self.mapping, VirtualMapping::new(0, Vec::new()));
+        mapping.free_kernel();
+    }
+}
@@ -0,0 +1,535 @@
+#![allow(unused)]
+
+use super::Mutex;
+use crate::drive::*;
+use crate::idt;
+use crate::memory::{alloc_dma_frame, map_mmio, DmaBuffer, DMA_FRAMES, MEMORY_MANAGER};
+use crate::pci::{self, Bar, PciDriver, PciInfo, PciMatch};
+use crate::pic8259;
+use crate::utils::*;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+const VENDOR_ID: u16 = 0x1af4;
+// Transitional devices also have the legacy interface, modern ones only the capabilities
+const TRANSITIONAL_DEVICE_ID: u16 = 0x1001;
+const MODERN_DEVICE_ID: u16 = 0x1042;
+
+// Registers of the legacy interface, in the I/O BAR 0
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08; // Frame number of the queue
+const LEGACY_QUEUE_SIZE: u16 = 0x0c;
+const LEGACY_QUEUE_SELECT: u16 = 0x0e;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+const LEGACY_DEVICE_CONFIG: u16 = 0x14; // Without MSI-X
+
+// Vendor specific capabilities of modern devices, pointing to their register blocks
+const CAPABILITY_VENDOR: u8 = 0x09;
+const CONFIG_COMMON: u8 = 1;
+const CONFIG_NOTIFY: u8 = 2;
+const CONFIG_ISR: u8 = 3;
+const CONFIG_DEVICE: u8 = 4;
+
+// Registers of the common configuration of modern devices
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0c;
+const COMMON_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1a;
+const COMMON_QUEUE_ENABLE: u64 = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const COMMON_QUEUE_DESCRIPTORS: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+const NO_VECTOR: u16 = 0xffff;
+const FEATURE_FLUSH: u32 = 1 << 9;
+// Feature bit 32, which is bit 0 of the second feature dword
+const FEATURE_VERSION_1: u32 = 1 << 0;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+const DESCRIPTOR_NEXT: u16 = 1;
+const DESCRIPTOR_WRITE: u16 = 2; // Written by the device
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+const REQUEST_STATUS_OK: u8 = 0;
+
+// Every request uses one descriptor for the header, one per DMA frame, and one for the status
+const DMA_MAX_SECTORS: u64 = (DmaBuffer::SIZE / SECTOR_SIZE) as u64;
+const REQUEST_DESCRIPTORS: u16 = DMA_FRAMES as u16 + 2;
+// Size of the queue of modern devices, which can be smaller than the one offered by the device
+const MODERN_QUEUE_SIZE: u16 = 32;
+// Offset of the status byte in the request frame, after the header
+const REQUEST_STATUS: u64 = 0x10;
+
+static DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    matches: &[
+        PciMatch::Id(VENDOR_ID, TRANSITIONAL_DEVICE_ID),
+        PciMatch::Id(VENDOR_ID, MODERN_DEVICE_ID),
+    ],
+    probe,
+};
+
+// Drives set up by probe, until init hands them out
+static PROBED: Mutex<Vec<Arc<QueuedDrive<VirtioBlk>>>> = Mutex::new(Vec::new());
+// Interrupt line of every drive, used by the IRQ handler
+static INTERRUPTS: Mutex<Vec<(u8, Arc<QueuedDrive<VirtioBlk>>)>> = Mutex::new(Vec::new());
+
+// Registers the driver with the PCI subsystem, and returns the drives it found. Their transfers
+// are queued, and completed by their IRQ
+pub fn init() -> Result<Vec<Arc<QueuedDrive<VirtioBlk>>>, &'static str> {
+    pci::register_driver(&DRIVER);
+    let drives = core::mem::take(&mut *PROBED.lock());
+    if drives.is_empty() {
+        return Err("No virtio-blk device");
+    }
+    Ok(drives)
+}
+
+// Acknowledges the interrupt of every drive by reading its ISR, lets the drives that raised it
+// complete their transfers, and returns the IRQ to end
+pub fn handle_irq() -> u8 {
+    let interrupts = INTERRUPTS.lock();
+    let mut irq = interrupts.first().map(|(irq, _)| *irq).unwrap_or(0);
+    for (line, drive) in interrupts.iter() {
+        if drive.controller().transport.isr() & 1 != 0 {
+            irq = *line;
+            drive.handle_irq();
+        }
+    }
+    irq
+}
+
+fn probe(info: &PciInfo) -> Result<(), &'static str> {
+    // Without MSI-X, completions are signaled on the legacy interrupt line
+    if info.interrupt_pin == 0 || info.interrupt_line >= 16 {
+        return Err("No interrupt line");
+    }
+
+    info.device.enable_decoding();
+    info.device.enable_bus_master();
+    let transport = match Transport::modern(info) {
+        Some(transport) => transport,
+        None => match info.bars[0] {
+            Some(Bar::Io { port, .. }) => Transport::Legacy { io_base: port },
+            _ => return Err("No usable virtio interface"),
+        },
+    };
+
+    let drive = Arc::new(QueuedDrive::new(VirtioBlk::new(transport)?));
+    without_interrupts(|| INTERRUPTS.lock().push((info.interrupt_line, drive.clone())));
+    idt::set_virtio_blk_irq(info.interrupt_line);
+    pic8259::enable_irq(info.interrupt_line);
+    PROBED.lock().push(drive);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Transport {
+    Legacy {
+        io_base: u16,
+    },
+    // Addresses of the register blocks, identity mapped. notify is the one of queue 0
+    Modern {
+        common: u64,
+        notify: u64,
+        isr: u64,
+        device: u64,
+    },
+}
+
+impl Transport {
+    // Finds the register blocks of a modern device and maps them
+    fn modern(info: &PciInfo) -> Option<Transport> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device = None;
+        for (_, offset) in info
+            .capabilities
+            .iter()
+            .filter(|(id, _)| *id == CAPABILITY_VENDOR)
+        {
+            let header = info.device.read(*offset);
+            let bar = info.device.read(offset + 4) as u8;
+            let start = info.device.read(offset + 8) as u64;
+            let length = info.device.read(offset + 12) as u64;
+            let address = match info.bars.get(bar as usize) {
+                Some(Some(Bar::Memory { address, .. })) => address + start,
+                _ => continue,
+            };
+
+            match (header >> 24) as u8 {
+                CONFIG_COMMON => common = Some((address, length)),
+                CONFIG_NOTIFY => {
+                    let multiplier = info.device.read(offset + 16) as u64;
+                    notify = Some((address, length, multiplier));
+                }
+                CONFIG_ISR => isr = Some((address, length)),
+                CONFIG_DEVICE => device = Some((address, length)),
+                _ => {}
+            }
+        }
+
+        let (common, notify, isr, device) = (common?, notify?, isr?, device?);
+        for (address, length) in [common, (notify.0, notify.1), isr, device] {
+            map_mmio(address, length);
+        }
+
+        // The notify register of a queue is at an offset given by the common configuration
+        write_volatile_u16(common.0 + COMMON_QUEUE_SELECT, 0);
+        let notify_off = read_volatile_u16(common.0 + COMMON_QUEUE_NOTIFY_OFF) as u64;
+        Some(Transport::Modern {
+            common: common.0,
+            notify: notify.0 + notify_off * notify.2,
+            isr: isr.0,
+            device: device.0,
+        })
+    }
+
+    fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { io_base } => inb(io_base + LEGACY_STATUS),
+            Transport::Modern { common, .. } => read_volatile_u8(common + COMMON_STATUS),
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match *self {
+            Transport::Legacy { io_base } => outb(io_base + LEGACY_STATUS, status),
+            Transport::Modern { common, .. } => write_volatile_u8(common + COMMON_STATUS, status),
+        }
+    }
+
+    // Reading the ISR acknowledges the interrupt
+    fn isr(&self) -> u8 {
+        match *self {
+            Transport::Legacy { io_base } => inb(io_base + LEGACY_ISR),
+            Transport::Modern { isr, .. } => read_volatile_u8(isr),
+        }
+    }
+
+    fn notify(&self) {
+        match *self {
+            Transport::Legacy { io_base } => outw(io_base + LEGACY_QUEUE_NOTIFY, 0),
+            Transport::Modern { notify, .. } => write_volatile_u16(notify, 0),
+        }
+    }
+
+    // Capacity of the drive in sectors, the first field of the device configuration
+    fn capacity(&self) -> u64 {
+        match *self {
+            Transport::Legacy { io_base } => {
+                ind(io_base + LEGACY_DEVICE_CONFIG) as u64
+                    | (ind(io_base + LEGACY_DEVICE_CONFIG + 4) as u64) << 32
+            }
+            Transport::Modern { device, .. } => {
+                read_volatile_u32(device) as u64 | (read_volatile_u32(device + 4) as u64) << 32
+            }
+        }
+    }
+
+    // Resets the device and negotiates features. Only FLUSH and VERSION_1, which modern devices
+    // require, are accepted. Returns the accepted features of the first dword
+    fn reset(&self) -> Result<u32, &'static str> {
+        self.set_status(0);
+        while self.status() != 0 {}
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        match *self {
+            Transport::Legacy { io_base } => {
+                let features = ind(io_base + LEGACY_DEVICE_FEATURES) & FEATURE_FLUSH;
+                outd(io_base + LEGACY_DRIVER_FEATURES, features);
+                Ok(features)
+            }
+            Transport::Modern { common, .. } => {
+                write_volatile_u32(common + COMMON_DEVICE_FEATURE_SELECT, 1);
+                if read_volatile_u32(common + COMMON_DEVICE_FEATURE) & FEATURE_VERSION_1 == 0 {
+                    self.set_status(STATUS_FAILED);
+                    return Err("Device does not support VERSION_1");
+                }
+                write_volatile_u32(common + COMMON_DEVICE_FEATURE_SELECT, 0);
+                let features = read_volatile_u32(common + COMMON_DEVICE_FEATURE) & FEATURE_FLUSH;
+                write_volatile_u32(common + COMMON_DRIVER_FEATURE_SELECT, 0);
+                write_volatile_u32(common + COMMON_DRIVER_FEATURE, features);
+                write_volatile_u32(common + COMMON_DRIVER_FEATURE_SELECT, 1);
+                write_volatile_u32(common + COMMON_DRIVER_FEATURE, FEATURE_VERSION_1);
+
+                self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+                if self.status() & STATUS_FEATURES_OK == 0 {
+                    self.set_status(STATUS_FAILED);
+                    return Err("Features not accepted");
+                }
+                Ok(features)
+            }
+        }
+    }
+
+    // Allocates queue 0 and gives it to the device
+    fn setup_queue(&self) -> Result<Virtqueue, &'static str> {
+        let queue = match *self {
+            // The legacy layout is fixed: the descriptors and the available ring, then the used
+            // ring on the next page boundary, all in contiguous memory
+            Transport::Legacy { io_base } => {
+                outw(io_base + LEGACY_QUEUE_SELECT, 0);
+                let size = inw(io_base + LEGACY_QUEUE_SIZE);
+                let used_offset = (16 * size as u64 + 6 + 2 * size as u64).next_multiple_of(0x1000);
+                let pages = (used_offset + 6 + 8 * size as u64).div_ceil(0x1000);
+                let base = MEMORY_MANAGER
+                    .lock()
+                    .physical_map
+                    .alloc_contiguous_frames(pages);
+                for page in 0..pages {
+                    clear_page(base + page * 0x1000);
+                }
+                let queue = Virtqueue {
+                    size,
+                    descriptors: base,
+                    available: base + 16 * size as u64,
+                    used: base + used_offset,
+                };
+                if size >= REQUEST_DESCRIPTORS {
+                    outd(io_base + LEGACY_QUEUE_ADDRESS, (base >> 12) as u32);
+                }
+                queue
+            }
+            // Each part of the queue of a modern device can be anywhere
+            Transport::Modern { common, .. } => {
+                write_volatile_u16(common + COMMON_QUEUE_SELECT, 0);
+                let size = u16::min(
+                    read_volatile_u16(common + COMMON_QUEUE_SIZE),
+                    MODERN_QUEUE_SIZE,
+                );
+                let queue = Virtqueue {
+                    size,
+                    descriptors: alloc_dma_frame(false)?,
+                    available: alloc_dma_frame(false)?,
+                    used: alloc_dma_frame(false)?,
+                };
+                if size >= REQUEST_DESCRIPTORS {
+                    write_volatile_u16(common + COMMON_QUEUE_SIZE, size);
+                    write_volatile_u16(common + COMMON_QUEUE_MSIX_VECTOR, NO_VECTOR);
+                    write_volatile_u64(common + COMMON_QUEUE_DESCRIPTORS, queue.descriptors);
+                    write_volatile_u64(common + COMMON_QUEUE_DRIVER, queue.available);
+                    write_volatile_u64(common + COMMON_QUEUE_DEVICE, queue.used);
+                    write_volatile_u16(common + COMMON_QUEUE_ENABLE, 1);
+                }
+                queue
+            }
+        };
+
+        if queue.size < REQUEST_DESCRIPTORS {
+            self.set_status(STATUS_FAILED);
+            return Err("Queue too small");
+        }
+        Ok(queue)
+    }
+}
+
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct RequestHeader {
+    t: u32,
+    _reserved: u32,
+    sector: u64,
+}
+
+// Split virtqueue. Every address is physical, and identity mapped in the kernel
+struct Virtqueue {
+    size: u16,
+    descriptors: u64,
+    available: u64, // flags: u16, idx: u16, ring: [u16; size]
+    used: u64,      // flags: u16, idx: u16, ring: [(id: u32, len: u32); size]
+}
+
+// A virtio-blk drive, run by a QueuedDrive. Requests are sent one at a time, always starting from
+// descriptor 0, and the device signals their completion on its interrupt line
+pub struct VirtioBlk {
+    transport: Transport,
+    queue: Virtqueue,
+    request: u64, // Header, followed by the status byte
+    buffer: DmaBuffer,
+    // Set if the device has a write cache that must be flushed
+    can_flush: bool,
+    pub capacity: u64,
+}
+
+impl VirtioBlk {
+    fn new(transport: Transport) -> Result<VirtioBlk, &'static str> {
+        let features = transport.reset()?;
+        let queue = transport.setup_queue()?;
+        let request = alloc_dma_frame(false)?;
+        let buffer = DmaBuffer::new(false)?;
+
+        transport.set_status(transport.status() | STATUS_DRIVER_OK);
+        Ok(VirtioBlk {
+            transport,
+            queue,
+            request,
+            buffer,
+            can_flush: features & FEATURE_FLUSH != 0,
+            capacity: transport.capacity(),
+        })
+    }
+
+    fn set_descriptor(&self, index: u16, address: u64, len: u32, flags: u16) {
+        unsafe {
+            write_volatile(
+                (self.queue.descriptors as *mut Descriptor).add(index as usize),
+                Descriptor {
+                    address,
+                    len,
+                    flags,
+                    next: index + 1,
+                },
+            );
+        }
+    }
+
+    // Sends a request transferring len bytes from or to the DMA buffer. The device raises its IRQ
+    // once it is done
+    fn send(&self, t: u32, sector: u64, len: usize) {
+        unsafe {
+            write_volatile(
+                self.request as *mut RequestHeader,
+                RequestHeader {
+                    t,
+                    _reserved: 0,
+                    sector,
+                },
+            );
+            write_volatile((self.request + REQUEST_STATUS) as *mut u8, 0xff);
+        }
+
+        // Header, data frames and status, chained in order from descriptor 0
+        self.set_descriptor(
+            0,
+            self.request,
+            size_of::<RequestHeader>() as u32,
+            DESCRIPTOR_NEXT,
+        );
+        let data_flags = if t == REQUEST_IN {
+            DESCRIPTOR_NEXT | DESCRIPTOR_WRITE
+        } else {
+            DESCRIPTOR_NEXT
+        };
+        let mut index = 1;
+        for (frame, size) in self.buffer.regions(len) {
+            self.set_descriptor(index, frame, size as u32, data_flags);
+            index += 1;
+        }
+        self.set_descriptor(index, self.request + REQUEST_STATUS, 1, DESCRIPTOR_WRITE);
+
+        let available_idx = (self.queue.available + 2) as *mut u16;
+        unsafe {
+            let idx = read_volatile(available_idx);
+            let slot = (self.queue.available + 4 + 2 * (idx % self.queue.size) as u64) as *mut u16;
+            write_volatile(slot, 0);
+            fence(Ordering::SeqCst);
+            write_volatile(available_idx, idx.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+        self.transport.notify();
+    }
+
+    // Only one request is in the queue at a time, so it is done once the device has used every
+    // available descriptor chain
+    fn is_done(&self) -> bool {
+        let available_idx = (self.queue.available + 2) as *const u16;
+        let used_idx = (self.queue.used + 2) as *const u16;
+        unsafe { read_volatile(used_idx) == read_volatile(available_idx) }
+    }
+}
+
+impl Controller for VirtioBlk {
+    fn start(&self, request: &mut Request) -> Chunk {
+        let sectors = u64::min(request.sector_count - request.done, DMA_MAX_SECTORS);
+        let lba = request.lba + request.done;
+        let offset = request.done as usize * SECTOR_SIZE;
+        let len = sectors as usize * SECTOR_SIZE;
+        match request.kind {
+            // Without a write cache, every write is already on the drive
+            RequestKind::Flush if !self.can_flush => return Chunk::Done(0, Ok(())),
+            RequestKind::Flush => self.send(REQUEST_FLUSH, 0, 0),
+            RequestKind::Read => self.send(REQUEST_IN, lba, len),
+            RequestKind::Write => {
+                self.buffer.copy_from(request.data[offset..].as_ptr(), len);
+                self.send(REQUEST_OUT, lba, len);
+            }
+        }
+        Chunk::Running(sectors)
+    }
+
+    fn finish(&self, request: &mut Request, sectors: u64) -> Option<Result<(), DriveError>> {
+        if !self.is_done() {
+            return None;
+        }
+        let status = unsafe { read_volatile((self.request + REQUEST_STATUS) as *const u8) };
+        if status != REQUEST_STATUS_OK {
+            return Some(Err(DriveError::Io));
+        }
+
+        if request.kind == RequestKind::Read {
+            let offset = request.done as usize * SECTOR_SIZE;
+            self.buffer.copy_to(
+                request.data[offset..].as_mut_ptr(),
+                sectors as usize * SECTOR_SIZE,
+            );
+        }
+        Some(Ok(()))
+    }
+}
+
+fn read_volatile_u8(address: u64) -> u8 {
+    unsafe { read_volatile(address as *const u8) }
+}
+
+fn write_volatile_u8(address: u64, value: u8) {
+    unsafe { write_volatile(address as *mut u8, value) }
+}
+
+fn read_volatile_u16(address: u64) -> u16 {
+    unsafe { read_volatile(address as *const u16) }
+}
+
+fn write_volatile_u16(address: u64, value: u16) {
+    unsafe { write_volatile(address as *mut u16, value) }
+}
+
+fn read_volatile_u32(address: u64) -> u32 {
+    unsafe { read_volatile(address as *const u32) }
+}
+
+fn write_volatile_u32(address: u64, value: u32) {
+    unsafe { write_volatile(address as *mut u32, value) }
+}
+
+// 64 bit registers are written as two halves, low first
+fn write_volatile_u64(address: u64, value: u64) {
+    write_volatile_u32(address, value as u32);
+    write_volatile_u32(address + 4, (value >> 32) as u32);
+}
@@ -1,29 +1,325 @@
 #![allow(unused)]
 
+use super::io::*;
 use super::*;
 
-pub struct File {
-    pub ptr: u64,
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+
+// Attributes and timestamps use the FAT encoding
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Stat {
     pub size: u64,
+    pub attributes: u8,
+    pub creation_time_hundredths: u8,
+    pub creation_time: u16,
+    pub creation_date: u16,
+    pub last_accessed_date: u16,
+    pub last_modification_time: u16,
+    pub last_modification_date: u16,
+}
+
+impl Stat {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTRIBUTE_DIRECTORY != 0
+    }
+}
+
+// Flags of the open syscall
+const OPEN_CREATE: u64 = 1 << 0;
+const OPEN_TRUNCATE: u64 = 1 << 1;
+
+// An open file, which is closed when dropped
+pub struct File {
+    fd: u64,
 }
 
 impl File {
-    pub fn load(path: &str) -> Result<File, &str> {
-        let mut file = File { ptr: 0, size: 0 };
+    fn open_with_flags(path: &str, flags: u64) -> Result<File, SyscallError> {
+        let mut status: u64;
+        let mut fd: u64;
         unsafe {
             asm!(
                 "int 0x80",
-                in("rax") 0x30,
+                inlateout("rax") 0x37u64 => status,
                 in("rcx") path.as_ptr(),
                 in("rdx") path.len(),
-                out("r8") file.ptr,
-                out("r9") file.size
+                inout("r8") flags => fd,
+            );
+        }
+        SyscallError::check(status)?;
+        Ok(File { fd })
+    }
+
+    pub fn open(path: &str) -> Result<File, SyscallError> {
+        File::open_with_flags(path, 0)
+    }
+
+    // Opens the file, creating it if it does not exist and emptying it if it does
+    pub fn create(path: &str) -> Result<File, SyscallError> {
+        File::open_with_flags(path, OPEN_CREATE | OPEN_TRUNCATE)
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, SyscallError> {
+        let mut status: u64;
+        let mut read: u64;
+        unsafe {
+            asm!(
+                "int 0x80",
+                inlateout("rax") 0x38u64 => status,
+                in("rcx") self.fd,
+                in("rdx") buffer.as_mut_ptr(),
+                inout("r8") buffer.len() => read,
+            );
+        }
+        SyscallError::check(status)?;
+        Ok(read as usize)
+    }
+}
+
+impl Write for File {
+    fn write(&mut self, data: &[u8]) -> Result<usize, SyscallError> {
+        let mut status: u64;
+        let mut written: u64;
+        unsafe {
+            asm!(
+                "int 0x80",
+                inlateout("rax") 0x39u64 => status,
+                in("rcx") self.fd,
+                in("rdx") data.as_ptr(),
+                inout("r8") data.len() => written,
+            );
+        }
+        SyscallError::check(status)?;
+        Ok(written as usize)
+    }
+}
+
+impl Seek for File {
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, SyscallError> {
+        let (offset, whence) = match position {
+            SeekFrom::Start(offset) => (offset as i64, 0u64),
+            SeekFrom::Current(offset) => (offset, 1),
+            SeekFrom::End(offset) => (offset, 2),
+        };
+
+        let mut status: u64;
+        let mut new_offset: u64;
+        unsafe {
+            asm!(
+                "int 0x80",
+                inlateout("rax") 0x3au64 => status,
+                in("rcx") self.fd,
+                in("rdx") offset,
+                inout("r8") whence => new_offset,
+            );
+        }
+        SyscallError::check(status)?;
+        Ok(new_offset)
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        unsafe {
+            asm!(
+                "int 0x80",
+                inlateout("rax") 0x3b => _,
+                in("rcx") self.fd,
             );
         }
+    }
+}
+
+// Reads the whole file at path
+pub fn read(path: &str) -> Result<Vec<u8>, SyscallError> {
+    let mut data = vec![0u8; stat(path)?.size as usize];
+    let mut file = File::open(path)?;
+
+    let mut done = 0;
+    while done < data.len() {
+        match file.read(&mut data[done..])? {
+            0 => break,
+            read => done += read,
+        }
+    }
+    data.truncate(done);
+    Ok(data)
+}
+
+// Creates an empty file. Fails if the file already exists
+pub fn create(path: &str) -> Result<(), SyscallError> {
+    let mut status: u64;
+    unsafe {
+        asm!(
+            "int 0x80",
+            inlateout("rax") 0x31u64 => status,
+            in("rcx") path.as_ptr(),
+            in("rdx") path.len(),
+        );
+    }
+    SyscallError::check(status)
+}
+
+// Writes data to the file at offset, growing it if needed
+pub fn write(path: &str, offset: u64, data: &[u8]) -> Result<(), SyscallError> {
+    let mut status: u64;
+    unsafe {
+        asm!(
+            "int 0x80",
+            inlateout("rax") 0x32u64 => status,
+            in("rcx") path.as_ptr(),
+            in("rdx") path.len(),
+            in("r8") data.as_ptr(),
+            in("r9") data.len(),
+            in("r10") offset,
+        );
+    }
+    SyscallError::check(status)
+}
+
+// Shrinks or grows the file to size bytes, padding it with zeros
+pub fn truncate(path: &str, size: u64) -> Result<(), SyscallError> {
+    let mut status: u64;
+    unsafe {
+        asm!(
+            "int 0x80",
+            inlateout("rax") 0x33u64 => status,
+            in("rcx") path.as_ptr(),
+            in("rdx") path.len(),
+            in("r8") size,
+        );
+    }
+    SyscallError::check(status)
+}
+
+// Creates an empty directory. Fails if an entry with the same name already exists
+pub fn create_dir(path: &str) -> Result<(), SyscallError> {
+    let mut status: u64;
+    unsafe {
+        asm!(
+            "int 0x80",
+            inlateout("rax") 0x3cu64 => status,
+            in("rcx") path.as_ptr(),
+            in("rdx") path.len(),
+        );
+    }
+    SyscallError::check(status)
+}
+
+// Deletes a file. Empty directories can also be deleted on filesystems that allow it, like /tmp
+pub fn delete(path: &str) -> Result<(), SyscallError> {
+    let mut status: u64;
+    unsafe {
+        asm!(
+            "int 0x80",
+            inlateout("rax") 0x34u64 => status,
+            in("rcx") path.as_ptr(),
+            in("rdx") path.len(),
+        );
+    }
+    SyscallError::check(status)
+}
+
+pub fn stat(path: &str) -> Result<Stat, SyscallError> {
+    let mut stat = Stat::default();
+    let mut status: u64;
+    unsafe {
+        asm!(
+            "int 0x80",
+            inlateout("rax") 0x36u64 => status,
+            in("rcx") path.as_ptr(),
+            in("rdx") path.len(),
+            in("r8") &mut stat as *mut Stat,
+        );
+    }
+    SyscallError::check(status)?;
+    Ok(stat)
+}
+
+// Counters of the block cache of a filesystem
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub write_backs: u64,
+}
+
+// Returns the block cache counters of the filesystem that holds path
+pub fn cache_stats(path: &str) -> Result<CacheStats, SyscallError> {
+    let mut stats = CacheStats::default();
+    let mut status: u64;
+    unsafe {
+        asm!(
+            "int 0x80",
+            inlateout("rax") 0x3du64 => status,
+            in("rcx") path.as_ptr(),
+            in("rdx") path.len(),
+            in("r8") &mut stats as *mut CacheStats,
+        );
+    }
+    SyscallError::check(status)?;
+    Ok(stats)
+}
+
+// Iterates over the names of the entries of a directory
+pub struct ReadDir {
+    path: String,
+    // Position of the next entry, which is only meaningful to the kernel
+    cursor: u64,
+    done: bool,
+}
 
-        if file.ptr == 0 {
-            return Err("Error loading file");
+impl Iterator for ReadDir {
+    type Item = Result<String, SyscallError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
         }
-        Ok(file)
+
+        let mut name = [0u8; 1024];
+        let mut status: u64;
+        let mut len: u64;
+        let mut present: u64;
+        let mut next: u64;
+        unsafe {
+            asm!(
+                "int 0x80",
+                inlateout("rax") 0x35u64 => status,
+                in("rcx") self.path.as_ptr(),
+                in("rdx") self.path.len(),
+                inout("r8") self.cursor => len,
+                inout("r9") name.as_mut_ptr() => present,
+                inout("r10") name.len() => next,
+            );
+        }
+
+        if let Err(e) = SyscallError::check(status) {
+            self.done = true;
+            return Some(Err(e));
+        }
+        if present == 0 {
+            self.done = true;
+            return None;
+        }
+
+        self.cursor = next;
+        Some(Ok(
+            String::from_utf8_lossy(&name[..len as usize]).into_owned()
+        ))
+    }
+}
+
+// Lists the directory at path. The empty path is the root directory
+pub fn read_dir(path: &str) -> ReadDir {
+    ReadDir {
+        path: String::from(path),
+        cursor: 0,
+        done: false,
     }
 }
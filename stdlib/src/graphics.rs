@@ -76,27 +76,28 @@ impl Line {
 }
 
 pub struct Image {
-    pub file: File,
+    pub data: Vec<u8>,
     pub width: u32,
     pub height: u32,
     pub start: u64,
 }
 
 impl Image {
-    pub fn new(file: File) -> Result<Image, &'static str> {
-        if unsafe { *(file.ptr as *const u16) } != 0x3650 {
+    pub fn new(data: Vec<u8>) -> Result<Image, &'static str> {
+        let ptr = data.as_ptr() as u64;
+        if unsafe { *(ptr as *const u16) } != 0x3650 {
             return Err("Invalid format");
         }
 
         // Skip comment
         let mut start = 2;
         loop {
-            if unsafe { *((file.ptr + start) as *const u8) } == '\n' as u8 {
+            if unsafe { *((ptr + start) as *const u8) } == '\n' as u8 {
                 start += 1;
-                if unsafe { *((file.ptr + start) as *const u8) } == '#' as u8 {
+                if unsafe { *((ptr + start) as *const u8) } == '#' as u8 {
                     loop {
                         start += 1;
-                        if unsafe { *((file.ptr + start) as *const u8) } == '\n' as u8 {
+                        if unsafe { *((ptr + start) as *const u8) } == '\n' as u8 {
                             start += 1;
                             break;
                         }
@@ -111,7 +112,7 @@ impl Image {
         let mut buffer: [u8; 10] = [0; 10];
         let mut i = 0;
         loop {
-            buffer[i] = unsafe { *((file.ptr + start) as *const u8) };
+            buffer[i] = unsafe { *((ptr + start) as *const u8) };
             if buffer[i] < '0' as u8 || buffer[i] > '9' as u8 {
                 buffer[i] = 0;
                 break;
@@ -130,7 +131,7 @@ impl Image {
         let mut i = 0;
         start += 1;
         loop {
-            buffer[i] = unsafe { *((file.ptr + start) as *const u8) };
+            buffer[i] = unsafe { *((ptr + start) as *const u8) };
             if buffer[i] < '0' as u8 || buffer[i] > '9' as u8 {
                 buffer[i] = 0;
                 break;
@@ -148,7 +149,7 @@ impl Image {
         start += 5;
 
         let out = Image {
-            file,
+            data,
             width,
             height,
             start,
@@ -181,16 +182,22 @@ impl Image {
 
     fn get_pixel(&self, x: u64, y: u64) -> u32 {
         let r = unsafe {
-            *((self.file.ptr + self.start + (y as u64 * self.width as u64 + x as u64) * 3 + 0)
-                as *const u8)
+            *((self.data.as_ptr() as u64
+                + self.start
+                + (y as u64 * self.width as u64 + x as u64) * 3
+                + 0) as *const u8)
         };
         let g = unsafe {
-            *((self.file.ptr + self.start + (y as u64 * self.width as u64 + x as u64) * 3 + 1)
-                as *const u8)
+            *((self.data.as_ptr() as u64
+                + self.start
+                + (y as u64 * self.width as u64 + x as u64) * 3
+                + 1) as *const u8)
         };
         let b = unsafe {
-            *((self.file.ptr + self.start + (y as u64 * self.width as u64 + x as u64) * 3 + 2)
-                as *const u8)
+            *((self.data.as_ptr() as u64
+                + self.start
+                + (y as u64 * self.width as u64 + x as u64) * 3
+                + 2) as *const u8)
         };
         ((r as u32) << 16) | ((g as u32) << 8) | b as u32
     }
@@ -268,7 +275,7 @@ impl ScreenBuffer<'_> {
         unsafe {
             asm!(
                 "int 0x80",
-                in("rax") 0x11,
+                inlateout("rax") 0x11 => _,
                 in("rcx") self.base.as_ptr(),
                 in("rdx") self.x,
                 in("r8") self.y,
@@ -319,7 +326,7 @@ pub fn get_screen() -> Screen {
     unsafe {
         asm!(
             "int 0x80",
-            in("rax") 0x12,
+            inlateout("rax") 0x12 => _,
             out("rcx") screen.width,
             out("rdx") screen.height,
         );
@@ -2,25 +2,26 @@ use super::*;
 use alloc::string::*;
 
 pub struct Font {
-    file: File,
+    data: Vec<u8>,
     byte_size: u8,
 }
 
 impl Font {
-    pub fn new(file: File) -> Result<Font, &'static str> {
-        if unsafe { *(file.ptr as *const u16) } != 0x0436 {
+    pub fn new(data: Vec<u8>) -> Result<Font, &'static str> {
+        let ptr = data.as_ptr() as u64;
+        if unsafe { *(ptr as *const u16) } != 0x0436 {
             return Err("Unsupported psf format");
         }
 
         // Flags
-        let flags = unsafe { *(file.ptr as *const u8).offset(2) };
+        let flags = unsafe { *(ptr as *const u8).offset(2) };
         if flags & 0b10 == 0 {
             return Err("Unsupported psf1 format");
         }
 
-        let byte_size = unsafe { *(file.ptr as *const u8).offset(3) };
+        let byte_size = unsafe { *(ptr as *const u8).offset(3) };
 
-        Ok(Font { file, byte_size })
+        Ok(Font { data, byte_size })
     }
 
     pub fn draw_char(
@@ -36,7 +37,7 @@ impl Font {
             return;
         }
 
-        let off = self.file.ptr + 4 + char as u64 * self.byte_size as u64;
+        let off = self.data.as_ptr() as u64 + 4 + char as u64 * self.byte_size as u64;
         for i in 0..(self.byte_size as u64 * scale) {
             let b = unsafe { *(off as *const u8).offset(i as isize / scale as isize) };
             for j in 0..8 * scale {
@@ -6,7 +6,7 @@ static HEAP: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new(
 
 const USER_HEAP_PAGE_COUNT: u64 = 0x1000;
 pub fn init() -> Result<(), ()> {
-    let heap_base = alloc_pages(USER_HEAP_PAGE_COUNT);
+    let heap_base = alloc_pages(USER_HEAP_PAGE_COUNT).map_err(|_| ())?;
 
     unsafe {
         HEAP.lock()
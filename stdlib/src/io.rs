@@ -0,0 +1,38 @@
+use super::*;
+
+pub trait Read {
+    // Returns the number of bytes read, which is 0 at the end of the file
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, SyscallError>;
+
+    // Reads until the end of the file, appending to buffer
+    fn read_to_end(&mut self, buffer: &mut Vec<u8>) -> Result<usize, SyscallError> {
+        let mut chunk = [0u8; 0x1000];
+        let mut total = 0;
+        loop {
+            match self.read(&mut chunk)? {
+                0 => return Ok(total),
+                read => {
+                    buffer.extend_from_slice(&chunk[..read]);
+                    total += read;
+                }
+            }
+        }
+    }
+}
+
+pub trait Write {
+    // Returns the number of bytes written
+    fn write(&mut self, data: &[u8]) -> Result<usize, SyscallError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+pub trait Seek {
+    // Returns the new offset from the start of the file
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, SyscallError>;
+}
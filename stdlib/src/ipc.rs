@@ -1,10 +1,30 @@
 use super::*;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReceiveError {
+    Empty,
+    NoMailBox,
+    TimedOut,
+    BufferTooSmall(usize), // Length of the message
+    Syscall(SyscallError),
+}
+
+impl ReceiveError {
+    fn from_status(status: u64, len: u64) -> ReceiveError {
+        match status {
+            1 => ReceiveError::Empty,
+            3 => ReceiveError::TimedOut,
+            4 => ReceiveError::BufferTooSmall(len as usize),
+            _ => ReceiveError::NoMailBox,
+        }
+    }
+}
+
 pub fn create_mailbox(name: String) {
     unsafe {
         asm!(
             "int 0x80",
-            in("rax") 0x42,
+            inlateout("rax") 0x42 => _,
             in("rcx") name.as_ptr(),
             in("rdx") name.len(),
         );
@@ -15,7 +35,7 @@ pub fn delete_mailbox(name: String) {
     unsafe {
         asm!(
             "int 0x80",
-            in("rax") 0x43,
+            inlateout("rax") 0x43 => _,
             in("rcx") name.as_ptr(),
             in("rdx") name.len(),
         );
@@ -26,7 +46,7 @@ pub fn send(mailbox: String, data: &[u8]) {
     unsafe {
         asm!(
             "int 0x80",
-            in("rax") 0x44,
+            inlateout("rax") 0x44 => _,
             in("rcx") mailbox.as_ptr(),
             in("rdx") mailbox.len(),
             in("r8") data.as_ptr(),
@@ -35,22 +55,69 @@ pub fn send(mailbox: String, data: &[u8]) {
     }
 }
 
-pub fn try_receive(mailbox: String, data: &mut [u8]) -> Result<(), ()> {
+// Receives the first message of the mail box, if there is one that fits in data. On success returns
+// the pid of the sender and the length of the message. If the message is too long, it is left in
+// the mail box and its length is returned in the error
+pub fn try_receive(mailbox: String, data: &mut [u8]) -> Result<(u32, usize), ReceiveError> {
+    let mut from: u64;
+    let mut len: u64;
     let mut res: u64;
+    let mut status: u64;
     unsafe {
         asm!(
             "int 0x80",
-            in("rax") 0x45,
+            inlateout("rax") 0x45u64 => status,
             in("rcx") mailbox.as_ptr(),
             in("rdx") mailbox.len(),
-            in("r8") data.as_ptr(),
+            inout("r8") data.as_mut_ptr() => from,
+            inout("r9") data.len() => len,
             out("r10") res,
         );
     }
 
-    if res == 0 {
-        return Ok(());
+    if let Some(e) = SyscallError::from_status(status) {
+        Err(ReceiveError::Syscall(e))
+    } else if res == 0 {
+        Ok((from as u32, len as usize))
+    } else {
+        Err(ReceiveError::from_status(res, len))
+    }
+}
+
+// Waits until a message is available, or until timeout milliseconds have passed. On success returns
+// the pid of the sender and the length of the message
+pub fn receive(
+    mailbox: String,
+    data: &mut [u8],
+    timeout: Option<u64>,
+) -> Result<(u32, usize), ReceiveError> {
+    let deadline = match timeout {
+        // Deadlines past the end of time become u64::MAX, which the kernel treats as no deadline
+        Some(timeout) => get_milliseconds_since_startup().saturating_add(timeout),
+        None => u64::MAX,
+    };
+
+    let mut from: u64;
+    let mut len: u64;
+    let mut res: u64;
+    let mut status: u64;
+    unsafe {
+        asm!(
+            "int 0x80",
+            inlateout("rax") 0x46u64 => status,
+            in("rcx") mailbox.as_ptr(),
+            in("rdx") mailbox.len(),
+            inout("r8") data.as_mut_ptr() => from,
+            inout("r9") data.len() => len,
+            inout("r10") deadline => res,
+        );
+    }
+
+    if let Some(e) = SyscallError::from_status(status) {
+        Err(ReceiveError::Syscall(e))
+    } else if res == 0 {
+        Ok((from as u32, len as usize))
     } else {
-        return Err(());
+        Err(ReceiveError::from_status(res, len))
     }
 }
@@ -7,6 +7,7 @@ pub mod desktop;
 pub mod fs;
 pub mod graphics;
 pub mod heap;
+pub mod io;
 pub mod ipc;
 
 pub extern crate alloc;
@@ -47,6 +48,52 @@ pub fn _print(args: core::fmt::Arguments) {
     }
 }
 
+// Error reported by the kernel in rax when a syscall fails
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyscallError {
+    UnknownSyscall,
+    InvalidPointer,
+    InvalidArgument,
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    NoSpace,
+    Io,
+    BufferTooSmall,
+    BadFileDescriptor,
+    ReadOnly,
+    NotEmpty,
+}
+
+impl SyscallError {
+    pub fn from_status(status: u64) -> Option<SyscallError> {
+        match status {
+            0 => None,
+            1 => Some(SyscallError::UnknownSyscall),
+            2 => Some(SyscallError::InvalidPointer),
+            4 => Some(SyscallError::NotFound),
+            5 => Some(SyscallError::AlreadyExists),
+            6 => Some(SyscallError::NotADirectory),
+            7 => Some(SyscallError::IsADirectory),
+            8 => Some(SyscallError::NoSpace),
+            9 => Some(SyscallError::Io),
+            10 => Some(SyscallError::BufferTooSmall),
+            11 => Some(SyscallError::BadFileDescriptor),
+            12 => Some(SyscallError::ReadOnly),
+            13 => Some(SyscallError::NotEmpty),
+            _ => Some(SyscallError::InvalidArgument),
+        }
+    }
+
+    pub fn check(status: u64) -> Result<(), SyscallError> {
+        match SyscallError::from_status(status) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
 struct StdOut;
 
 static mut STDOUT: StdOut = StdOut;
@@ -56,7 +103,7 @@ impl core::fmt::Write for StdOut {
         unsafe {
             asm!(
                 "int 0x80",
-                in("rax") 0x10,
+                inlateout("rax") 0x10 => _,
                 in("rcx") s.as_ptr(),
                 in("rdx") s.len()
             );
@@ -70,23 +117,37 @@ pub fn get_milliseconds_since_startup() -> u64 {
     unsafe {
         asm!(
             "int 0x80",
-            in("rax") 0x50,
+            inlateout("rax") 0x50 => _,
             out("rcx") ms
         );
     }
     ms
 }
 
-pub fn alloc_pages(page_count: u64) -> u64 {
+// Suspends the process for at least the given amount of milliseconds
+pub fn sleep(ms: u64) {
+    unsafe {
+        asm!(
+            "int 0x80",
+            inlateout("rax") 0x51 => _,
+            in("rcx") ms
+        );
+    }
+}
+
+// Fails with NoSpace if there is not enough memory left for the pages
+pub fn alloc_pages(page_count: u64) -> Result<u64, SyscallError> {
+    let mut status: u64;
     let mut addr: u64;
     unsafe {
         asm!("int 0x80",
-            in("rax") 0x40,
+            inlateout("rax") 0x40u64 => status,
             in("rcx") page_count,
             out("rdx") addr
         );
     }
-    addr
+    SyscallError::check(status)?;
+    Ok(addr)
 }
 
 pub fn get_key() -> Option<(u8, u8)> {
@@ -96,7 +157,7 @@ pub fn get_key() -> Option<(u8, u8)> {
     unsafe {
         asm!(
             "int 0x80",
-            in("rax") 0x20,
+            inlateout("rax") 0x20 => _,
             out("rcx") present,
             out("rdx") c,
             out("r8") sc
@@ -116,7 +177,7 @@ pub fn get_mouse() -> (u64, u64, bool, bool) {
     let mut r: u64;
     unsafe {
         asm!("int 0x80",
-            in("rax") 0x21,
+            inlateout("rax") 0x21 => _,
             out("rcx") out.0,
             out("rdx") out.1,
             out("r8") l,
@@ -128,14 +189,42 @@ pub fn get_mouse() -> (u64, u64, bool, bool) {
     out
 }
 
-// Returns the pid of the spawned process
-pub fn exec(path: &str) -> Result<u32, ()> {
+// A process started by exec. Dropping the handle does not affect the process. When it ends, its exit
+// status is kept until it is waited for
+#[derive(Debug)]
+pub struct Child {
+    pid: u32,
+}
+
+impl Child {
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    // Blocks until the process ends
+    pub fn wait(&self) -> Result<ExitStatus, SyscallError> {
+        let (_, status) = waitpid(Some(self.pid), 0)?.ok_or(SyscallError::NotFound)?;
+        Ok(status)
+    }
+
+    // Returns None if the process is still running
+    pub fn try_wait(&self) -> Result<Option<ExitStatus>, SyscallError> {
+        Ok(waitpid(Some(self.pid), WAIT_NO_HANG)?.map(|(_, status)| status))
+    }
+
+    pub fn kill(&self) -> Result<(), SyscallError> {
+        kill(self.pid)
+    }
+}
+
+pub fn exec(path: &str) -> Result<Child, ()> {
+    let mut status: u64 = 0;
     let mut res: u64 = 0;
     let mut pid: u64 = 0;
     unsafe {
         asm!(
             "int 0x80",
-            in("rax") 0x60,
+            inlateout("rax") 0x60u64 => status,
             in("rcx") path.as_ptr(),
             in("rdx") path.len(),
             out("r8") res,
@@ -143,10 +232,10 @@ pub fn exec(path: &str) -> Result<u32, ()> {
         );
     }
 
-    if res == 0 {
+    if status != 0 || res == 0 {
         Err(())
     } else {
-        Ok(pid as u32)
+        Ok(Child { pid: pid as u32 })
     }
 }
 
@@ -155,28 +244,174 @@ pub fn get_shared_page() -> u64 {
     unsafe {
         asm!(
             "int 0x80",
-            in("rax") 0x41,
+            inlateout("rax") 0x41 => _,
             out("rcx") ptr,
         );
     }
     ptr
 }
 
-pub fn exit() {
+// Ends the process. The code is reported to the parent by waitpid
+pub fn exit(code: u32) -> ! {
     unsafe {
         asm!(
             "int 0x80",
             in("rax") 0x61,
+            in("rcx") code as u64,
+            options(noreturn),
         );
     }
 }
 
-pub fn kill(pid: u32) {
+// Fails with NotFound if there is no process with pid
+pub fn kill(pid: u32) -> Result<(), SyscallError> {
+    let mut status: u64;
     unsafe {
         asm!(
             "int 0x80",
-            in("rax") 0x62,
+            inlateout("rax") 0x62u64 => status,
             in("rcx") pid as u64,
         );
     }
+    SyscallError::check(status)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitStatus {
+    Exited(u32), // With the code passed to exit
+    Killed,
+    Faulted, // By an exception
+}
+
+// Flags of waitpid
+pub const WAIT_NO_HANG: u64 = 1 << 0; // Return None instead of blocking if no child has ended yet
+const WAIT_ANY: u64 = u64::MAX;
+
+// Waits for the child with pid to end, or any child if pid is None, and returns its pid and exit
+// status. Fails with NotFound if there is no such child
+pub fn waitpid(pid: Option<u32>, flags: u64) -> Result<Option<(u32, ExitStatus)>, SyscallError> {
+    let mut status: u64;
+    let mut reaped: u64;
+    let mut child: u64;
+    let mut reason: u64;
+    let mut code: u64;
+    unsafe {
+        asm!(
+            "int 0x80",
+            inlateout("rax") 0x65u64 => status,
+            in("rcx") pid.map_or(WAIT_ANY, |pid| pid as u64),
+            in("rdx") flags,
+            out("r8") reaped,
+            out("r9") child,
+            out("r10") reason,
+            out("r11") code,
+        );
+    }
+    SyscallError::check(status)?;
+
+    if reaped == 0 {
+        return Ok(None);
+    }
+    let status = match reason {
+        0 => ExitStatus::Exited(code as u32),
+        1 => ExitStatus::Killed,
+        _ => ExitStatus::Faulted,
+    };
+    Ok(Some((child as u32, status)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProcessState {
+    Running,
+    Ready,
+    Sleeping,
+    Blocked,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub path: String, // Of the executable the process was started from
+    pub state: ProcessState,
+    pub cpu_ticks: u64, // Milliseconds during which the process was running
+    pub resident_pages: u64,
+}
+
+// Copied as is from the kernel
+#[derive(Default)]
+#[repr(C)]
+struct RawProcessInfo {
+    pid: u32,
+    state: u32,
+    cpu_ticks: u64,
+    resident_pages: u64,
+}
+
+// Returns the pids of every process, including the calling one
+pub fn processes() -> Result<Vec<u32>, SyscallError> {
+    let mut pids = vec![0u32; 16];
+    loop {
+        let mut status: u64;
+        let mut count: u64;
+        unsafe {
+            asm!(
+                "int 0x80",
+                inlateout("rax") 0x63u64 => status,
+                in("rcx") pids.as_mut_ptr(),
+                in("rdx") pids.len(),
+                out("r8") count,
+            );
+        }
+
+        // Processes may be created between two attempts, so the size is checked again
+        match SyscallError::check(status) {
+            Err(SyscallError::BufferTooSmall) => pids.resize(count as usize, 0),
+            result => {
+                result?;
+                pids.truncate(count as usize);
+                return Ok(pids);
+            }
+        }
+    }
+}
+
+pub fn process_info(pid: u32) -> Result<ProcessInfo, SyscallError> {
+    let mut info = RawProcessInfo::default();
+    let mut path = vec![0u8; 64];
+    loop {
+        let mut status: u64;
+        let mut len: u64;
+        unsafe {
+            asm!(
+                "int 0x80",
+                inlateout("rax") 0x64u64 => status,
+                in("rcx") pid as u64,
+                in("rdx") &mut info as *mut RawProcessInfo,
+                in("r8") path.as_mut_ptr(),
+                inout("r9") path.len() => len,
+            );
+        }
+
+        match SyscallError::check(status) {
+            Err(SyscallError::BufferTooSmall) => path.resize(len as usize, 0),
+            result => {
+                result?;
+                path.truncate(len as usize);
+                break;
+            }
+        }
+    }
+
+    Ok(ProcessInfo {
+        pid: info.pid,
+        path: String::from_utf8_lossy(&path).into_owned(),
+        state: match info.state {
+            0 => ProcessState::Running,
+            1 => ProcessState::Ready,
+            2 => ProcessState::Sleeping,
+            _ => ProcessState::Blocked,
+        },
+        cpu_ticks: info.cpu_ticks,
+        resident_pages: info.resident_pages,
+    })
 }
@@ -21,13 +21,22 @@ extern "C" fn main() {
     send(String::from("Hello"), msg.as_bytes());
     println!("Sent message");
 
-    let mut res = String::from("   ");
-    unsafe {
-        try_receive(String::from("Hello"), res.as_bytes_mut()).expect("Should be able to receive");
-    }
-    println!("Received message: {res}");
+    let mut res = [0u8; 16];
+    let (from, len) =
+        try_receive(String::from("Hello"), &mut res).expect("Should be able to receive");
+    let res = core::str::from_utf8(&res[..len]).unwrap();
+    println!("Received message from {from}: {res}");
 
     delete_mailbox(String::from("Hello"));
     println!("Deleted mailbox");
-    exit();
+
+    for pid in processes().unwrap() {
+        if let Ok(info) = process_info(pid) {
+            println!(
+                "{} {} {:?} {} ms {} pages",
+                info.pid, info.path, info.state, info.cpu_ticks, info.resident_pages
+            );
+        }
+    }
+    exit(0);
 }
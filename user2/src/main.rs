@@ -43,5 +43,6 @@ extern "C" fn main() {
         sbuffer.clear(0);
         circ.draw(&mut sbuffer);
         window_buffer.copy_from_screen_buffer(&sbuffer);
+        sleep(16);
     }
 }